@@ -0,0 +1,210 @@
+// A comparator decides the order of keys. Everything that sorts keys, or
+// searches through sorted ones, goes through a comparator rather than `Ord`,
+// so data written under one comparator is meaningless under another. That's
+// why a database records the `name` of the comparator it was created with,
+// and refuses to open with any other.
+use std::{cmp::Ordering, sync::Arc};
+
+use crate::encoding::Bytes;
+
+pub trait Comparator<K>: std::fmt::Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    fn compare(&self, a: &K, b: &K) -> Ordering;
+
+    // A key in `[start, limit)`, ideally shorter than `start`, which can stand
+    // in as a boundary between the two. Returning `start` is always correct.
+    fn shortest_separator(&self, start: &K, limit: &K) -> K;
+
+    // A key no smaller than `k`, ideally shorter than it. Returning `k` is
+    // always correct.
+    fn successor(&self, k: &K) -> K;
+
+    fn lt(&self, a: &K, b: &K) -> bool {
+        self.compare(a, b) == Ordering::Less
+    }
+
+    fn le(&self, a: &K, b: &K) -> bool {
+        self.compare(a, b) != Ordering::Greater
+    }
+}
+
+// Orders keys by their `Ord` impl. This is the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OrdComparator;
+
+impl<K> Comparator<K> for OrdComparator
+where
+    K: Ord + Clone,
+{
+    fn name(&self) -> &str {
+        "lsm.OrdComparator"
+    }
+
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn shortest_separator(&self, start: &K, _limit: &K) -> K {
+        start.clone()
+    }
+
+    fn successor(&self, k: &K) -> K {
+        k.clone()
+    }
+}
+
+// Orders byte strings lexicographically, and knows how to shorten them.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BytewiseComparator;
+
+impl Comparator<Bytes> for BytewiseComparator {
+    fn name(&self) -> &str {
+        "lsm.BytewiseComparator"
+    }
+
+    fn compare(&self, a: &Bytes, b: &Bytes) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn shortest_separator(&self, Bytes(start): &Bytes, Bytes(limit): &Bytes) -> Bytes {
+        let shared = start
+            .iter()
+            .zip(limit.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        // Bump the first differing byte, as long as that keeps us below
+        // `limit`.
+        if shared < start.len() && shared < limit.len() {
+            let byte = start[shared];
+            if byte < u8::MAX && byte + 1 < limit[shared] {
+                let mut separator = start[..=shared].to_vec();
+                separator[shared] += 1;
+                return Bytes(separator);
+            }
+        }
+        Bytes(start.clone())
+    }
+
+    fn successor(&self, Bytes(k): &Bytes) -> Bytes {
+        // Bump the first byte that can be, and cut off everything after it.
+        match k.iter().position(|b| *b < u8::MAX) {
+            Some(idx) => {
+                let mut successor = k[..=idx].to_vec();
+                successor[idx] += 1;
+                Bytes(successor)
+            }
+            None => Bytes(k.clone()),
+        }
+    }
+}
+
+// Orders internal keys by their user key, according to `user`, and then by
+// timestamp and seqnum.
+#[derive(Debug)]
+pub struct InternalKeyComparator<K> {
+    user: Arc<dyn Comparator<K>>,
+}
+
+impl<K> InternalKeyComparator<K>
+where
+    K: Clone + std::fmt::Debug + 'static,
+{
+    // Orders the internal keys for user keys ordered by `user`.
+    pub fn wrap(user: Arc<dyn Comparator<K>>) -> Arc<dyn Comparator<(K, u64, usize)>> {
+        Arc::new(InternalKeyComparator { user })
+    }
+}
+
+impl<K> Comparator<(K, u64, usize)> for InternalKeyComparator<K>
+where
+    K: Clone + std::fmt::Debug,
+{
+    fn name(&self) -> &str {
+        self.user.name()
+    }
+
+    fn compare(&self, a: &(K, u64, usize), b: &(K, u64, usize)) -> Ordering {
+        self.user
+            .compare(&a.0, &b.0)
+            .then(a.1.cmp(&b.1))
+            .then(a.2.cmp(&b.2))
+    }
+
+    fn shortest_separator(
+        &self,
+        start: &(K, u64, usize),
+        limit: &(K, u64, usize),
+    ) -> (K, u64, usize) {
+        let separator = self.user.shortest_separator(&start.0, &limit.0);
+        // The smallest timestamp and seqnum sort first, so this is above
+        // `start` as long as its user key is.
+        if self.user.lt(&start.0, &separator) && self.user.lt(&separator, &limit.0) {
+            (separator, 0, 0)
+        } else {
+            start.clone()
+        }
+    }
+
+    fn successor(&self, k: &(K, u64, usize)) -> (K, u64, usize) {
+        let successor = self.user.successor(&k.0);
+        if self.user.lt(&k.0, &successor) {
+            (successor, 0, 0)
+        } else {
+            k.clone()
+        }
+    }
+}
+
+// Orders keys backwards, to check that nothing relies on `Ord`.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ReverseComparator;
+
+#[cfg(test)]
+impl<K> Comparator<K> for ReverseComparator
+where
+    K: Ord + Clone,
+{
+    fn name(&self) -> &str {
+        "lsm.ReverseComparator"
+    }
+
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        b.cmp(a)
+    }
+
+    fn shortest_separator(&self, start: &K, _limit: &K) -> K {
+        start.clone()
+    }
+
+    fn successor(&self, k: &K) -> K {
+        k.clone()
+    }
+}
+
+#[test]
+fn test_bytewise_comparator() {
+    let c = BytewiseComparator;
+    let b = |s: &[u8]| Bytes(s.to_vec());
+    let sep = |start: &[u8], limit: &[u8]| c.shortest_separator(&b(start), &b(limit));
+    assert_eq!(sep(b"abcdef", b"abzzz"), b(b"abd"));
+    // There's no room between the differing bytes.
+    assert_eq!(sep(b"abc", b"abd"), b(b"abc"));
+    // `start` is a prefix of `limit`.
+    assert_eq!(sep(b"ab", b"abc"), b(b"ab"));
+
+    assert_eq!(c.successor(&b(b"abc")), b(b"b"));
+    assert_eq!(c.successor(&b(&[0xff, 0xff, 3])), b(&[0xff, 0xff, 4]));
+    assert_eq!(c.successor(&b(&[0xff])), b(&[0xff]));
+
+    let internal = InternalKeyComparator::wrap(Arc::new(BytewiseComparator));
+    assert_eq!(
+        internal.shortest_separator(&(b(b"abcdef"), 0, 5), &(b(b"abzzz"), 0, 1)),
+        (b(b"abd"), 0, 0)
+    );
+    assert_eq!(
+        internal.compare(&(b(b"a"), 1, 5), &(b(b"a"), 0, 7)),
+        Ordering::Greater
+    );
+}
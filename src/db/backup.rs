@@ -0,0 +1,419 @@
+// Incremental backups of a database, kept in a directory of their own.
+//
+// Each backup has its own copies of the WALs and of the manifest, under
+// `private/<id>/`. SSTs never change once written, so they're kept under
+// `shared/` and only copied by the first backup that needs them. The list of
+// backups is itself kept in a manifest, and a backup only exists once it's
+// been recorded there, so one that was cut short is never seen.
+use std::collections::HashSet;
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    encoding::{Decode, Encode},
+    fs::{DbDir, DbFile},
+    root::{Root, Versioned},
+};
+
+use super::{Db, DiskLayout};
+
+pub type BackupId = usize;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BackupFile {
+    // Its name in the database.
+    name: String,
+    // Its name in the backup directory.
+    path: String,
+    size: usize,
+    checksum: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Backup {
+    id: BackupId,
+    created_at: u64,
+    // Every write up to and including this one is in the backup.
+    seqnum: usize,
+    // In the order they're restored in, which leaves the manifest until last.
+    files: Vec<BackupFile>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Backups {
+    next_id: BackupId,
+    backups: Vec<Backup>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum BackupEdit {
+    Add(Backup),
+    Remove(BackupId),
+}
+
+impl Versioned for Backups {
+    type Edit = BackupEdit;
+
+    fn apply(&mut self, edit: BackupEdit) -> anyhow::Result<()> {
+        match edit {
+            BackupEdit::Add(backup) => {
+                self.next_id = backup.id + 1;
+                self.backups.push(backup);
+            }
+            BackupEdit::Remove(id) => self.backups.retain(|b| b.id != id),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub id: BackupId,
+    // When it was taken, according to the database's clock.
+    pub created_at: u64,
+    // Every write up to and including this one is in the backup.
+    pub seqnum: usize,
+    pub num_files: usize,
+    // The total size of the files in it, including any it shares.
+    pub size: usize,
+}
+
+pub struct BackupEngine<D>
+where
+    D: DbDir,
+{
+    dir: D,
+    backups: Root<Backups, D>,
+}
+
+impl<D> BackupEngine<D>
+where
+    D: DbDir + std::fmt::Debug + 'static,
+{
+    pub fn open(dir: D) -> anyhow::Result<Self> {
+        let backups = Root::load(dir.clone())?;
+        Ok(Self { dir, backups })
+    }
+
+    // Backs up the database as it is now, and returns the new backup's id.
+    pub fn create_backup<K, V>(&mut self, db: &mut Db<D, K, V>) -> anyhow::Result<BackupId>
+    where
+        K: Ord + Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
+        V: Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
+    {
+        let id = self.backups.data.next_id;
+        let private = format!("private/{}", id);
+        // Only trust shared files that some backup holds; anything else may
+        // have been left half written by a backup that never finished.
+        let shared: HashSet<String> = self
+            .backups
+            .data
+            .backups
+            .iter()
+            .flat_map(|b| b.files.iter().map(|f| f.path.clone()))
+            .collect();
+
+        let mut files = Vec::new();
+        for name in db.live_ssts() {
+            let contents = read(&mut db.dir, &name)?;
+            let checksum = crc32fast::hash(&contents);
+            let path = format!("shared/{}_{:08x}_{}", name, checksum, contents.len());
+            if !shared.contains(&path) {
+                write(&mut self.dir, &path, &contents)?;
+            }
+            files.push(BackupFile {
+                name,
+                path,
+                size: contents.len(),
+                checksum,
+            });
+        }
+        for name in db.root.data.wals.clone() {
+            let contents = read(&mut db.dir, &name)?;
+            let path = format!("{}/{}", private, name);
+            write(&mut self.dir, &path, &contents)?;
+            files.push(BackupFile {
+                name,
+                path,
+                size: contents.len(),
+                checksum: crc32fast::hash(&contents),
+            });
+        }
+        let mut private_dir = self.dir.cd(&private);
+        for name in db.root.copy_to(&mut private_dir)? {
+            let contents = read(&mut private_dir, &name)?;
+            files.push(BackupFile {
+                path: format!("{}/{}", private, name),
+                name,
+                size: contents.len(),
+                checksum: crc32fast::hash(&contents),
+            });
+        }
+
+        self.backups.edit(vec![BackupEdit::Add(Backup {
+            id,
+            created_at: db.options.clock.now(),
+            seqnum: db.visible_seqnum(),
+            files,
+        })])?;
+        Ok(id)
+    }
+
+    // Oldest first.
+    pub fn list_backups(&self) -> Vec<BackupInfo> {
+        self.backups
+            .data
+            .backups
+            .iter()
+            .map(|b| BackupInfo {
+                id: b.id,
+                created_at: b.created_at,
+                seqnum: b.seqnum,
+                num_files: b.files.len(),
+                size: b.files.iter().map(|f| f.size).sum(),
+            })
+            .collect()
+    }
+
+    // Deletes all but the newest `keep` backups, along with any files no
+    // remaining backup shares.
+    pub fn purge_old_backups(&mut self, keep: usize) -> anyhow::Result<()> {
+        let backups = &self.backups.data.backups;
+        let purged: Vec<Backup> = backups[..backups.len().saturating_sub(keep)].to_vec();
+        self.backups
+            .edit(purged.iter().map(|b| BackupEdit::Remove(b.id)).collect())?;
+
+        let kept: HashSet<&str> = self
+            .backups
+            .data
+            .backups
+            .iter()
+            .flat_map(|b| b.files.iter().map(|f| f.path.as_str()))
+            .collect();
+        for file in purged.iter().flat_map(|b| &b.files) {
+            if !kept.contains(file.path.as_str()) {
+                self.dir.unlink(&file.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Checks that every file in the backup is present and intact.
+    pub fn verify_backup(&mut self, id: BackupId) -> anyhow::Result<()> {
+        for file in self.backup(id)?.files.clone() {
+            self.read_verified(id, &file)?;
+        }
+        Ok(())
+    }
+
+    // Writes the database as it was when the backup was taken into `target`.
+    pub fn restore(&mut self, id: BackupId, mut target: D) -> anyhow::Result<()> {
+        if Root::<DiskLayout, D>::exists(&mut target) {
+            bail!("there's already a database where the backup was to be restored");
+        }
+        for file in self.backup(id)?.files.clone() {
+            let contents = self.read_verified(id, &file)?;
+            write(&mut target, &file.name, &contents)?;
+        }
+        Ok(())
+    }
+
+    // Restores the backup, then replays the writes `db` has made since it was
+    // taken, from its archived and live WALs, up to and including `seqnum`.
+    pub fn restore_to_seqnum<K, V>(
+        &mut self,
+        id: BackupId,
+        db: &mut Db<D, K, V>,
+        target: D,
+        seqnum: usize,
+    ) -> anyhow::Result<()>
+    where
+        K: Ord + Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
+        V: Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
+    {
+        let backup_seqnum = self.backup(id)?.seqnum;
+        if seqnum < backup_seqnum {
+            bail!("backup {} was taken after seqnum {}", id, seqnum);
+        }
+        let commands = db.wal_commands(backup_seqnum, seqnum)?;
+        self.restore(id, target.clone())?;
+        let family_options = db.families[1..]
+            .iter()
+            .map(|family| (family.name.clone(), family.options.clone()))
+            .collect();
+        let mut restored = Db::with_column_families(target, db.options.clone(), family_options)?;
+        for command in commands {
+            restored.commit_command(command)?;
+        }
+        Ok(())
+    }
+
+    fn backup(&self, id: BackupId) -> anyhow::Result<&Backup> {
+        self.backups
+            .data
+            .backups
+            .iter()
+            .find(|b| b.id == id)
+            .ok_or_else(|| anyhow!("there's no backup {}", id))
+    }
+
+    fn read_verified(&mut self, id: BackupId, file: &BackupFile) -> anyhow::Result<Vec<u8>> {
+        let contents = self
+            .dir
+            .open(&file.path)
+            .ok_or_else(|| anyhow!("backup {} is missing {}", id, file.path))?
+            .read_all();
+        if contents.len() != file.size || crc32fast::hash(&contents) != file.checksum {
+            bail!("backup {}'s copy of {} is corrupt", id, file.name);
+        }
+        Ok(contents)
+    }
+}
+
+fn read<D: DbDir>(dir: &mut D, name: &str) -> anyhow::Result<Vec<u8>> {
+    Ok(dir
+        .open(&name)
+        .ok_or_else(|| anyhow!("{} does not exist", name))?
+        .read_all())
+}
+
+// Replaces anything already called `name`.
+fn write<D: DbDir>(dir: &mut D, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+    dir.unlink(&name)?;
+    let mut file = dir.create(&name)?.unwrap();
+    file.write(contents)?;
+    file.sync()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fs::{DbDir, DbFile, Event, MockDir};
+
+    use super::{
+        super::{
+            options::{DbOptions, WalArchive},
+            Db,
+        },
+        BackupEngine,
+    };
+
+    fn contents(db: &mut Db<MockDir, String, String>) -> Vec<(String, String)> {
+        db.scan().unwrap().collect()
+    }
+
+    #[test]
+    fn test_backups() {
+        let dir = MockDir::new();
+        let backup_dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::new(dir.clone()).unwrap();
+        let mut engine = BackupEngine::open(backup_dir.clone()).unwrap();
+
+        db.insert("a".into(), "1".into()).unwrap();
+        db.flush_memtable().unwrap();
+        db.insert("b".into(), "2".into()).unwrap();
+        let first = engine.create_backup(&mut db).unwrap();
+        let first_contents = contents(&mut db);
+
+        // The second backup only copies the new SST.
+        db.insert("c".into(), "3".into()).unwrap();
+        db.flush_memtable().unwrap();
+        (*backup_dir.fs).borrow_mut().take_events();
+        let second = engine.create_backup(&mut db).unwrap();
+        let shared_copies = (*backup_dir.fs)
+            .borrow_mut()
+            .take_events()
+            .iter()
+            .filter(|e| matches!(e, Event::Create(f, _) if f.starts_with("shared/")))
+            .count();
+        assert_eq!(shared_copies, 1);
+        let second_contents = contents(&mut db);
+
+        let mut engine = BackupEngine::open(backup_dir.clone()).unwrap();
+        let ids: Vec<_> = engine.list_backups().iter().map(|b| b.id).collect();
+        assert_eq!(ids, vec![first, second]);
+
+        for (id, expected) in [(first, &first_contents), (second, &second_contents)] {
+            engine.verify_backup(id).unwrap();
+            let target = MockDir::new();
+            engine.restore(id, target.clone()).unwrap();
+            let mut restored: Db<_, String, String> = Db::new(target.clone()).unwrap();
+            assert_eq!(&contents(&mut restored), expected);
+            assert!(engine.restore(id, target).is_err());
+        }
+
+        // Purging the first backup keeps the SST the second shares with it.
+        engine.purge_old_backups(1).unwrap();
+        assert_eq!(engine.list_backups().len(), 1);
+        assert!(engine.restore(first, MockDir::new()).is_err());
+        engine.verify_backup(second).unwrap();
+
+        // Corruption is caught.
+        let mut backup_dir = backup_dir;
+        let mut f = backup_dir
+            .open(&format!("private/{}/MANIFEST-0", second))
+            .unwrap();
+        f.write(b"X").unwrap();
+        assert!(engine.verify_backup(second).is_err());
+        assert!(engine.restore(second, MockDir::new()).is_err());
+    }
+
+    #[test]
+    fn test_point_in_time_recovery() {
+        let options = DbOptions {
+            wal_archive: WalArchive::MaxBytes(1 << 20),
+            ..Default::default()
+        };
+        let mut dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::with_options(dir.clone(), options.clone()).unwrap();
+        let mut engine = BackupEngine::open(MockDir::new()).unwrap();
+        db.insert("a".into(), "1".into()).unwrap();
+        let id = engine.create_backup(&mut db).unwrap();
+
+        db.insert("b".into(), "2".into()).unwrap();
+        db.flush_memtable().unwrap();
+        db.insert("c".into(), "3".into()).unwrap();
+        let good = db.visible_seqnum();
+        let good_contents = contents(&mut db);
+        // A bad write, which made it into an SST before anyone noticed.
+        db.insert("a".into(), "oops".into()).unwrap();
+        db.flush_memtable().unwrap();
+
+        // The retired WALs went into the archive rather than away.
+        let archived = db.root.data.archived_wals.clone();
+        assert!(!archived.is_empty());
+        for wal in &archived {
+            assert!(dir.open(&wal.name).is_none());
+            assert!(dir.open(&format!("archive/{}", wal.name)).is_some());
+        }
+
+        let target = MockDir::new();
+        engine
+            .restore_to_seqnum(id, &mut db, target.clone(), good)
+            .unwrap();
+        let mut restored: Db<_, String, String> = Db::with_options(target, options).unwrap();
+        assert_eq!(contents(&mut restored), good_contents);
+        assert!(engine
+            .restore_to_seqnum(id, &mut db, MockDir::new(), 0)
+            .is_err());
+
+        // With no room in the archive, the WALs since the backup are gone.
+        let mut db: Db<_, String, String> = Db::with_options(
+            MockDir::new(),
+            DbOptions {
+                wal_archive: WalArchive::MaxBytes(0),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let id = engine.create_backup(&mut db).unwrap();
+        db.insert("a".into(), "1".into()).unwrap();
+        db.flush_memtable().unwrap();
+        db.insert("b".into(), "2".into()).unwrap();
+        assert!(db.root.data.archived_wals.is_empty());
+        let seqnum = db.visible_seqnum();
+        assert!(engine
+            .restore_to_seqnum(id, &mut db, MockDir::new(), seqnum)
+            .is_err());
+    }
+}
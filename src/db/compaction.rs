@@ -0,0 +1,606 @@
+// Rewrites the entries flowing out of a compaction before they are written to
+// the new SST.
+use crate::{
+    comparator::Comparator,
+    memtable::{DbValue, KVIter, RangeTombstone, RangeTombstones},
+};
+
+use super::{
+    compaction_filter::{CompactionFilter, FilterDecision},
+    merge_operator::MergeOperator,
+};
+
+type Entry<K, V> = ((K, u64, usize), DbValue<V>);
+
+// Everything about the database that decides what a compaction can drop or
+// fold together.
+pub(crate) struct Compaction<'a, K, V> {
+    pub comparator: &'a dyn Comparator<K>,
+    pub merge_operator: Option<&'a dyn MergeOperator<K, V>>,
+    pub compaction_filter: Option<&'a dyn CompactionFilter<K, V>>,
+    // Whether nothing older than the entries being compacted exists in the
+    // database.
+    pub bottommost: bool,
+    // The current time, for expiring puts.
+    pub now: u64,
+    // Reads are never served below this timestamp, so history older than it
+    // can be trimmed.
+    pub timestamp_low: u64,
+    // Versions above this seqnum are within the history retention window, so
+    // they're written out exactly as they came in.
+    pub retain_above: usize,
+    // Reads can still be served at this seqnum, so range tombstones above it
+    // don't delete anything yet.
+    pub oldest_snapshot: usize,
+}
+
+impl<'a, K, V> Compaction<'a, K, V> {
+    pub fn new(comparator: &'a dyn Comparator<K>) -> Self {
+        Compaction {
+            comparator,
+            merge_operator: None,
+            compaction_filter: None,
+            bottommost: false,
+            now: 0,
+            timestamp_low: 0,
+            retain_above: usize::MAX,
+            oldest_snapshot: usize::MAX,
+        }
+    }
+}
+
+impl<'a, K, V> Compaction<'a, K, V>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    // Streams the (sorted) output of `iter`, dropping anything deleted by one
+    // of `range_tombstones`, turning puts which expired by `now` into deletes
+    // (or dropping them, if there's nothing older for them to shadow),
+    // dropping versions below the low timestamp which are shadowed by another
+    // one that is also below it, and folding each key's newest run of merge
+    // operands as far as it can:
+    //  * if the operands sit on top of a put or delete, they become a single
+    //    put,
+    //  * if `bottommost` is set, nothing older can exist in the database, so
+    //    they also become a single put,
+    //  * otherwise, they are combined with `partial_merge` if the operator
+    //    allows.
+    // The folded entry takes the newest operand's timestamp and seqnum. Only
+    // operands which share that timestamp are folded, unless they're all below
+    // the low timestamp, so that reads at older timestamps are unaffected.
+    // Anything older than the run is passed through untouched. Versions of a
+    // key are ordered oldest first. Finally, every value that's left is run
+    // through `compaction_filter`. None of this touches versions above
+    // `retain_above`, and range tombstones above `oldest_snapshot` don't
+    // drop anything.
+    //
+    // The range tombstones are returned to be written alongside the entries,
+    // unless this is the bottommost compaction, in which case there's nothing
+    // left for them to delete (besides versions which were retained, or
+    // which a snapshot can still see).
+    pub fn compact<I>(
+        self,
+        iter: I,
+        range_tombstones: Vec<RangeTombstone<K>>,
+    ) -> (CompactionIter<'a, I, K, V>, Vec<RangeTombstone<K>>)
+    where
+        I: KVIter<(K, u64, usize), DbValue<V>>,
+    {
+        let out_tombstones = if self.bottommost {
+            range_tombstones
+                .iter()
+                .filter(|t| t.seqnum > self.retain_above.min(self.oldest_snapshot))
+                .cloned()
+                .collect()
+        } else {
+            range_tombstones.clone()
+        };
+        let iter = CompactionIter {
+            range_tombstones: RangeTombstones::new(self.comparator, &range_tombstones),
+            compaction: self,
+            input: iter,
+            pending: Vec::new().into_iter(),
+        };
+        (iter, out_tombstones)
+    }
+
+    // Every version of the next key in `input` which survives the range
+    // tombstones and expiry, oldest first, and whether it sits on top of a
+    // range tombstone. Returns `None` once `input` runs out.
+    fn read_key<I>(
+        &self,
+        input: &mut I,
+        range_tombstones: &RangeTombstones<K>,
+    ) -> Option<(Vec<Entry<K, V>>, bool)>
+    where
+        I: KVIter<(K, u64, usize), DbValue<V>>,
+    {
+        let key = input.peek()?.0 .0.clone();
+        let covered = range_tombstones.covering_seqnum(self.comparator, &key, self.oldest_snapshot);
+        let mut versions: Vec<Entry<K, V>> = Vec::new();
+        while let Some((k, v)) = input.peek() {
+            if k.0 != key {
+                break;
+            }
+            let retained = k.2 > self.retain_above;
+            if k.2 > covered || retained {
+                if v.is_expired(self.now) && !retained {
+                    if !(self.bottommost && versions.is_empty()) {
+                        versions.push((k.clone(), DbValue::Delete));
+                    }
+                } else {
+                    versions.push((k.clone(), v.clone()));
+                }
+            }
+            input.next();
+        }
+        Some((versions, covered > 0))
+    }
+
+    // `versions` holds every version of a single key, oldest first. If
+    // `range_deleted` is set, they sit on top of a range tombstone.
+    fn fold_key(
+        &self,
+        versions: &mut Vec<Entry<K, V>>,
+        range_deleted: bool,
+        out: &mut Vec<Entry<K, V>>,
+    ) {
+        // Nobody reads below the low timestamp, so only the newest put or
+        // delete under it still matters.
+        let floor = versions
+            .iter()
+            .rposition(|((_, ts, _), v)| *ts < self.timestamp_low && !v.is_merge());
+        if let Some(floor) = floor {
+            let mut idx = 0;
+            versions.retain(|((_, _, seqnum), _)| {
+                idx += 1;
+                idx > floor || *seqnum > self.retain_above
+            });
+        }
+
+        let Some(((key, ts, seqnum), _)) = versions.last().cloned() else {
+            return;
+        };
+        let run = versions
+            .iter()
+            .rev()
+            .take_while(|((_, t, s), v)| {
+                v.is_merge() && (*t == ts || ts < self.timestamp_low) && *s <= self.retain_above
+            })
+            .count();
+        let merge_operator = match self.merge_operator {
+            Some(merge_operator) if run > 0 => merge_operator,
+            _ => {
+                out.append(versions);
+                return;
+            }
+        };
+
+        let older = versions.len() - run;
+        let operands: Vec<V> = versions[older..]
+            .iter()
+            .map(|(_, v)| match v {
+                DbValue::Merge(v) => v.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+
+        let base = older.checked_sub(1).map(|idx| &versions[idx].1);
+        let folded = match base {
+            Some(DbValue::Put(base)) => Some(DbValue::Put(merge_operator.full_merge(
+                &key,
+                Some(base),
+                &operands,
+            ))),
+            Some(DbValue::Delete) => Some(DbValue::Put(
+                merge_operator.full_merge(&key, None, &operands),
+            )),
+            None if self.bottommost || range_deleted => Some(DbValue::Put(
+                merge_operator.full_merge(&key, None, &operands),
+            )),
+            // Either the run was cut short at an older timestamp, or the base
+            // may yet expire out from under the operands, so they can only be
+            // combined with one another.
+            _ if run == 1 => None,
+            _ => merge_operator
+                .partial_merge(&key, &operands)
+                .map(DbValue::Merge),
+        };
+
+        match folded {
+            Some(v) => {
+                versions.truncate(older);
+                out.append(versions);
+                out.push(((key, ts, seqnum), v));
+            }
+            None => out.append(versions),
+        }
+    }
+}
+
+// The output of a compaction, produced a key at a time as it's read, so that
+// it can be written straight into an SST. See `Compaction::compact`.
+pub(crate) struct CompactionIter<'a, I, K, V> {
+    compaction: Compaction<'a, K, V>,
+    input: I,
+    range_tombstones: RangeTombstones<K>,
+    // What's left of the output for the last key read.
+    pending: std::vec::IntoIter<Entry<K, V>>,
+}
+
+impl<I, K, V> Iterator for CompactionIter<'_, I, K, V>
+where
+    I: KVIter<(K, u64, usize), DbValue<V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    type Item = Entry<K, V>;
+
+    fn next(&mut self) -> Option<Entry<K, V>> {
+        loop {
+            if let Some(entry) = self.pending.next() {
+                return Some(entry);
+            }
+            let compaction = &self.compaction;
+            let (mut versions, range_deleted) =
+                compaction.read_key(&mut self.input, &self.range_tombstones)?;
+            let mut out = Vec::with_capacity(versions.len());
+            compaction.fold_key(&mut versions, range_deleted, &mut out);
+            if let Some(compaction_filter) = compaction.compaction_filter {
+                out = filter(
+                    out,
+                    compaction_filter,
+                    compaction.bottommost,
+                    compaction.retain_above,
+                );
+            }
+            self.pending = out.into_iter();
+        }
+    }
+}
+
+impl<I, K, V> CompactionIter<'_, I, K, V>
+where
+    I: KVIter<(K, u64, usize), DbValue<V>>,
+    K: Ord,
+{
+    // If reading the input failed, the output stopped short, and this is
+    // why.
+    pub fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.input.take_error()
+    }
+}
+
+// Runs every put in `entries` (which are sorted) at or below `retain_above`
+// through `compaction_filter`. Removed values become deletes, unless
+// `bottommost` is set and there's nothing older for them to hide, in which
+// case they're dropped.
+pub(crate) fn filter<K, V>(
+    entries: Vec<Entry<K, V>>,
+    compaction_filter: &dyn CompactionFilter<K, V>,
+    bottommost: bool,
+    retain_above: usize,
+) -> Vec<Entry<K, V>>
+where
+    K: Ord,
+{
+    let mut out: Vec<Entry<K, V>> = Vec::with_capacity(entries.len());
+    for ((k, ts, seqnum), v) in entries {
+        let value = match &v {
+            DbValue::Put(value) | DbValue::ExpiringPut(value, _) if seqnum <= retain_above => value,
+            _ => {
+                out.push(((k, ts, seqnum), v));
+                continue;
+            }
+        };
+        let v = match compaction_filter.filter(&k, value) {
+            FilterDecision::Keep => v,
+            FilterDecision::Remove => {
+                let oldest = out.last().is_none_or(|((prev, _, _), _)| prev != &k);
+                if bottommost && oldest {
+                    continue;
+                }
+                DbValue::Delete
+            }
+            FilterDecision::ChangeValue(value) => match v {
+                DbValue::ExpiringPut(_, deadline) => DbValue::ExpiringPut(value, deadline),
+                _ => DbValue::Put(value),
+            },
+        };
+        out.push(((k, ts, seqnum), v));
+    }
+    out
+}
+
+#[test]
+fn test_compact_merge_operands() {
+    use super::merge_operator::ConcatOperator;
+    use crate::{comparator::OrdComparator, memtable::VecIter};
+    use std::sync::Arc;
+
+    let s = |s: &str| s.to_owned();
+    let entries = vec![
+        ((s("a"), 0, 1), DbValue::Put(s("1"))),
+        ((s("a"), 0, 2), DbValue::Merge(s("2"))),
+        ((s("a"), 0, 3), DbValue::Merge(s("3"))),
+        ((s("b"), 0, 1), DbValue::Merge(s("x"))),
+        ((s("b"), 0, 2), DbValue::Merge(s("y"))),
+        ((s("c"), 0, 4), DbValue::Merge(s("z"))),
+    ];
+    let compacted = |bottommost| {
+        Compaction {
+            merge_operator: Some(&ConcatOperator),
+            bottommost,
+            ..Compaction::new(&OrdComparator)
+        }
+        .compact(VecIter::new(Arc::new(entries.clone())), Vec::new())
+        .0
+        .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        compacted(false),
+        vec![
+            ((s("a"), 0, 1), DbValue::Put(s("1"))),
+            ((s("a"), 0, 3), DbValue::Put(s("1,2,3"))),
+            ((s("b"), 0, 2), DbValue::Merge(s("x,y"))),
+            ((s("c"), 0, 4), DbValue::Merge(s("z"))),
+        ]
+    );
+    assert_eq!(
+        compacted(true),
+        vec![
+            ((s("a"), 0, 1), DbValue::Put(s("1"))),
+            ((s("a"), 0, 3), DbValue::Put(s("1,2,3"))),
+            ((s("b"), 0, 2), DbValue::Put(s("x,y"))),
+            ((s("c"), 0, 4), DbValue::Put(s("z"))),
+        ]
+    );
+}
+
+#[test]
+fn test_compact_range_tombstones() {
+    use crate::{comparator::OrdComparator, memtable::VecIter};
+    use std::sync::Arc;
+
+    let s = |s: &str| s.to_owned();
+    let entries = vec![
+        ((s("a"), 0, 1), DbValue::Put(s("a1"))),
+        ((s("b"), 0, 1), DbValue::Put(s("b1"))),
+        ((s("b"), 0, 3), DbValue::Put(s("b3"))),
+        ((s("c"), 0, 1), DbValue::Put(s("c1"))),
+    ];
+    let range_tombstones = vec![RangeTombstone {
+        start: s("b"),
+        end: s("c"),
+        seqnum: 2,
+    }];
+    let compacted = |bottommost| {
+        Compaction::<_, String> {
+            bottommost,
+            ..Compaction::new(&OrdComparator)
+        }
+        .compact(
+            VecIter::new(Arc::new(entries.clone())),
+            range_tombstones.clone(),
+        )
+    };
+    let compacted = |bottommost| {
+        let (entries, range_tombstones) = compacted(bottommost);
+        (entries.collect::<Vec<_>>(), range_tombstones)
+    };
+
+    let expected = vec![
+        ((s("a"), 0, 1), DbValue::Put(s("a1"))),
+        ((s("b"), 0, 3), DbValue::Put(s("b3"))),
+        ((s("c"), 0, 1), DbValue::Put(s("c1"))),
+    ];
+    assert_eq!(
+        compacted(false),
+        (expected.clone(), range_tombstones.clone())
+    );
+    assert_eq!(compacted(true), (expected, Vec::new()));
+}
+
+#[test]
+fn test_compact_expired() {
+    use super::merge_operator::ConcatOperator;
+    use crate::{comparator::OrdComparator, memtable::VecIter};
+    use std::sync::Arc;
+
+    let s = |s: &str| s.to_owned();
+    let entries = vec![
+        ((s("a"), 0, 1), DbValue::ExpiringPut(s("a1"), 10)),
+        ((s("b"), 0, 1), DbValue::Put(s("b1"))),
+        ((s("b"), 0, 2), DbValue::ExpiringPut(s("b2"), 10)),
+        ((s("c"), 0, 1), DbValue::ExpiringPut(s("c1"), 20)),
+        ((s("c"), 0, 2), DbValue::Merge(s("x"))),
+    ];
+    let compacted = |bottommost, now| {
+        Compaction {
+            merge_operator: Some(&ConcatOperator),
+            bottommost,
+            now,
+            ..Compaction::new(&OrdComparator)
+        }
+        .compact(VecIter::new(Arc::new(entries.clone())), Vec::new())
+        .0
+        .collect::<Vec<_>>()
+    };
+
+    // Nothing has expired yet, and the operand can't be folded into a value
+    // that might still expire.
+    assert_eq!(compacted(true, 5), entries);
+    // Expired puts still shadow older versions...
+    assert_eq!(
+        compacted(false, 10),
+        vec![
+            ((s("a"), 0, 1), DbValue::Delete),
+            ((s("b"), 0, 1), DbValue::Put(s("b1"))),
+            ((s("b"), 0, 2), DbValue::Delete),
+            ((s("c"), 0, 1), DbValue::ExpiringPut(s("c1"), 20)),
+            ((s("c"), 0, 2), DbValue::Merge(s("x"))),
+        ]
+    );
+    // ...but are dropped once there can't be any.
+    assert_eq!(
+        compacted(true, 20),
+        vec![
+            ((s("b"), 0, 1), DbValue::Put(s("b1"))),
+            ((s("b"), 0, 2), DbValue::Delete),
+            ((s("c"), 0, 2), DbValue::Put(s("x"))),
+        ]
+    );
+}
+
+#[test]
+fn test_compact_filter() {
+    use super::compaction_filter::PrefixFilter;
+    use crate::{comparator::OrdComparator, memtable::VecIter};
+    use std::sync::Arc;
+
+    let s = |s: &str| s.to_owned();
+    let entries = vec![
+        ((s("t1/a"), 0, 1), DbValue::Put(s("a1"))),
+        ((s("t1/a"), 0, 2), DbValue::Put(s("a2"))),
+        ((s("t1/b"), 0, 3), DbValue::Delete),
+        ((s("t2/a"), 0, 1), DbValue::Put(s("a1"))),
+    ];
+    let compacted = |bottommost| {
+        Compaction::<_, String> {
+            compaction_filter: Some(&PrefixFilter("t1/")),
+            bottommost,
+            ..Compaction::new(&OrdComparator)
+        }
+        .compact(VecIter::new(Arc::new(entries.clone())), Vec::new())
+        .0
+        .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        compacted(false),
+        vec![
+            ((s("t1/a"), 0, 1), DbValue::Delete),
+            ((s("t1/a"), 0, 2), DbValue::Delete),
+            ((s("t1/b"), 0, 3), DbValue::Delete),
+            ((s("t2/a"), 0, 1), DbValue::Put(s("a1"))),
+        ]
+    );
+    // With nothing older left for them to hide, removed values are dropped
+    // outright.
+    assert_eq!(
+        compacted(true),
+        vec![
+            ((s("t1/b"), 0, 3), DbValue::Delete),
+            ((s("t2/a"), 0, 1), DbValue::Put(s("a1"))),
+        ]
+    );
+}
+
+#[test]
+fn test_compact_timestamps() {
+    use super::merge_operator::ConcatOperator;
+    use crate::{comparator::OrdComparator, memtable::VecIter};
+    use std::sync::Arc;
+
+    let s = |s: &str| s.to_owned();
+    let entries = vec![
+        ((s("a"), 1, 1), DbValue::Put(s("a1"))),
+        ((s("a"), 2, 4), DbValue::Delete),
+        ((s("a"), 3, 2), DbValue::Put(s("a3"))),
+        ((s("a"), 5, 3), DbValue::Put(s("a5"))),
+        ((s("b"), 1, 1), DbValue::Put(s("b1"))),
+        ((s("b"), 2, 2), DbValue::Merge(s("x"))),
+        ((s("b"), 3, 3), DbValue::Merge(s("y"))),
+        ((s("b"), 3, 4), DbValue::Merge(s("z"))),
+    ];
+    let compacted = |timestamp_low| {
+        Compaction {
+            merge_operator: Some(&ConcatOperator),
+            timestamp_low,
+            ..Compaction::new(&OrdComparator)
+        }
+        .compact(VecIter::new(Arc::new(entries.clone())), Vec::new())
+        .0
+        .collect::<Vec<_>>()
+    };
+
+    // Every timestamp can still be read, so only the operands written at the
+    // same one are folded together.
+    assert_eq!(
+        compacted(0),
+        vec![
+            ((s("a"), 1, 1), DbValue::Put(s("a1"))),
+            ((s("a"), 2, 4), DbValue::Delete),
+            ((s("a"), 3, 2), DbValue::Put(s("a3"))),
+            ((s("a"), 5, 3), DbValue::Put(s("a5"))),
+            ((s("b"), 1, 1), DbValue::Put(s("b1"))),
+            ((s("b"), 2, 2), DbValue::Merge(s("x"))),
+            ((s("b"), 3, 4), DbValue::Merge(s("y,z"))),
+        ]
+    );
+    // Below 4, only the newest version is needed.
+    assert_eq!(
+        compacted(4),
+        vec![
+            ((s("a"), 3, 2), DbValue::Put(s("a3"))),
+            ((s("a"), 5, 3), DbValue::Put(s("a5"))),
+            ((s("b"), 1, 1), DbValue::Put(s("b1"))),
+            ((s("b"), 3, 4), DbValue::Put(s("b1,x,y,z"))),
+        ]
+    );
+}
+
+#[test]
+fn test_compact_history_retention() {
+    use super::merge_operator::ConcatOperator;
+    use crate::{comparator::OrdComparator, memtable::VecIter};
+    use std::sync::Arc;
+
+    let s = |s: &str| s.to_owned();
+    let entries = vec![
+        ((s("a"), 0, 1), DbValue::Put(s("a1"))),
+        ((s("a"), 0, 3), DbValue::Merge(s("x"))),
+        ((s("a"), 0, 4), DbValue::Merge(s("y"))),
+        ((s("b"), 0, 1), DbValue::ExpiringPut(s("b1"), 10)),
+        ((s("b"), 0, 3), DbValue::ExpiringPut(s("b3"), 10)),
+    ];
+    let tombstones = vec![RangeTombstone {
+        start: s("a"),
+        end: s("b"),
+        seqnum: 2,
+    }];
+    let compacted = |retain_above| {
+        Compaction {
+            merge_operator: Some(&ConcatOperator),
+            bottommost: true,
+            now: 10,
+            retain_above,
+            ..Compaction::new(&OrdComparator)
+        }
+        .compact(VecIter::new(Arc::new(entries.clone())), tombstones.clone())
+    };
+    let compacted = |retain_above| {
+        let (entries, range_tombstones) = compacted(retain_above);
+        (entries.collect::<Vec<_>>(), range_tombstones)
+    };
+
+    assert_eq!(
+        compacted(usize::MAX),
+        (vec![((s("a"), 0, 4), DbValue::Put(s("x,y")))], vec![])
+    );
+    // Anything newer than the tombstone is left alone, so the tombstone has
+    // to stay to keep hiding what's under it.
+    assert_eq!(
+        compacted(1),
+        (
+            vec![
+                ((s("a"), 0, 3), DbValue::Merge(s("x"))),
+                ((s("a"), 0, 4), DbValue::Merge(s("y"))),
+                ((s("b"), 0, 3), DbValue::ExpiringPut(s("b3"), 10)),
+            ],
+            tombstones.clone()
+        )
+    );
+}
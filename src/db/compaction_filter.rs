@@ -0,0 +1,43 @@
+// A compaction filter sees every value as it's flushed or compacted, and can
+// drop or rewrite it. This is a cheap way to garbage collect data that the
+// application knows is dead (say, everything belonging to a deleted tenant)
+// without having to find and delete each key.
+//
+// Since values are filtered whenever flushes and compactions happen to get to
+// them, the filter has no control over when a change becomes visible, and
+// must give the same answer for a value each time it's asked. Like the merge
+// operator, its name is recorded with each column family that uses it.
+pub trait CompactionFilter<K, V>: std::fmt::Debug {
+    fn name(&self) -> &str;
+
+    fn filter(&self, key: &K, value: &V) -> FilterDecision<V>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision<V> {
+    Keep,
+    // The value is replaced with a delete, so that it still hides any older
+    // versions of its key.
+    Remove,
+    ChangeValue(V),
+}
+
+// Removes keys which start with a given prefix.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct PrefixFilter(pub &'static str);
+
+#[cfg(test)]
+impl CompactionFilter<String, String> for PrefixFilter {
+    fn name(&self) -> &str {
+        "prefix"
+    }
+
+    fn filter(&self, key: &String, _value: &String) -> FilterDecision<String> {
+        if key.starts_with(self.0) {
+            FilterDecision::Remove
+        } else {
+            FilterDecision::Keep
+        }
+    }
+}
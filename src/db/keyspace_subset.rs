@@ -3,6 +3,8 @@
 // --(------)------(---)-----
 //   bar    baz    foo zed
 // [(bar, baz), (foo, zed)]
+use crate::comparator::Comparator;
+
 #[derive(Debug, Clone)]
 pub struct KeyspaceSubset<K>
 where
@@ -34,16 +36,17 @@ where
         }
     }
 
-    pub fn intersects(&self, other: &KeyspaceSubset<K>) -> bool {
+    pub fn intersects(&self, comparator: &dyn Comparator<K>, other: &KeyspaceSubset<K>) -> bool {
         let mut my_idx = 0;
         let mut other_idx = 0;
         while my_idx < self.ranges.len() && other_idx < other.ranges.len() {
             let (a, b) = &self.ranges[my_idx];
-            let (c, d) = &other.ranges[my_idx];
-            if a <= d && c <= b {
+            let (c, d) = &other.ranges[other_idx];
+            if comparator.le(a, d) && comparator.le(c, b) {
                 return true;
             }
-            if a < c {
+            // Whichever range ends first can't intersect anything else.
+            if comparator.lt(b, d) {
                 my_idx += 1;
             } else {
                 other_idx += 1;
@@ -52,7 +55,11 @@ where
         false
     }
 
-    pub fn union(&self, other: &KeyspaceSubset<K>) -> KeyspaceSubset<K> {
+    pub fn union(
+        &self,
+        comparator: &dyn Comparator<K>,
+        other: &KeyspaceSubset<K>,
+    ) -> KeyspaceSubset<K> {
         let mut my_idx = 0;
         let mut other_idx = 0;
 
@@ -64,14 +71,14 @@ where
             let (c, d) = &other.ranges[other_idx];
             match state {
                 MergeState::NoNo => {
-                    if a < c {
+                    if comparator.lt(a, c) {
                         state = MergeState::YesNo(a.clone());
                     } else {
                         state = MergeState::NoYes(c.clone());
                     }
                 }
                 MergeState::YesNo(start) => {
-                    if c <= b {
+                    if comparator.le(c, b) {
                         state = MergeState::YesYes(start);
                     } else {
                         result.push((start, b.clone()));
@@ -80,7 +87,7 @@ where
                     }
                 }
                 MergeState::NoYes(start) => {
-                    if a <= d {
+                    if comparator.le(a, d) {
                         state = MergeState::YesYes(start);
                     } else {
                         result.push((start, d.clone()));
@@ -89,7 +96,7 @@ where
                     }
                 }
                 MergeState::YesYes(start) => {
-                    if b < d {
+                    if comparator.lt(b, d) {
                         state = MergeState::NoYes(start);
                         my_idx += 1;
                     } else {
@@ -128,13 +135,25 @@ where
 
 #[test]
 fn test_keyspace_subset() {
+    use crate::comparator::OrdComparator;
+
     let subset1 = KeyspaceSubset::new_from_singleton((1, 3));
     let subset2 = KeyspaceSubset::new_from_singleton((2, 4));
     let subset3 = KeyspaceSubset::new_from_singleton((4, 5));
 
-    assert!(subset1.intersects(&subset2));
-    assert!(!subset1.intersects(&subset3));
-    assert!(subset2.intersects(&subset3));
+    assert!(subset1.intersects(&OrdComparator, &subset2));
+    assert!(!subset1.intersects(&OrdComparator, &subset3));
+    assert!(subset2.intersects(&OrdComparator, &subset3));
+
+    let multi = KeyspaceSubset {
+        ranges: vec![(1, 2), (10, 20)],
+    };
+    assert!(multi.intersects(
+        &OrdComparator,
+        &KeyspaceSubset::new_from_singleton((15, 16))
+    ));
+    assert!(KeyspaceSubset::new_from_singleton((15, 16)).intersects(&OrdComparator, &multi));
+    assert!(!multi.intersects(&OrdComparator, &KeyspaceSubset::new_from_singleton((3, 9))));
 
     let mut unioned = KeyspaceSubset::new();
     let interval_tests = [
@@ -144,7 +163,10 @@ fn test_keyspace_subset() {
         ((2, 3), vec![(1, 4), (6, 7)]),
     ];
     for (interval, expected) in interval_tests {
-        unioned = unioned.union(&KeyspaceSubset::new_from_singleton(interval));
+        unioned = unioned.union(
+            &OrdComparator,
+            &KeyspaceSubset::new_from_singleton(interval),
+        );
         assert_eq!(unioned.ranges, expected);
     }
 
@@ -159,7 +181,7 @@ fn test_keyspace_subset() {
     for (l, r, expected) in union_tests {
         let a = KeyspaceSubset { ranges: l };
         let b = KeyspaceSubset { ranges: r };
-        let union = a.union(&b);
+        let union = a.union(&OrdComparator, &b);
         assert_eq!(union.ranges, expected);
     }
 }
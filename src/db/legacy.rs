@@ -0,0 +1,187 @@
+// Before the manifest, a database's layout was kept in ROOT, as JSON which was
+// rewritten on every change, and its SSTs had no checksums, format version or
+// timestamps. Opening such a database moves it over: every SST is rewritten in
+// the current format, and the manifest starts out describing the new ones.
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+
+use crate::{
+    comparator::{Comparator, InternalKeyComparator, OrdComparator},
+    encoding::{Decode, Encode},
+    fs::DbDir,
+    memtable::DbValue,
+    root::Versioned,
+    sst::{legacy, writer::SstWriter},
+};
+
+use super::{
+    internal_key_format, options::DbOptions, Db, DiskLayout, VersionEdit, DEFAULT_COLUMN_FAMILY,
+    DEFAULT_COLUMN_FAMILY_NAME,
+};
+
+// What ROOT held. There was only one column family then.
+#[derive(Debug, Deserialize)]
+struct LegacyDiskLayout {
+    max_sst_seqnum: usize,
+    next_sst_id: usize,
+    l0: Vec<String>,
+    ssts: Vec<Vec<String>>,
+    wals: Vec<String>,
+}
+
+impl<D, K, V> Db<D, K, V>
+where
+    D: DbDir + std::fmt::Debug + 'static,
+    K: Ord + Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
+    V: Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
+{
+    // Turns the contents of a legacy ROOT into the layout to start the
+    // manifest with, rewriting its SSTs under new names. The old SSTs are
+    // added to `obsolete`, to be removed once the manifest has been written.
+    pub(super) fn migrate_legacy_root(
+        dir: &mut D,
+        buf: &[u8],
+        options: &DbOptions<K, V>,
+        obsolete: &mut Vec<String>,
+    ) -> anyhow::Result<DiskLayout> {
+        let legacy: LegacyDiskLayout = serde_json::from_slice(buf)?;
+        let comparator = options.comparator.name();
+        if comparator != Comparator::<K>::name(&OrdComparator) {
+            bail!(
+                "legacy databases are ordered by Ord, so can't be opened with comparator {}",
+                comparator
+            );
+        }
+        if options.timestamps {
+            bail!("legacy databases don't have timestamps, so can't be opened with them");
+        }
+
+        let mut edits = vec![
+            VersionEdit::SetComparator(comparator.to_owned()),
+            VersionEdit::set_family_options(DEFAULT_COLUMN_FAMILY, options),
+            VersionEdit::MaxSstSeqnum {
+                cf: DEFAULT_COLUMN_FAMILY,
+                seqnum: legacy.max_sst_seqnum,
+            },
+        ];
+        let mut next_sst_id = legacy.next_sst_id;
+        let levels = std::iter::once(&legacy.l0).chain(&legacy.ssts);
+        for (level, names) in levels.enumerate() {
+            for (index, name) in names.iter().enumerate() {
+                let file = dir
+                    .open(name)
+                    .ok_or_else(|| anyhow!("{} does not exist", name))?;
+                let entries = legacy::read_entries::<(K, usize), Option<V>, _>(&file)
+                    .map_err(|e| anyhow!("couldn't read legacy sst {}: {}", name, e))?;
+
+                let new_name = format!("sst{}.sst", next_sst_id);
+                next_sst_id += 1;
+                dir.unlink(&new_name)?;
+                let file = dir
+                    .create(&new_name)?
+                    .ok_or_else(|| anyhow!("{} already exists", new_name))?;
+                let entries = entries.into_iter().map(|((k, seqnum), v)| {
+                    let v = match v {
+                        Some(v) => DbValue::Put(v),
+                        None => DbValue::Delete,
+                    };
+                    ((k, 0, seqnum), v)
+                });
+                SstWriter::new(entries, file)
+                    .with_comparator(InternalKeyComparator::wrap(options.comparator.clone()))
+                    .with_key_format(internal_key_format(false))
+                    .with_column_family(
+                        DEFAULT_COLUMN_FAMILY,
+                        DEFAULT_COLUMN_FAMILY_NAME.to_owned(),
+                    )
+                    .write()?;
+
+                edits.push(VersionEdit::AddSst {
+                    cf: DEFAULT_COLUMN_FAMILY,
+                    level,
+                    index,
+                    sst: Self::read_sst_metadata(dir, &new_name, options)?.1,
+                });
+                obsolete.push(name.clone());
+            }
+        }
+        edits.push(VersionEdit::NextSstId(next_sst_id));
+        edits.extend(legacy.wals.into_iter().map(VersionEdit::AddWal));
+
+        let mut layout = DiskLayout::new();
+        for edit in edits {
+            layout.apply(edit)?;
+        }
+        Ok(layout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        fs::{DbDir, DbFile, MockDir},
+        log::file_log::Log,
+        sst::legacy::write_entries,
+    };
+
+    use super::super::{options::DbOptions, DBCommand, Db};
+
+    #[test]
+    fn test_legacy_root() {
+        let mut dir = MockDir::new();
+        let put = |k: &str, seqnum, v: &str| ((k.to_owned(), seqnum), Some(v.to_owned()));
+        let delete = |k: &str, seqnum| ((k.to_owned(), seqnum), None);
+        let mut sst = |name: &str, entries: &[((String, usize), Option<String>)]| {
+            let mut file = dir.create(&name).unwrap().unwrap();
+            write_entries(&mut file, entries).unwrap();
+        };
+        sst("sst0.sst", &[put("d", 1, "d1"), put("e", 2, "e2")]);
+        sst("sst1.sst", &[put("a", 3, "a3"), delete("d", 4)]);
+        let mut wal = Log::new(dir.clone(), 5).unwrap();
+        wal.write(&DBCommand::Write(5, "f".to_owned(), "f5".to_owned()))
+            .unwrap();
+        let wal_name = wal.fname().to_owned();
+        drop(wal);
+        let root = format!(
+            r#"{{"max_sst_seqnum":4,"next_sst_id":2,"l0":["sst1.sst"],"ssts":[["sst0.sst"]],"wals":["{}"]}}"#,
+            wal_name
+        );
+        dir.create(&"ROOT")
+            .unwrap()
+            .unwrap()
+            .write(root.as_bytes())
+            .unwrap();
+
+        // A failed migration leaves everything as it was.
+        assert!(Db::<_, String, String>::with_options(
+            dir.clone(),
+            DbOptions {
+                timestamps: true,
+                ..Default::default()
+            }
+        )
+        .is_err());
+        assert!(dir.open(&"ROOT").is_some());
+
+        let expected = vec![
+            ("a".to_owned(), "a3".to_owned()),
+            ("e".to_owned(), "e2".to_owned()),
+            ("f".to_owned(), "f5".to_owned()),
+        ];
+        let mut db: Db<_, String, String> = Db::new(dir.clone()).unwrap();
+        assert_eq!(db.scan().unwrap().collect::<Vec<_>>(), expected);
+        let family = &db.root.data.families[0];
+        assert_eq!(family.l0[0].filename, "sst2.sst");
+        assert_eq!(family.ssts[0][0].filename, "sst3.sst");
+        assert_eq!((family.l0[0].min_seqnum, family.l0[0].max_seqnum), (3, 4));
+        for name in ["ROOT", "sst0.sst", "sst1.sst"] {
+            assert!(dir.open(&name).is_none(), "{} is still there", name);
+        }
+        drop(db);
+
+        let mut db: Db<_, String, String> = Db::new(dir).unwrap();
+        assert_eq!(db.scan().unwrap().collect::<Vec<_>>(), expected);
+        db.merge(vec![(0, 0)], 1).unwrap();
+        assert_eq!(db.scan().unwrap().collect::<Vec<_>>(), expected);
+    }
+}
@@ -1,104 +1,277 @@
-use std::marker::PhantomData;
+// Iterates over a sorted run of files that don't overlap, such as a level
+// below L0. Only the bounds of each file are kept up front; a file is opened
+// when the iterator moves into it. Opening a file means reading its footer and
+// index, so the one before is kept open too, and stepping back and forth over
+// a boundary between two files doesn't reopen either of them.
+use std::{marker::PhantomData, sync::Arc};
 
-use crate::memtable::KVIter;
+use crate::{
+    comparator::{Comparator, OrdComparator},
+    memtable::KVIter,
+};
+
+type Opener<I> = Box<dyn FnMut(usize) -> anyhow::Result<I>>;
 
-#[derive(Debug)]
 pub struct LevelIter<K, V, I>
 where
     K: Ord,
     I: KVIter<K, V>,
 {
-    iters: Vec<I>,
+    // The (min, max) keys of each file, in order.
+    bounds: Vec<(K, K)>,
+    open: Opener<I>,
     idx: usize,
-    index: Vec<(K, usize)>,
+    // The open file, and which one it is.
+    current: Option<(usize, I)>,
+    // The file that was open before `current`.
+    previous: Option<(usize, I)>,
+    // Why a file couldn't be opened. Iteration stops until it's taken.
+    error: Option<anyhow::Error>,
+    comparator: Arc<dyn Comparator<K>>,
     _marker: PhantomData<(K, V)>,
 }
 
+impl<K, V, I> std::fmt::Debug for LevelIter<K, V, I>
+where
+    K: Ord + std::fmt::Debug,
+    I: KVIter<K, V> + std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LevelIter")
+            .field("bounds", &self.bounds)
+            .field("idx", &self.idx)
+            .field("current", &self.current)
+            .finish()
+    }
+}
+
 impl<K, V, I> LevelIter<K, V, I>
 where
     K: Clone + Ord,
     I: KVIter<K, V>,
 {
-    pub fn new<C: IntoIterator<Item = I>>(c: C) -> Self {
-        let mut index: Vec<(K, usize)> = Vec::new();
-        let mut iters = Vec::new();
-        for (i, mut v) in c.into_iter().enumerate() {
-            if let Some(entry) = v.peek().map(|(k, _v)| (k.clone(), i)) {
-                index.push(entry);
-            }
-
-            iters.push(v);
-        }
-
-        assert!(!iters.is_empty());
+    // `open(i)` opens the file with the `i`th of `bounds`, positioned at its
+    // start.
+    pub fn new<F>(bounds: Vec<(K, K)>, open: F) -> Self
+    where
+        F: FnMut(usize) -> anyhow::Result<I> + 'static,
+    {
+        assert!(!bounds.is_empty());
 
         LevelIter {
-            iters,
-            index,
+            bounds,
+            open: Box::new(open),
             idx: 0,
+            current: None,
+            previous: None,
+            error: None,
+            comparator: Arc::new(OrdComparator),
             _marker: PhantomData,
         }
     }
+
+    pub fn with_comparator(mut self, comparator: Arc<dyn Comparator<K>>) -> Self {
+        self.comparator = comparator;
+        self
+    }
+
+    // The file at `idx`, opening it if it isn't already. Returns `None` if it
+    // couldn't be opened, or an earlier error hasn't been taken yet.
+    fn file(&mut self) -> Option<&mut I> {
+        if self.error.is_some() {
+            return None;
+        }
+        if self
+            .current
+            .as_ref()
+            .is_none_or(|(idx, _)| *idx != self.idx)
+        {
+            if self
+                .previous
+                .as_ref()
+                .is_some_and(|(idx, _)| *idx == self.idx)
+            {
+                std::mem::swap(&mut self.current, &mut self.previous);
+            } else {
+                match (self.open)(self.idx) {
+                    Ok(file) => self.previous = self.current.replace((self.idx, file)),
+                    Err(err) => {
+                        self.error = Some(err);
+                        return None;
+                    }
+                }
+            }
+        }
+        self.current.as_mut().map(|(_, file)| file)
+    }
+
+    // Moves forward past any files with nothing left in them, unless there
+    // are no more files after them.
+    fn skip_forward(&mut self) -> Option<()> {
+        while self.idx < self.bounds.len() - 1 && self.file()?.peek().is_none() {
+            self.idx += 1;
+            self.file()?.start();
+        }
+        Some(())
+    }
+
+    fn skip_backward(&mut self) -> Option<()> {
+        while self.idx > 0 && self.file()?.peek_prev().is_none() {
+            self.idx -= 1;
+            self.file()?.end();
+        }
+        Some(())
+    }
 }
 
 impl<K, V, I> KVIter<K, V> for LevelIter<K, V, I>
 where
-    K: Ord + std::fmt::Debug,
+    K: Clone + Ord + std::fmt::Debug,
     V: std::fmt::Debug,
     I: KVIter<K, V> + std::fmt::Debug,
 {
     fn next(&mut self) -> Option<(&K, &V)> {
-        while self.idx < self.iters.len() - 1 && self.iters[self.idx].peek().is_none() {
-            self.idx += 1;
-            self.iters[self.idx].start();
-        }
-        self.iters[self.idx].next()
+        self.skip_forward()?;
+        self.file()?.next()
     }
 
     fn peek(&mut self) -> Option<(&K, &V)> {
-        while self.idx < self.iters.len() - 1 && self.iters[self.idx].peek().is_none() {
-            self.idx += 1;
-            self.iters[self.idx].start();
-        }
-        self.iters[self.idx].peek()
+        self.skip_forward()?;
+        self.file()?.peek()
     }
 
     fn prev(&mut self) -> Option<(&K, &V)> {
-        while self.idx > 0 && self.iters[self.idx].peek_prev().is_none() {
-            self.idx += 1;
-            self.iters[self.idx].end();
-        }
-        self.iters[self.idx].prev()
+        self.skip_backward()?;
+        self.file()?.prev()
     }
 
     fn peek_prev(&mut self) -> Option<(&K, &V)> {
-        while self.idx > 0 && self.iters[self.idx].peek_prev().is_none() {
-            self.idx += 1;
-            self.iters[self.idx].end();
-        }
-        self.iters[self.idx].peek_prev()
+        self.skip_backward()?;
+        self.file()?.peek_prev()
     }
 
     fn seek_ge(&mut self, key: &K) {
-        self.idx = match self.index.binary_search_by_key(&key, |(k, _)| k) {
-            Ok(i) => i,
-            Err(i) => std::cmp::min(i.saturating_sub(1), self.iters.len() - 1),
-        };
-
-        self.iters[self.idx].seek_ge(key);
+        // The first file that ends at or after `key`.
+        let idx = self
+            .bounds
+            .partition_point(|(_, max)| self.comparator.lt(max, key));
+        if idx == self.bounds.len() {
+            self.end();
+        } else {
+            self.idx = idx;
+            if let Some(file) = self.file() {
+                file.seek_ge(key);
+            }
+        }
     }
 
     fn start(&mut self) {
-        if !self.iters.is_empty() {
-            self.idx = 0;
-            self.iters[self.idx].start();
+        self.idx = 0;
+        if let Some(file) = self.file() {
+            file.start();
         }
     }
 
     fn end(&mut self) {
-        if !self.iters.is_empty() {
-            self.idx = self.iters.len() - 1;
-            self.iters[self.idx].end();
+        self.idx = self.bounds.len() - 1;
+        if let Some(file) = self.file() {
+            file.end();
+        }
+    }
+
+    fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.error.take().or_else(|| {
+            [&mut self.current, &mut self.previous]
+                .into_iter()
+                .flatten()
+                .find_map(|(_, file)| file.take_error())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, rc::Rc, sync::Arc};
+
+    use crate::memtable::{KVIter, VecIter};
+
+    use super::LevelIter;
+
+    #[test]
+    fn test_level_iter() {
+        let files: Vec<Vec<(usize, usize)>> =
+            vec![vec![(1, 1), (2, 2)], vec![(5, 5)], vec![(7, 7), (9, 9)]];
+        let bounds = files.iter().map(|f| (f[0].0, f[f.len() - 1].0)).collect();
+        let opened = Rc::new(RefCell::new(Vec::new()));
+        let mut it = LevelIter::new(bounds, {
+            let opened = opened.clone();
+            move |i| {
+                opened.borrow_mut().push(i);
+                Ok(VecIter::new(Arc::new(files[i].clone())))
+            }
+        });
+
+        // Nothing is opened until it's needed, and a seek only opens the one
+        // file it lands in.
+        assert!(opened.borrow().is_empty());
+        it.seek_ge(&6);
+        assert_eq!(*opened.borrow(), vec![2]);
+        assert_eq!(it.next().map(|(k, _)| *k), Some(7));
+
+        // Going backwards crosses into earlier files.
+        let mut keys = Vec::new();
+        it.end();
+        while let Some((k, _)) = it.prev() {
+            keys.push(*k);
         }
+        assert_eq!(keys, vec![9, 7, 5, 2, 1]);
+
+        let mut keys = Vec::new();
+        it.start();
+        while let Some((k, _)) = it.next() {
+            keys.push(*k);
+        }
+        assert_eq!(keys, vec![1, 2, 5, 7, 9]);
+
+        it.seek_ge(&10);
+        assert!(it.peek().is_none());
+        assert_eq!(it.peek_prev().map(|(k, _)| *k), Some(9));
+
+        // Stepping back and forth over a boundary doesn't reopen anything.
+        it.seek_ge(&5);
+        opened.borrow_mut().clear();
+        for _ in 0..3 {
+            assert_eq!(it.prev().map(|(k, _)| *k), Some(2));
+            assert_eq!(it.next().map(|(k, _)| *k), Some(2));
+            assert_eq!(it.next().map(|(k, _)| *k), Some(5));
+            assert_eq!(it.prev().map(|(k, _)| *k), Some(5));
+        }
+        assert_eq!(*opened.borrow(), vec![0]);
+    }
+
+    #[test]
+    fn test_level_iter_open_error() {
+        let files: Vec<Vec<(usize, usize)>> =
+            vec![vec![(1, 1), (2, 2)], vec![(5, 5)], vec![(7, 7), (9, 9)]];
+        let bounds = files.iter().map(|f| (f[0].0, f[f.len() - 1].0)).collect();
+        let mut it = LevelIter::new(bounds, move |i| {
+            if i == 1 {
+                anyhow::bail!("sst1.sst is corrupt");
+            }
+            Ok(VecIter::new(Arc::new(files[i].clone())))
+        });
+
+        // The iterator stops at the file it can't open, and says why.
+        let mut keys = Vec::new();
+        it.start();
+        while let Some((k, _)) = it.next() {
+            keys.push(*k);
+        }
+        assert_eq!(keys, vec![1, 2]);
+        assert_eq!(it.take_error().unwrap().to_string(), "sst1.sst is corrupt");
+        assert!(it.take_error().is_none());
+
+        it.seek_ge(&6);
+        assert_eq!(it.next().map(|(k, _)| *k), Some(7));
     }
 }
@@ -0,0 +1,302 @@
+// Per-key locks for pessimistic transactions.
+//
+// A key can be locked by any number of transactions in shared mode, or by a
+// single transaction in exclusive mode. A transaction holding the only shared
+// lock on a key can upgrade it to exclusive.
+//
+// Waiters block on a condvar for up to a timeout. Before waiting, a
+// transaction records who it is waiting for in the wait-for graph, and if
+// that would close a cycle it fails with `LockError::Deadlock` instead, so
+// one member of every deadlock backs out right away rather than timing out.
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+pub type TxnId = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockError {
+    Timeout,
+    Deadlock,
+}
+
+impl std::fmt::Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Timeout => write!(f, "timed out waiting for a lock"),
+            LockError::Deadlock => write!(f, "deadlock detected"),
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+#[derive(Debug, Default)]
+struct KeyLock {
+    shared: HashSet<TxnId>,
+    exclusive: Option<TxnId>,
+}
+
+impl KeyLock {
+    // The transactions that stand in the way of `txn` taking the lock in
+    // `mode`.
+    fn blockers(&self, txn: TxnId, mode: LockMode) -> HashSet<TxnId> {
+        let mut blockers = HashSet::new();
+        if let Some(holder) = self.exclusive {
+            if holder != txn {
+                blockers.insert(holder);
+            }
+        }
+        if mode == LockMode::Exclusive {
+            blockers.extend(self.shared.iter().filter(|t| **t != txn));
+        }
+        blockers
+    }
+
+    fn is_free(&self) -> bool {
+        self.shared.is_empty() && self.exclusive.is_none()
+    }
+}
+
+#[derive(Debug)]
+struct LockTable<K> {
+    locks: HashMap<K, KeyLock>,
+    // The keys each transaction holds a lock on.
+    held: HashMap<TxnId, HashSet<K>>,
+    // Edges of the wait-for graph: each waiting transaction, and the
+    // transactions it's waiting on.
+    waits_for: HashMap<TxnId, HashSet<TxnId>>,
+}
+
+impl<K> LockTable<K> {
+    // Whether `to` can be reached from `from` in the wait-for graph.
+    fn reachable(&self, from: TxnId, to: TxnId) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = vec![from];
+        while let Some(txn) = stack.pop() {
+            if txn == to {
+                return true;
+            }
+            if seen.insert(txn) {
+                if let Some(next) = self.waits_for.get(&txn) {
+                    stack.extend(next.iter().copied());
+                }
+            }
+        }
+        false
+    }
+}
+
+#[derive(Debug)]
+pub struct LockManager<K> {
+    table: Mutex<LockTable<K>>,
+    // Signalled whenever a lock is released.
+    released: Condvar,
+    next_txn_id: AtomicUsize,
+}
+
+impl<K> LockManager<K> {
+    pub fn new() -> Self {
+        LockManager {
+            table: Mutex::new(LockTable {
+                locks: HashMap::new(),
+                held: HashMap::new(),
+                waits_for: HashMap::new(),
+            }),
+            released: Condvar::new(),
+            next_txn_id: AtomicUsize::new(1),
+        }
+    }
+
+    // Allocates an id for a new transaction.
+    pub fn begin(&self) -> TxnId {
+        self.next_txn_id.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+impl<K> LockManager<K>
+where
+    K: Hash + Eq + Clone,
+{
+    // Takes a lock on `key` for `txn`, waiting up to `timeout` for any
+    // conflicting locks to be released. Taking a lock that's already held is
+    // a no-op.
+    pub fn lock(
+        &self,
+        txn: TxnId,
+        key: &K,
+        mode: LockMode,
+        timeout: Duration,
+    ) -> Result<(), LockError> {
+        let deadline = Instant::now() + timeout;
+        let mut table = self.table.lock().unwrap();
+        loop {
+            let blockers = table
+                .locks
+                .get(key)
+                .map(|lock| lock.blockers(txn, mode))
+                .unwrap_or_default();
+            if blockers.is_empty() {
+                table.waits_for.remove(&txn);
+                let lock = table.locks.entry(key.clone()).or_default();
+                match mode {
+                    LockMode::Shared => {
+                        if lock.exclusive != Some(txn) {
+                            lock.shared.insert(txn);
+                        }
+                    }
+                    LockMode::Exclusive => {
+                        lock.shared.remove(&txn);
+                        lock.exclusive = Some(txn);
+                    }
+                }
+                table.held.entry(txn).or_default().insert(key.clone());
+                return Ok(());
+            }
+
+            if blockers.iter().any(|b| table.reachable(*b, txn)) {
+                table.waits_for.remove(&txn);
+                return Err(LockError::Deadlock);
+            }
+            table.waits_for.insert(txn, blockers);
+
+            let now = Instant::now();
+            if now >= deadline {
+                table.waits_for.remove(&txn);
+                return Err(LockError::Timeout);
+            }
+            table = self.released.wait_timeout(table, deadline - now).unwrap().0;
+        }
+    }
+
+    // Releases every lock held by `txn`.
+    pub fn unlock_all(&self, txn: TxnId) {
+        let mut table = self.table.lock().unwrap();
+        table.waits_for.remove(&txn);
+        for key in table.held.remove(&txn).unwrap_or_default() {
+            if let Some(lock) = table.locks.get_mut(&key) {
+                lock.shared.remove(&txn);
+                if lock.exclusive == Some(txn) {
+                    lock.exclusive = None;
+                }
+                if lock.is_free() {
+                    table.locks.remove(&key);
+                }
+            }
+        }
+        self.released.notify_all();
+    }
+
+    // The keys currently locked by `txn`.
+    pub fn held(&self, txn: TxnId) -> HashSet<K> {
+        let table = self.table.lock().unwrap();
+        table.held.get(&txn).cloned().unwrap_or_default()
+    }
+}
+
+impl<K> Default for LockManager<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{mpsc, Arc},
+        thread,
+        time::Duration,
+    };
+
+    use super::{LockError, LockManager, LockMode};
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn test_shared_and_exclusive() {
+        let lm = LockManager::new();
+        let (a, b) = (lm.begin(), lm.begin());
+
+        lm.lock(a, &"k", LockMode::Shared, TIMEOUT).unwrap();
+        lm.lock(b, &"k", LockMode::Shared, TIMEOUT).unwrap();
+        // Neither can upgrade while the other holds a shared lock.
+        assert_eq!(
+            lm.lock(a, &"k", LockMode::Exclusive, TIMEOUT),
+            Err(LockError::Timeout)
+        );
+
+        lm.unlock_all(b);
+        lm.lock(a, &"k", LockMode::Exclusive, TIMEOUT).unwrap();
+        assert_eq!(
+            lm.lock(b, &"k", LockMode::Shared, TIMEOUT),
+            Err(LockError::Timeout)
+        );
+        // Re-locking in a weaker mode keeps the exclusive lock.
+        lm.lock(a, &"k", LockMode::Shared, TIMEOUT).unwrap();
+        assert_eq!(
+            lm.lock(b, &"k", LockMode::Shared, TIMEOUT),
+            Err(LockError::Timeout)
+        );
+
+        lm.unlock_all(a);
+        lm.lock(b, &"k", LockMode::Exclusive, TIMEOUT).unwrap();
+        assert!(lm.held(a).is_empty());
+    }
+
+    #[test]
+    fn test_waiter_wakes_on_release() {
+        let lm = Arc::new(LockManager::new());
+        let (a, b) = (lm.begin(), lm.begin());
+        lm.lock(a, &"k", LockMode::Exclusive, TIMEOUT).unwrap();
+
+        let waiter = {
+            let lm = lm.clone();
+            thread::spawn(move || lm.lock(b, &"k", LockMode::Exclusive, Duration::from_secs(10)))
+        };
+        thread::sleep(Duration::from_millis(20));
+        lm.unlock_all(a);
+        waiter.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_deadlock_detection() {
+        let lm = Arc::new(LockManager::new());
+        let (a, b) = (lm.begin(), lm.begin());
+        lm.lock(a, &"x", LockMode::Exclusive, TIMEOUT).unwrap();
+        lm.lock(b, &"y", LockMode::Exclusive, TIMEOUT).unwrap();
+
+        // a waits on b...
+        let (tx, rx) = mpsc::channel();
+        let waiter = {
+            let lm = lm.clone();
+            thread::spawn(move || {
+                tx.send(()).unwrap();
+                let result = lm.lock(a, &"y", LockMode::Exclusive, Duration::from_secs(10));
+                lm.unlock_all(a);
+                result
+            })
+        };
+        rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(20));
+
+        // ...so b waiting on a would never finish. Whichever of the two closes
+        // the cycle is told so, and backing it out lets the other through.
+        let result = lm.lock(b, &"x", LockMode::Exclusive, Duration::from_secs(10));
+        lm.unlock_all(b);
+        let results = [result, waiter.join().unwrap()];
+        assert!(results.contains(&Err(LockError::Deadlock)));
+        assert!(results.contains(&Ok(())));
+    }
+}
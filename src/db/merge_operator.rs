@@ -0,0 +1,48 @@
+// A merge operator lets a write describe a change to a value ("add 3",
+// "append x") rather than the new value itself. Those changes, called
+// _operands_, are stored like any other write, and folded onto whatever value
+// they apply to when the key is read, or ahead of time during compaction.
+//
+// Because folding happens wherever the operands end up being read, the
+// operator must be deterministic, and must be configured whenever a database
+// that contains operands is opened. Its name is recorded with each column
+// family that uses it, which can only be opened with the same one.
+pub trait MergeOperator<K, V>: std::fmt::Debug {
+    fn name(&self) -> &str;
+
+    // Applies `operands`, oldest first, to `existing`, which is `None` if the
+    // key had no value (either it was never written, or it was deleted).
+    fn full_merge(&self, key: &K, existing: Option<&V>, operands: &[V]) -> V;
+
+    // Combines `operands`, oldest first, into a single operand with the same
+    // effect, if possible. This is used during compaction when the value the
+    // operands apply to isn't part of the compaction.
+    fn partial_merge(&self, _key: &K, _operands: &[V]) -> Option<V> {
+        None
+    }
+}
+
+// Joins operands onto the existing value with commas.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ConcatOperator;
+
+#[cfg(test)]
+impl<K> MergeOperator<K, String> for ConcatOperator {
+    fn name(&self) -> &str {
+        "concat"
+    }
+
+    fn full_merge(&self, _key: &K, existing: Option<&String>, operands: &[String]) -> String {
+        existing
+            .into_iter()
+            .chain(operands.iter())
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn partial_merge(&self, _key: &K, operands: &[String]) -> Option<String> {
+        Some(operands.join(","))
+    }
+}
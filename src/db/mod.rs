@@ -1,33 +1,73 @@
 #![allow(dead_code)]
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
+    hash::Hash,
     marker::PhantomData,
     sync::atomic::{AtomicUsize, Ordering},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    encoding::{Decode, Encode},
-    fs::DbDir,
+    comparator::{Comparator, InternalKeyComparator},
+    encoding::{Bytes, Decode, Encode, KeyReader, KeyWriter},
+    fs::{self, DbDir, DbFile},
     log::{
         file_log::{Log, LogReader},
         LogEntry,
     },
-    memtable::{KVIter, Memtable, MergingIter, SeqnumIter},
-    root::Root,
-    sst::{reader::SstReader, writer::SstWriter},
+    memtable::{
+        DbValue, KVIter, Memtable, MergingIter, RangeTombstone, RangeTombstones, SeqnumIter,
+    },
+    root::{Root, Versioned},
+    sst::{reader::SstReader, writer::SstWriter, Encoded, KeyFormat},
 };
 
-use self::{keyspace_subset::KeyspaceSubset, level_iter::LevelIter};
+use self::{
+    compaction::Compaction,
+    keyspace_subset::KeyspaceSubset,
+    level_iter::LevelIter,
+    lock_manager::{LockManager, LockMode},
+    options::{DbOptions, HistoryRetention, WalArchive},
+    snapshot::{Snapshot, SnapshotList},
+    transaction::{PessimisticTransaction, Transaction, TransactionConflict},
+    write_batch::WriteBatch,
+    write_buffer_manager::WriteBufferManager,
+    write_stall::{WriteStall, WriteStallCause, WriteStopped},
+};
 
+mod backup;
+mod clock;
+mod compaction;
+mod compaction_filter;
 mod keyspace_subset;
+mod legacy;
 mod level_iter;
-mod merging_iter;
+mod lock_manager;
+pub(crate) mod merge_operator;
 #[cfg(test)]
 mod metamorphic_test;
+mod options;
+mod repair;
+mod snapshot;
 #[cfg(test)]
 mod trace_test;
+mod transaction;
+mod typed;
+mod verify;
+mod write_batch;
+mod write_buffer_manager;
+mod write_stall;
+
+type BoxedInternalIter<K, V> = Box<dyn KVIter<(K, u64, usize), DbValue<V>>>;
+// Every version of every key, along with the range tombstones that apply to
+// them.
+type InternalIter<K, V> = (
+    MergingIter<BoxedInternalIter<K, V>, (K, u64, usize), DbValue<V>>,
+    Arc<RangeTombstones<K>>,
+);
 
 struct DbIterator<K, V, I>
 where
@@ -52,6 +92,21 @@ where
     }
 }
 
+impl<K, V, I> DbIterator<K, V, I>
+where
+    K: Ord,
+    I: KVIter<K, V>,
+{
+    // Whether the iteration ended because of an error rather than because it
+    // ran out of entries. Check this once `next` returns `None`.
+    fn status(&mut self) -> anyhow::Result<()> {
+        match self.iter.take_error() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum DBCommand<K, V>
 where
@@ -60,6 +115,59 @@ where
 {
     Write(usize, K, V),
     Delete(usize, K),
+    Merge(usize, K, V),
+    // A write which expires at the given time, according to the database's
+    // clock.
+    ExpiringWrite(usize, K, V, u64),
+    // Deletes every key in [start, end).
+    DeleteRange(usize, K, K),
+    // Commands which are applied atomically. They all share the batch's
+    // seqnum.
+    Batch(usize, Vec<DBCommand<K, V>>),
+    // A command against a column family other than the default one.
+    Family(ColumnFamilyId, Box<DBCommand<K, V>>),
+    // A write or delete at an application-supplied timestamp. Everything
+    // else is at timestamp 0.
+    AtTimestamp(u64, Box<DBCommand<K, V>>),
+}
+
+impl<K, V> DBCommand<K, V>
+where
+    K: std::fmt::Debug + Encode,
+    V: std::fmt::Debug + Encode,
+{
+    // This command, written at timestamp `ts`.
+    fn at_timestamp(self, ts: u64) -> Self {
+        DBCommand::AtTimestamp(ts, Box::new(self))
+    }
+
+    // This command, applied to `cf` rather than the default family.
+    fn in_family(self, cf: ColumnFamilyId) -> Self {
+        if cf == DEFAULT_COLUMN_FAMILY {
+            self
+        } else {
+            DBCommand::Family(cf, Box::new(self))
+        }
+    }
+
+    // This command, with any point writes that weren't given a timestamp
+    // written at `ts` instead.
+    fn with_default_timestamp(self, ts: u64) -> Self {
+        match self {
+            DBCommand::Batch(seqnum, commands) => DBCommand::Batch(
+                seqnum,
+                commands
+                    .into_iter()
+                    .map(|command| command.with_default_timestamp(ts))
+                    .collect(),
+            ),
+            DBCommand::Family(cf, command) => {
+                DBCommand::Family(cf, Box::new(command.with_default_timestamp(ts)))
+            }
+            DBCommand::AtTimestamp(..) | DBCommand::DeleteRange(..) => self,
+            command => command.at_timestamp(ts),
+        }
+    }
 }
 
 impl<K, V> Encode for DBCommand<K, V>
@@ -75,6 +183,37 @@ where
             DBCommand::Delete(seqnum, k) => {
                 (1_u8, (seqnum, k)).write_bytes(kw);
             }
+            DBCommand::Merge(seqnum, k, v) => {
+                (2_u8, (seqnum, (k, v))).write_bytes(kw);
+            }
+            DBCommand::DeleteRange(seqnum, start, end) => {
+                (3_u8, (seqnum, (start, end))).write_bytes(kw);
+            }
+            DBCommand::Batch(seqnum, commands) => {
+                (4_u8, (seqnum, commands)).write_bytes(kw);
+            }
+            DBCommand::Family(cf, command) => {
+                (5_u8, (cf, command.as_ref())).write_bytes(kw);
+            }
+            DBCommand::ExpiringWrite(seqnum, k, v, deadline) => {
+                (6_u8, (seqnum, (deadline, (k, v)))).write_bytes(kw);
+            }
+            DBCommand::AtTimestamp(ts, command) => {
+                (7_u8, (ts, command.as_ref())).write_bytes(kw);
+            }
+        }
+    }
+
+    fn needs_delimiter(&self) -> bool {
+        match self {
+            DBCommand::Write(_, _, v)
+            | DBCommand::Merge(_, _, v)
+            | DBCommand::ExpiringWrite(_, _, v, _) => v.needs_delimiter(),
+            DBCommand::Delete(_, k) | DBCommand::DeleteRange(_, _, k) => k.needs_delimiter(),
+            DBCommand::Batch(_, _) => false,
+            DBCommand::Family(_, command) | DBCommand::AtTimestamp(_, command) => {
+                command.needs_delimiter()
+            }
         }
     }
 }
@@ -96,6 +235,30 @@ where
                 let (seqnum, k) = <(usize, K)>::decode(kr)?;
                 Ok(DBCommand::Delete(seqnum, k))
             }
+            2 => {
+                let (seqnum, (k, v)) = <(usize, (K, V))>::decode(kr)?;
+                Ok(DBCommand::Merge(seqnum, k, v))
+            }
+            3 => {
+                let (seqnum, (start, end)) = <(usize, (K, K))>::decode(kr)?;
+                Ok(DBCommand::DeleteRange(seqnum, start, end))
+            }
+            4 => {
+                let (seqnum, commands) = <(usize, Vec<DBCommand<K, V>>)>::decode(kr)?;
+                Ok(DBCommand::Batch(seqnum, commands))
+            }
+            5 => {
+                let (cf, command) = <(ColumnFamilyId, DBCommand<K, V>)>::decode(kr)?;
+                Ok(DBCommand::Family(cf, Box::new(command)))
+            }
+            6 => {
+                let (seqnum, (deadline, (k, v))) = <(usize, (u64, (K, V)))>::decode(kr)?;
+                Ok(DBCommand::ExpiringWrite(seqnum, k, v, deadline))
+            }
+            7 => {
+                let (ts, command) = <(u64, DBCommand<K, V>)>::decode(kr)?;
+                Ok(DBCommand::AtTimestamp(ts, Box::new(command)))
+            }
             _ => bail!("invalid command"),
         }
     }
@@ -110,50 +273,429 @@ where
         match self {
             DBCommand::Write(x, _, _) => *x,
             DBCommand::Delete(x, _) => *x,
+            DBCommand::Merge(x, _, _) => *x,
+            DBCommand::ExpiringWrite(x, _, _, _) => *x,
+            DBCommand::DeleteRange(x, _, _) => *x,
+            DBCommand::Batch(x, _) => *x,
+            DBCommand::Family(_, command) | DBCommand::AtTimestamp(_, command) => command.seqnum(),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
-struct DiskLayout {
+// A single version of a key, as returned by `Db::history`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyVersion<V> {
+    pub seqnum: usize,
+    // 0 unless it was written with a timestamp.
+    pub timestamp: u64,
+    pub value: DbValue<V>,
+}
+
+// The on-disk state of a single column family.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+struct FamilyDiskLayout {
+    name: String,
+    // Every write to this family at or below this seqnum is in its SSTs, so
+    // it can be skipped when replaying the WALs.
     max_sst_seqnum: usize,
-    next_sst_id: usize,
-    l0: Vec<String>,
-    ssts: Vec<Vec<String>>,
-    wals: Vec<String>,
+    l0: Vec<SstMetadata>,
+    ssts: Vec<Vec<SstMetadata>>,
+    // The names of the merge operator and compaction filter the family was
+    // last configured with. It can only be opened with the same ones.
+    #[serde(default)]
+    merge_operator: Option<String>,
+    #[serde(default)]
+    compaction_filter: Option<String>,
 }
 
-impl DiskLayout {
-    fn new() -> Self {
-        DiskLayout {
+// Everything about an SST that's needed without opening it, so that opening
+// the database only has to read the manifest. Keys are stored encoded, since
+// the layout doesn't know their type.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SstMetadata {
+    filename: String,
+    // The internal keys that bound its entries and range tombstones.
+    min_key: Bytes,
+    max_key: Bytes,
+    // Its range tombstones, as (start, end) pairs of internal keys.
+    range_tombstones: Bytes,
+    num_bytes: usize,
+    num_entries: usize,
+    // The seqnums of its entries and range tombstones fall in this range.
+    min_seqnum: usize,
+    max_seqnum: usize,
+    // When it was written, according to the database's clock.
+    created_at: u64,
+}
+
+impl FamilyDiskLayout {
+    fn new(name: String) -> Self {
+        FamilyDiskLayout {
+            name,
             max_sst_seqnum: 0,
-            next_sst_id: 0,
             l0: Vec::new(),
             ssts: Vec::new(),
-            wals: Vec::new(),
+            merge_operator: None,
+            compaction_filter: None,
+        }
+    }
+
+    // Fails unless `options` have the merge operator and compaction filter
+    // the family was last configured with.
+    fn check_options<K, V>(&self, options: &DbOptions<K, V>) -> anyhow::Result<()> {
+        let checks = [
+            (
+                "merge operator",
+                self.merge_operator.as_deref(),
+                options.merge_operator_name(),
+            ),
+            (
+                "compaction filter",
+                self.compaction_filter.as_deref(),
+                options.compaction_filter_name(),
+            ),
+        ];
+        for (what, recorded, given) in checks {
+            if recorded != given {
+                bail!(
+                    "column family {} was configured with {} {}, not {}",
+                    self.name,
+                    what,
+                    recorded.unwrap_or("none"),
+                    given.unwrap_or("none")
+                );
+            }
         }
+        Ok(())
     }
 
     fn remove_sst(&mut self, fname: &str) {
-        self.l0.retain(|f| f != fname);
+        self.l0.retain(|sst| sst.filename != fname);
         for level in self.ssts.iter_mut() {
-            level.retain(|f| f != fname);
+            level.retain(|sst| sst.filename != fname);
         }
     }
 
-    fn add_sst(&mut self, fname: String, level: usize, index: usize) {
+    // Below L0, `index` is trusted to keep the level in order unless there's
+    // an `order` to check it with.
+    fn add_sst(
+        &mut self,
+        sst: SstMetadata,
+        level: usize,
+        index: usize,
+        order: Option<&KeyOrder>,
+    ) -> anyhow::Result<()> {
         if level == 0 {
-            self.l0.push(fname);
+            self.l0.push(sst);
         } else {
-            // TODO: We need to insert this in an appropriate location?
             while self.ssts.len() < level {
                 self.ssts.push(Vec::new());
             }
-            self.ssts[level - 1].insert(index, fname);
+            let ssts = &mut self.ssts[level - 1];
+            if index > ssts.len() {
+                bail!(
+                    "can't add {} at index {} of level {}, which has {} SSTs",
+                    sst.filename,
+                    index,
+                    level,
+                    ssts.len()
+                );
+            }
+            if let Some(order) = order {
+                let before = index.checked_sub(1).map(|i| &ssts[i]);
+                let after = ssts.get(index);
+                for (left, right) in [(before, Some(&sst)), (Some(&sst), after)] {
+                    if let (Some(left), Some(right)) = (left, right) {
+                        if !order.lt(&left.max_key, &right.min_key)? {
+                            bail!(
+                                "{} and {} overlap in level {}",
+                                left.filename,
+                                right.filename,
+                                level
+                            );
+                        }
+                    }
+                }
+            }
+            ssts.insert(index, sst);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DiskLayout {
+    next_sst_id: usize,
+    // The name of the comparator the keys are ordered by, or empty if the
+    // database has never been opened.
+    comparator: String,
+    // Whether keys have timestamps. SSTs only store them if so.
+    #[serde(default)]
+    timestamps: bool,
+    // Reads below this timestamp are refused, so compactions are free to
+    // drop the history they would have seen.
+    timestamp_low: u64,
+    // Pairs of a seqnum and a time by which every write up to it had been
+    // made, oldest first, for keeping history by time. Taken at each flush.
+    flush_times: Vec<(usize, u64)>,
+    // Shared by every column family, oldest first.
+    wals: Vec<String>,
+    // WALs that were retired into the archive, oldest first.
+    archived_wals: Vec<ArchivedWal>,
+    // Indexed by `ColumnFamilyId`.
+    families: Vec<FamilyDiskLayout>,
+    // Set in paranoid mode, so that SSTs added below L0 are checked against
+    // their neighbours.
+    #[serde(skip)]
+    key_order: Option<KeyOrder>,
+}
+
+type EncodedLt = dyn Fn(&Bytes, &Bytes) -> anyhow::Result<bool> + Send + Sync;
+
+// Orders the encoded internal keys in the manifest, by decoding them.
+#[derive(Clone)]
+struct KeyOrder(Arc<EncodedLt>);
+
+impl KeyOrder {
+    fn new<K: Decode + std::fmt::Debug + 'static>(
+        comparator: Arc<dyn Comparator<(K, u64, usize)>>,
+    ) -> Self {
+        KeyOrder(Arc::new(move |a, b| {
+            Ok(comparator.lt(&a.decode()?, &b.decode()?))
+        }))
+    }
+
+    fn lt(&self, a: &Bytes, b: &Bytes) -> anyhow::Result<bool> {
+        (self.0)(a, b)
+    }
+}
+
+impl std::fmt::Debug for KeyOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("KeyOrder")
+    }
+}
+
+// Leaves the timestamp out of SST keys in databases without timestamps, where
+// it's always 0.
+#[derive(Debug)]
+struct WithoutTimestamps;
+
+impl<K: Encode + Decode> KeyFormat<(K, u64, usize)> for WithoutTimestamps {
+    fn write(&self, (k, _, seqnum): &(K, u64, usize), kw: &mut KeyWriter) {
+        (k, seqnum).write_bytes(kw)
+    }
+
+    fn needs_delimiter(&self, (k, _, seqnum): &(K, u64, usize)) -> bool {
+        (k, seqnum).needs_delimiter()
+    }
+
+    fn read(&self, kr: &mut KeyReader) -> anyhow::Result<(K, u64, usize)> {
+        let (k, seqnum) = <(K, usize)>::decode(kr)?;
+        Ok((k, 0, seqnum))
+    }
+}
+
+// How internal keys are written in the data and index blocks of SSTs.
+fn internal_key_format<K: Encode + Decode + Send + Sync + 'static>(
+    timestamps: bool,
+) -> Arc<dyn KeyFormat<(K, u64, usize)>> {
+    if timestamps {
+        Arc::new(Encoded)
+    } else {
+        Arc::new(WithoutTimestamps)
+    }
+}
+
+impl Default for DiskLayout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DiskLayout {
+    fn new() -> Self {
+        DiskLayout {
+            next_sst_id: 0,
+            comparator: String::new(),
+            timestamps: false,
+            timestamp_low: 0,
+            flush_times: Vec::new(),
+            wals: Vec::new(),
+            archived_wals: Vec::new(),
+            families: vec![FamilyDiskLayout::new(DEFAULT_COLUMN_FAMILY_NAME.to_owned())],
+            key_order: None,
+        }
+    }
+
+    fn family_mut(&mut self, cf: ColumnFamilyId) -> anyhow::Result<&mut FamilyDiskLayout> {
+        self.families
+            .get_mut(cf)
+            .ok_or_else(|| anyhow!("no column family {} in the manifest", cf))
+    }
+
+    // Notes that every write up to `seqnum` had been made by `now`. Only the
+    // newest sample from at or before `horizon` is still needed to tell which
+    // writes are older than it.
+    fn record_flush_time(&mut self, seqnum: usize, now: u64, horizon: u64) {
+        self.flush_times.push((seqnum, now));
+        let oldest_needed = self
+            .flush_times
+            .iter()
+            .rposition(|(_, t)| *t <= horizon)
+            .unwrap_or(0);
+        self.flush_times.drain(..oldest_needed);
+    }
+
+    // Every WAL that only holds writes which have made it into SSTs.
+    // `unflushed` are the families that still have writes in their memtables;
+    // everything else is entirely on disk. The newest WAL is always needed,
+    // since it's the one being written to.
+    fn obsolete_wals(&self, unflushed: &[ColumnFamilyId]) -> Vec<String> {
+        let flushed_seqnum = unflushed
+            .iter()
+            .map(|cf| self.families[*cf].max_sst_seqnum)
+            .min()
+            .unwrap_or(usize::MAX);
+        // A WAL holds the seqnums above its lower bound, up to and including
+        // the next WAL's lower bound.
+        let upper_bounds: Vec<_> = self
+            .wals
+            .iter()
+            .skip(1)
+            .map(|w| wal_lower_bound(w))
+            .collect();
+        self.wals
+            .iter()
+            .zip(upper_bounds)
+            .filter(|(_, upper)| *upper <= flushed_seqnum)
+            .map(|(wal, _)| wal.clone())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ArchivedWal {
+    name: String,
+    num_bytes: usize,
+    // When it was archived, according to the database's clock.
+    archived_at: u64,
+}
+
+// A single change to the `DiskLayout`, as it's recorded in the manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum VersionEdit {
+    SetComparator(String),
+    EnableTimestamps,
+    SetTimestampLow(u64),
+    AddFamily(String),
+    AddWal(String),
+    RemoveWal(String),
+    // Moves a WAL from `wals` to `archived_wals`.
+    ArchiveWal(ArchivedWal),
+    RemoveArchivedWal(String),
+    // SSTs are added to the end of L0, and at `index` in any other level.
+    AddSst {
+        cf: ColumnFamilyId,
+        level: usize,
+        index: usize,
+        sst: SstMetadata,
+    },
+    RemoveSst {
+        cf: ColumnFamilyId,
+        filename: String,
+    },
+    NextSstId(usize),
+    MaxSstSeqnum {
+        cf: ColumnFamilyId,
+        seqnum: usize,
+    },
+    // Records the names of the merge operator and compaction filter `cf` is
+    // configured with.
+    SetFamilyOptions {
+        cf: ColumnFamilyId,
+        merge_operator: Option<String>,
+        compaction_filter: Option<String>,
+    },
+    // See `DiskLayout::record_flush_time`.
+    FlushTime {
+        seqnum: usize,
+        now: u64,
+        horizon: u64,
+    },
+}
+
+impl Versioned for DiskLayout {
+    type Edit = VersionEdit;
+
+    fn apply(&mut self, edit: VersionEdit) -> anyhow::Result<()> {
+        match edit {
+            VersionEdit::SetComparator(comparator) => self.comparator = comparator,
+            VersionEdit::EnableTimestamps => self.timestamps = true,
+            VersionEdit::SetTimestampLow(ts) => self.timestamp_low = ts,
+            VersionEdit::AddFamily(name) => self.families.push(FamilyDiskLayout::new(name)),
+            VersionEdit::AddWal(wal) => self.wals.push(wal),
+            VersionEdit::RemoveWal(wal) => self.wals.retain(|w| *w != wal),
+            VersionEdit::ArchiveWal(wal) => {
+                self.wals.retain(|w| *w != wal.name);
+                self.archived_wals.push(wal);
+            }
+            VersionEdit::RemoveArchivedWal(wal) => self.archived_wals.retain(|w| w.name != wal),
+            VersionEdit::AddSst {
+                cf,
+                level,
+                index,
+                sst,
+            } => {
+                let order = self.key_order.clone();
+                self.family_mut(cf)?
+                    .add_sst(sst, level, index, order.as_ref())?
+            }
+            VersionEdit::RemoveSst { cf, filename } => self.family_mut(cf)?.remove_sst(&filename),
+            VersionEdit::NextSstId(id) => self.next_sst_id = id,
+            VersionEdit::MaxSstSeqnum { cf, seqnum } => {
+                self.family_mut(cf)?.max_sst_seqnum = seqnum
+            }
+            VersionEdit::SetFamilyOptions {
+                cf,
+                merge_operator,
+                compaction_filter,
+            } => {
+                let family = self.family_mut(cf)?;
+                family.merge_operator = merge_operator;
+                family.compaction_filter = compaction_filter;
+            }
+            VersionEdit::FlushTime {
+                seqnum,
+                now,
+                horizon,
+            } => self.record_flush_time(seqnum, now, horizon),
+        }
+        Ok(())
+    }
+}
+
+impl VersionEdit {
+    fn set_family_options<K, V>(cf: ColumnFamilyId, options: &DbOptions<K, V>) -> Self {
+        VersionEdit::SetFamilyOptions {
+            cf,
+            merge_operator: options.merge_operator_name().map(str::to_owned),
+            compaction_filter: options.compaction_filter_name().map(str::to_owned),
         }
     }
 }
 
+fn archived_wal_path(name: &str) -> String {
+    format!("{}/{}", WAL_ARCHIVE, name)
+}
+
+fn wal_lower_bound(fname: &str) -> usize {
+    fname
+        .strip_prefix("wal")
+        .and_then(|n| n.parse().ok())
+        .expect("malformed wal name")
+}
+
 #[derive(Clone, Debug)]
 struct Sst<K, V>
 where
@@ -161,9 +703,10 @@ where
     V: Default + Clone + std::fmt::Debug + Encode + Decode,
 {
     filename: String,
-    min_key: (K, usize),
-    max_key: (K, usize),
+    min_key: (K, u64, usize),
+    max_key: (K, u64, usize),
     num_bytes: usize,
+    range_tombstones: Vec<RangeTombstone<K>>,
     // TODO: do we need this?
     _marker: PhantomData<V>,
 }
@@ -173,49 +716,246 @@ where
     K: Ord + Default + Clone + std::fmt::Debug + Encode + Decode,
     V: Default + Clone + std::fmt::Debug + Encode + Decode,
 {
-    fn new<D: DbDir>(d: &mut D, fname: String) -> Self {
-        // TODO: verify we don't do any more work than checking the metadata for this SST.
-        let reader = SstReader::<(K, usize), Option<V>, D>::load(
-            d.open(&fname).expect("sst file did not exist"),
-        )
-        .unwrap();
-
-        Sst {
-            filename: fname,
-            min_key: reader.sst_meta.min_key,
-            max_key: reader.sst_meta.max_key,
-            num_bytes: reader.sst_meta.num_bytes,
+    fn from_metadata(meta: &SstMetadata) -> anyhow::Result<Self> {
+        Ok(Sst {
+            filename: meta.filename.clone(),
+            min_key: meta.min_key.decode()?,
+            max_key: meta.max_key.decode()?,
+            num_bytes: meta.num_bytes,
+            range_tombstones: meta
+                .range_tombstones
+                .decode::<Vec<_>>()?
+                .into_iter()
+                .map(RangeTombstone::from_bounds)
+                .collect(),
             _marker: PhantomData,
+        })
+    }
+}
+
+// A pair of neighbouring SSTs in `level` that are out of order or overlap.
+fn find_overlap<'a, K, V>(
+    comparator: &dyn Comparator<(K, u64, usize)>,
+    level: impl IntoIterator<Item = &'a Sst<K, V>>,
+) -> Option<(String, String)>
+where
+    K: Ord + Default + Clone + std::fmt::Debug + Encode + Decode + 'a,
+    V: Default + Clone + std::fmt::Debug + Encode + Decode + 'a,
+{
+    let mut level = level.into_iter().peekable();
+    while let Some(left) = level.next() {
+        let right = level.peek()?;
+        if !comparator.lt(&left.max_key, &right.min_key) {
+            return Some((left.filename.clone(), right.filename.clone()));
         }
     }
+    None
 }
 
-#[derive(Debug)]
 struct Layout<K, V>
 where
     K: Ord + Default + Clone + std::fmt::Debug + Encode + Decode,
     V: Default + Clone + std::fmt::Debug + Encode + Decode,
 {
     active_memtable: Memtable<K, V>,
+    // Memtables which no longer accept writes, but whose contents have not
+    // yet made it into an SST. Ordered from oldest to newest.
+    immutable_memtables: Vec<Memtable<K, V>>,
     l0: Vec<Sst<K, V>>,
     ssts: Vec<Vec<Sst<K, V>>>,
+    // Every range tombstone above, fragmented. It's built by the first read
+    // after they change, and must be cleared whenever they do.
+    range_tombstones: Option<Arc<RangeTombstones<K>>>,
 }
 
-impl<K, V> Layout<K, V>
+// The fragmented range tombstones are left out, since they're only a cache.
+impl<K, V> std::fmt::Debug for Layout<K, V>
 where
     K: Ord + Default + Clone + std::fmt::Debug + Encode + Decode,
     V: Default + Clone + std::fmt::Debug + Encode + Decode,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Layout")
+            .field("active_memtable", &self.active_memtable)
+            .field("immutable_memtables", &self.immutable_memtables)
+            .field("l0", &self.l0)
+            .field("ssts", &self.ssts)
+            .finish()
+    }
+}
+
+impl<K, V> Layout<K, V>
+where
+    K: Ord + Default + Clone + std::fmt::Debug + Encode + Decode + Send + Sync + 'static,
+    V: Default + Clone + std::fmt::Debug + Encode + Decode + Send + Sync + 'static,
 {
     fn new(memtable: Memtable<K, V>, l0: Vec<Sst<K, V>>, ssts: Vec<Vec<Sst<K, V>>>) -> Self {
         Layout {
             active_memtable: memtable,
+            immutable_memtables: Vec::new(),
             l0,
             ssts,
+            range_tombstones: None,
         }
     }
 
     fn flush_memtable(&mut self) {
-        self.active_memtable = Memtable::new();
+        self.active_memtable = self.active_memtable.new_like();
+    }
+
+    fn freeze_memtable(&mut self) {
+        let fresh = self.active_memtable.new_like();
+        let memtable = std::mem::replace(&mut self.active_memtable, fresh);
+        self.immutable_memtables.push(memtable);
+    }
+
+    // Whether any writes are held only in memtables.
+    fn has_unflushed(&self) -> bool {
+        !self.active_memtable.is_empty() || !self.immutable_memtables.is_empty()
+    }
+
+    fn memtable_bytes(&self) -> usize {
+        self.active_memtable.approximate_bytes()
+            + self
+                .immutable_memtables
+                .iter()
+                .map(|imm| imm.approximate_bytes())
+                .sum::<usize>()
+    }
+
+    // Every range tombstone, from the memtables as well as the SSTs.
+    fn range_tombstones(&mut self, comparator: &dyn Comparator<K>) -> Arc<RangeTombstones<K>> {
+        if let Some(range_tombstones) = &self.range_tombstones {
+            return range_tombstones.clone();
+        }
+        let memtables = std::iter::once(&self.active_memtable)
+            .chain(self.immutable_memtables.iter())
+            .flat_map(|memtable| memtable.range_tombstones().iter());
+        let ssts = self
+            .l0
+            .iter()
+            .chain(self.ssts.iter().flatten())
+            .flat_map(|sst| sst.range_tombstones.iter());
+        let all: Vec<_> = memtables.chain(ssts).cloned().collect();
+        self.range_tombstones
+            .insert(Arc::new(RangeTombstones::new(comparator, &all)))
+            .clone()
+    }
+
+    // A pair of neighbouring SSTs in `level` (which is below L0) that are out
+    // of order or overlap, if there are any.
+    fn overlap_in_level(
+        &self,
+        comparator: &dyn Comparator<(K, u64, usize)>,
+        level: usize,
+    ) -> Option<(String, String)> {
+        find_overlap(comparator, &self.ssts[level - 1])
+    }
+
+    // How many bytes compactions have to move down a level to bring every
+    // level within its target size: all of L0, and whatever each lower level
+    // (including what's moved into it) holds beyond its target, except the
+    // last, which has nowhere further to go.
+    fn pending_compaction_bytes(&self, level1_target: usize, multiplier: usize) -> usize {
+        let bytes = |ssts: &[Sst<K, V>]| ssts.iter().map(|sst| sst.num_bytes).sum::<usize>();
+        let mut incoming = bytes(&self.l0);
+        let mut debt = incoming;
+        let mut target = level1_target;
+        for level in self.ssts.iter().take(self.ssts.len().saturating_sub(1)) {
+            incoming = (bytes(level) + incoming).saturating_sub(target);
+            debt += incoming;
+            target = target.saturating_mul(multiplier);
+        }
+        debt
+    }
+}
+
+// Fails unless the column family `name` can be given `options` in a database
+// opened with `db_options`.
+fn check_family_options<K, V>(
+    db_options: &DbOptions<K, V>,
+    name: &str,
+    options: &DbOptions<K, V>,
+) -> anyhow::Result<()> {
+    if options.comparator.name() != db_options.comparator.name() {
+        bail!(
+            "column family {} must use the database's comparator {}",
+            name,
+            db_options.comparator.name()
+        );
+    }
+    if options.timestamps != db_options.timestamps {
+        bail!(
+            "column family {} must match the database on whether keys have timestamps",
+            name
+        );
+    }
+    Ok(())
+}
+
+// Identifies a column family within a `Db`. Families are never dropped, so
+// this is just an index.
+pub type ColumnFamilyId = usize;
+
+pub const DEFAULT_COLUMN_FAMILY: ColumnFamilyId = 0;
+const DEFAULT_COLUMN_FAMILY_NAME: &str = "default";
+// Retired WALs are moved into this directory when `DbOptions::wal_archive`
+// is set.
+const WAL_ARCHIVE: &str = "archive";
+
+// An independent keyspace within a `Db`, with its own memtables and SSTs. All
+// of a database's families share its WAL and seqnums, so a batch which spans
+// several of them is still applied atomically.
+#[derive(Debug)]
+struct ColumnFamily<K, V>
+where
+    K: Ord + Default + Clone + std::fmt::Debug + Encode + Decode,
+    V: Default + Clone + std::fmt::Debug + Encode + Decode,
+{
+    name: String,
+    // Only the options which shape a single family's data are consulted here:
+    // the memtable rep, the write buffer size, the merge operator and the
+    // compaction filter.
+    options: DbOptions<K, V>,
+    layout: Layout<K, V>,
+}
+
+// Applies `cmd` to the active memtable of the family it's for, which is `cf`
+// unless it says otherwise. Commands at or below `flushed[cf]` are already in
+// that family's SSTs, and are skipped.
+fn route_command<K, V>(
+    families: &mut [ColumnFamily<K, V>],
+    cmd: DBCommand<K, V>,
+    cf: ColumnFamilyId,
+    flushed: &[usize],
+) -> anyhow::Result<()>
+where
+    K: Ord + Default + Clone + std::fmt::Debug + Encode + Decode + Send + Sync + 'static,
+    V: Default + Clone + std::fmt::Debug + Encode + Decode + Send + Sync + 'static,
+{
+    match cmd {
+        DBCommand::Batch(_, commands) => {
+            for command in commands {
+                route_command(families, command, cf, flushed)?;
+            }
+            Ok(())
+        }
+        DBCommand::Family(cf, command) => route_command(families, *command, cf, flushed),
+        cmd => {
+            if flushed
+                .get(cf)
+                .is_some_and(|seqnum| cmd.seqnum() <= *seqnum)
+            {
+                return Ok(());
+            }
+            let Some(family) = families.get_mut(cf) else {
+                bail!("command for unknown column family {}", cf)
+            };
+            if matches!(cmd, DBCommand::DeleteRange(..)) {
+                family.layout.range_tombstones = None;
+            }
+            family.layout.active_memtable.apply_command(cmd)
+        }
     }
 }
 
@@ -226,34 +966,157 @@ where
     V: std::fmt::Debug + Clone + Encode + Decode + Default,
 {
     root: Root<DiskLayout, D>,
-    layout: Layout<K, V>,
+    // Indexed by `ColumnFamilyId`. The first is the default family.
+    families: Vec<ColumnFamily<K, V>>,
     wal: Log<D, DBCommand<K, V>>,
     dir: D,
     next_seqnum: usize,
     // The seqnum that is used for reads.
     visible_seqnum: AtomicUsize,
+    options: DbOptions<K, V>,
+    // The most recent stall that a write ran into, if any.
+    last_write_stall: Option<WriteStall>,
+    // How much of `options.write_buffer_manager`'s budget our memtables
+    // currently hold.
+    write_buffer_reserved: usize,
+    // Shared by every pessimistic transaction against this database.
+    lock_manager: Arc<LockManager<K>>,
+    snapshots: Arc<SnapshotList>,
 }
 
 impl<D, K, V> Db<D, K, V>
 where
     D: DbDir + std::fmt::Debug + 'static,
-    K: Ord + Default + Clone + std::fmt::Debug + Decode + Encode,
-    V: Default + Clone + std::fmt::Debug + Decode + Encode,
+    K: Ord + Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
+    V: Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
 {
-    fn new(mut dir: D) -> anyhow::Result<Self> {
-        let mut root: Root<DiskLayout, _> = Root::load(dir.clone())?;
-        let mut memtable = Memtable::new();
-        let mut next_seqnum = 0;
+    fn new(dir: D) -> anyhow::Result<Self> {
+        Self::with_options(dir, DbOptions::default())
+    }
+
+    fn with_options(dir: D, options: DbOptions<K, V>) -> anyhow::Result<Self> {
+        Self::with_column_families(dir, options, Vec::new())
+    }
+
+    // Opens the database with `family_options` for the column families other
+    // than the default one, by name. Any family that isn't given some gets
+    // `options`, and every family has to end up with the merge operator and
+    // compaction filter it was last configured with.
+    fn with_column_families(
+        mut dir: D,
+        options: DbOptions<K, V>,
+        family_options: Vec<(String, DbOptions<K, V>)>,
+    ) -> anyhow::Result<Self> {
+        let mut obsolete = Vec::new();
+        let mut migrate_dir = dir.clone();
+        let mut root: Root<DiskLayout, _> = Root::load_or_migrate(dir.clone(), |buf| {
+            Self::migrate_legacy_root(&mut migrate_dir, buf, &options, &mut obsolete)
+        })?
+        .with_max_manifest_size(options.max_manifest_size);
+        // The manifest now refers to the rewritten SSTs instead.
+        for name in obsolete {
+            dir.unlink(&name)?;
+        }
+        let comparator = options.comparator.name();
+        if root.data.comparator.is_empty() {
+            let mut edits = vec![
+                VersionEdit::SetComparator(comparator.to_owned()),
+                VersionEdit::set_family_options(DEFAULT_COLUMN_FAMILY, &options),
+            ];
+            if options.timestamps {
+                edits.push(VersionEdit::EnableTimestamps);
+            }
+            root.edit(edits)?;
+        } else if root.data.comparator != comparator {
+            bail!(
+                "database was created with comparator {}, not {}",
+                root.data.comparator,
+                comparator
+            );
+        }
+        if root.data.timestamps != options.timestamps {
+            bail!(
+                "database was created with timestamps {}, so it must be opened that way",
+                if root.data.timestamps { "on" } else { "off" }
+            );
+        }
+        if options.paranoid_checks {
+            root.data.key_order = Some(KeyOrder::new(InternalKeyComparator::wrap(
+                options.comparator.clone(),
+            )));
+        }
+        let mut family_options: HashMap<_, _> = family_options.into_iter().collect();
+        let mut families: Vec<ColumnFamily<K, V>> = root
+            .data
+            .families
+            .iter()
+            .enumerate()
+            .map(|(cf, family)| {
+                let given = (cf != DEFAULT_COLUMN_FAMILY)
+                    .then(|| family_options.remove(&family.name))
+                    .flatten();
+                let options = match given {
+                    Some(given) => {
+                        check_family_options(&options, &family.name, &given)?;
+                        given
+                    }
+                    None => options.clone(),
+                };
+                family.check_options(&options)?;
+                let l0 = family
+                    .l0
+                    .iter()
+                    .map(Sst::from_metadata)
+                    .collect::<anyhow::Result<_>>()?;
+                let ssts = family
+                    .ssts
+                    .iter()
+                    .map(|level| level.iter().map(Sst::from_metadata).collect())
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(ColumnFamily {
+                    name: family.name.clone(),
+                    layout: Layout::new(
+                        Memtable::with_rep_and_comparator(
+                            options.memtable_rep,
+                            options.comparator.clone(),
+                        ),
+                        l0,
+                        ssts,
+                    ),
+                    options,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        if let Some(name) = family_options.keys().next() {
+            if name == DEFAULT_COLUMN_FAMILY_NAME {
+                bail!("the default column family takes the database's options");
+            }
+            bail!("there's no column family {}", name);
+        }
+        let flushed: Vec<_> = root
+            .data
+            .families
+            .iter()
+            .map(|family| family.max_sst_seqnum)
+            .collect();
         // Compute the seqnum we are to start at. It's the max of the seqnums provided by every data source.
+        let mut next_seqnum = flushed.iter().max().copied().unwrap_or(0) + 1;
+
+        // Finish archiving any WALs that were cut off part way through.
+        for wal in &root.data.archived_wals {
+            if dir.open(&wal.name).is_some() {
+                dir.rename(&wal.name, &archived_wal_path(&wal.name))?;
+            }
+        }
 
         let mut empty_wals = HashSet::new();
-        for wal_name in root.data.wals.iter().rev() {
+        for wal_name in root.data.wals.iter() {
             let wal = dir.open(wal_name).expect("wal file did not exist");
             let mut any = false;
             for command in LogReader::<_, DBCommand<K, V>>::new(wal)? {
                 any = true;
                 next_seqnum = std::cmp::max(command.seqnum() + 1, next_seqnum);
-                memtable.apply_command(command)
+                route_command(&mut families, command, DEFAULT_COLUMN_FAMILY, &flushed)?;
             }
             if !any {
                 empty_wals.insert(wal_name.clone());
@@ -268,105 +1131,314 @@ where
         if !empty_wals.is_empty() {
             // If a given WAL has no commands in it, then unlink it and remove it
             // from the set of WALs.
-            root.transform(|mut r| {
-                r.wals.retain(|w| !empty_wals.contains(w));
-                r
-            })?;
+            root.edit(
+                root.data
+                    .wals
+                    .iter()
+                    .filter(|w| empty_wals.contains(*w))
+                    .cloned()
+                    .map(VersionEdit::RemoveWal)
+                    .collect(),
+            )?;
             for wal in empty_wals {
                 dir.unlink(&wal)?;
             }
         }
 
-        let l0 = root
-            .data
-            .l0
-            .iter()
-            .map(|filename| Sst::new(&mut dir, filename.clone()))
-            .collect();
-        let ssts: Vec<Vec<Sst<K, V>>> = root
-            .data
-            .ssts
-            .iter()
-            .map(|level| {
-                level
-                    .iter()
-                    .map(|filename| Sst::new(&mut dir, filename.clone()))
-                    .collect()
-            })
-            .collect();
-
-        next_seqnum = std::cmp::max(next_seqnum, root.data.max_sst_seqnum + 1);
-
         let wal = Log::new(dir.clone(), next_seqnum)?;
 
         // When we open we create a fresh WAL, so we need to add that to the root.
         let wal_name = wal.fname().to_owned();
 
-        root.transform(move |mut layout| {
-            layout.wals.push(wal_name);
-            layout
-        })?;
+        root.edit(vec![VersionEdit::AddWal(wal_name)])?;
 
-        Ok(Self {
+        let mut db = Self {
             root,
-            layout: Layout::new(memtable, l0, ssts),
+            families,
             wal,
             dir,
             next_seqnum,
             visible_seqnum: AtomicUsize::new(next_seqnum),
-        })
+            options,
+            last_write_stall: None,
+            write_buffer_reserved: 0,
+            lock_manager: Arc::new(LockManager::new()),
+            snapshots: Arc::new(SnapshotList::new()),
+        };
+        db.update_write_buffer_usage();
+
+        Ok(db)
     }
 
-    fn apply_command(&mut self, cmd: DBCommand<K, V>) -> anyhow::Result<()> {
-        self.wal.write(&cmd)?;
-        self.apply_command_volatile(cmd);
-        Ok(())
+    // Returns the id of the column family called `name`, creating it if it
+    // doesn't exist yet. If it does, `options` replace the ones it was opened
+    // with; a new memtable rep only applies to memtables created after this.
+    fn create_column_family(
+        &mut self,
+        name: &str,
+        options: DbOptions<K, V>,
+    ) -> anyhow::Result<ColumnFamilyId> {
+        check_family_options(&self.options, name, &options)?;
+        if let Some(cf) = self.column_family(name) {
+            self.root
+                .edit(vec![VersionEdit::set_family_options(cf, &options)])?;
+            self.families[cf].options = options;
+            return Ok(cf);
+        }
+
+        let cf = self.families.len();
+        self.root.edit(vec![
+            VersionEdit::AddFamily(name.to_owned()),
+            VersionEdit::set_family_options(cf, &options),
+        ])?;
+        self.families.push(ColumnFamily {
+            name: name.to_owned(),
+            layout: Layout::new(
+                Memtable::with_rep_and_comparator(options.memtable_rep, options.comparator.clone()),
+                Vec::new(),
+                Vec::new(),
+            ),
+            options,
+        });
+        Ok(cf)
     }
 
-    fn apply_command_volatile(&mut self, cmd: DBCommand<K, V>) {
-        self.layout.active_memtable.apply_command(cmd);
+    // The comparator for the (key, seqnum) pairs stored in memtables and SSTs.
+    fn internal_comparator(&self) -> Arc<dyn Comparator<(K, u64, usize)>> {
+        InternalKeyComparator::wrap(self.options.comparator.clone())
     }
 
-    fn retrieve_sst(&self, level: usize, idx: usize) -> anyhow::Result<Sst<K, V>> {
-        if level == 0 {
-            if idx >= self.layout.l0.len() {
-                bail!("invalid sst")
+    fn key_format(&self) -> Arc<dyn KeyFormat<(K, u64, usize)>> {
+        internal_key_format(self.options.timestamps)
+    }
+
+    fn column_family(&self, name: &str) -> Option<ColumnFamilyId> {
+        self.families.iter().position(|family| family.name == name)
+    }
+
+    fn check_column_family(&self, cf: ColumnFamilyId) -> anyhow::Result<()> {
+        if cf >= self.families.len() {
+            bail!("no column family with id {}", cf);
+        }
+        Ok(())
+    }
+
+    // The families which have writes that are only held in memtables.
+    fn unflushed_families(&self) -> Vec<ColumnFamilyId> {
+        (0..self.families.len())
+            .filter(|cf| self.families[*cf].layout.has_unflushed())
+            .collect()
+    }
+
+    fn write_buffer_manager(&self) -> Option<&WriteBufferManager> {
+        self.options.write_buffer_manager.as_deref()
+    }
+
+    // Brings our reservation with the shared write buffer manager in line with
+    // what the memtables are actually using.
+    fn update_write_buffer_usage(&mut self) {
+        let used = self
+            .families
+            .iter()
+            .map(|family| family.layout.memtable_bytes())
+            .sum();
+        if let Some(manager) = self.write_buffer_manager() {
+            if used > self.write_buffer_reserved {
+                manager.reserve(used - self.write_buffer_reserved);
+            } else {
+                manager.free(self.write_buffer_reserved - used);
             }
-            Ok(self.layout.l0[idx].clone())
-        } else if level <= self.layout.ssts.len() {
-            if idx >= self.layout.ssts[level - 1].len() {
+        }
+        self.write_buffer_reserved = used;
+    }
+
+    fn should_flush_memtable(&self, cf: ColumnFamilyId) -> bool {
+        let family = &self.families[cf];
+        family.layout.active_memtable.approximate_bytes() >= family.options.write_buffer_size
+    }
+
+    // When the memtables sharing our write buffer manager are over its budget,
+    // our largest one is flushed, rather than every family's, which would
+    // turn the small ones into tiny SSTs.
+    fn memtable_over_shared_budget(&self) -> Option<ColumnFamilyId> {
+        if !self
+            .write_buffer_manager()
+            .is_some_and(|manager| manager.should_flush())
+        {
+            return None;
+        }
+        (0..self.families.len())
+            .map(|cf| {
+                (
+                    self.families[cf].layout.active_memtable.approximate_bytes(),
+                    cf,
+                )
+            })
+            .filter(|(bytes, _)| *bytes > 0)
+            .max()
+            .map(|(_, cf)| cf)
+    }
+
+    // Stalls are driven by whichever family is furthest behind, except for
+    // compaction debt, which is shared by all of them.
+    fn write_stall(&self) -> Option<WriteStall> {
+        let layouts = || self.families.iter().map(|family| &family.layout);
+        WriteStall::compute(
+            &self.options,
+            layouts().map(|layout| layout.l0.len()).max().unwrap_or(0),
+            layouts()
+                .map(|layout| layout.immutable_memtables.len())
+                .max()
+                .unwrap_or(0),
+            layouts()
+                .map(|layout| {
+                    layout.pending_compaction_bytes(
+                        self.options.level1_target_bytes,
+                        self.options.level_size_multiplier,
+                    )
+                })
+                .sum(),
+        )
+    }
+
+    // Applies backpressure to an incoming write if flushes or compactions
+    // have fallen behind.
+    fn maybe_stall_write(&mut self) -> anyhow::Result<()> {
+        let stall = self.write_stall();
+        if stall.is_some() {
+            self.last_write_stall = stall;
+        }
+        match stall {
+            None => Ok(()),
+            Some(WriteStall::Slowdown(_)) => {
+                std::thread::sleep(self.options.slowdown_delay);
+                Ok(())
+            }
+            Some(WriteStall::Stop(cause)) => {
+                let deadline = Instant::now() + self.options.write_stop_timeout;
+                let mut cause = cause;
+                loop {
+                    if Instant::now() >= deadline || !self.work_off_write_stop(cause)? {
+                        return Err(WriteStopped(cause).into());
+                    }
+                    match self.write_stall() {
+                        Some(WriteStall::Stop(next)) => cause = next,
+                        _ => return Ok(()),
+                    }
+                }
+            }
+        }
+    }
+
+    // Does one step of the flushing or compacting that `cause` is waiting on,
+    // in whichever family is furthest behind. Returns whether there was
+    // anything to do.
+    fn work_off_write_stop(&mut self, cause: WriteStallCause) -> anyhow::Result<bool> {
+        let furthest = |count: &dyn Fn(&Layout<K, V>) -> usize| {
+            (0..self.families.len())
+                .map(|cf| (count(&self.families[cf].layout), cf))
+                .filter(|(n, _)| *n > 0)
+                .max()
+                .map(|(_, cf)| cf)
+        };
+        match cause {
+            WriteStallCause::ImmutableMemtableCount(_) => {
+                match furthest(&|layout| layout.immutable_memtables.len()) {
+                    Some(cf) => self.flush_immutable_memtables(cf)?,
+                    None => return Ok(false),
+                }
+            }
+            // Debt below L1 needs compactions which only the caller knows how
+            // to pick.
+            WriteStallCause::L0FileCount(_) | WriteStallCause::PendingCompactionBytes(_) => {
+                match furthest(&|layout| layout.l0.len()) {
+                    Some(cf) => {
+                        let l0 = (0..self.families[cf].layout.l0.len())
+                            .map(|idx| (0, idx))
+                            .collect();
+                        self.merge_cf(cf, l0, 1)?;
+                    }
+                    None => return Ok(false),
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn apply_command(&mut self, cmd: DBCommand<K, V>) -> anyhow::Result<()> {
+        self.wal.write(&cmd)?;
+        self.apply_command_volatile(cmd)
+    }
+
+    fn apply_command_volatile(&mut self, cmd: DBCommand<K, V>) -> anyhow::Result<()> {
+        route_command(&mut self.families, cmd, DEFAULT_COLUMN_FAMILY, &[])
+    }
+
+    fn retrieve_sst(
+        &self,
+        cf: ColumnFamilyId,
+        level: usize,
+        idx: usize,
+    ) -> anyhow::Result<Sst<K, V>> {
+        let layout = &self.families[cf].layout;
+        if level == 0 {
+            if idx >= layout.l0.len() {
+                bail!("invalid sst")
+            }
+            Ok(layout.l0[idx].clone())
+        } else if level <= layout.ssts.len() {
+            if idx >= layout.ssts[level - 1].len() {
                 bail!("invalid sst")
             }
-            Ok(self.layout.ssts[level - 1][idx].clone())
+            Ok(layout.ssts[level - 1][idx].clone())
         } else {
             bail!("invalid sst")
         }
     }
 
-    fn remove_sst_from_in_memory(&mut self, filename: &String) {
-        self.layout.l0.retain(|f| &f.filename != filename);
-        for level in self.layout.ssts.iter_mut() {
+    fn remove_sst_from_in_memory(&mut self, cf: ColumnFamilyId, filename: &String) {
+        let layout = &mut self.families[cf].layout;
+        layout.range_tombstones = None;
+        layout.l0.retain(|f| &f.filename != filename);
+        for level in layout.ssts.iter_mut() {
             level.retain(|f| &f.filename != filename);
         }
     }
 
     fn merge(&mut self, targets: Vec<(usize, usize)>, target_level: usize) -> anyhow::Result<()> {
+        self.merge_cf(DEFAULT_COLUMN_FAMILY, targets, target_level)
+    }
+
+    // Compacts the `(level, index)` SSTs of `cf` (along with anything they
+    // overlap) into a single SST in `target_level`.
+    fn merge_cf(
+        &mut self,
+        cf: ColumnFamilyId,
+        targets: Vec<(usize, usize)>,
+        target_level: usize,
+    ) -> anyhow::Result<()> {
+        self.check_column_family(cf)?;
         let max_level = targets.iter().map(|(level, _)| *level).max().unwrap_or(0);
 
         if target_level < max_level {
             bail!("merging is not allowed to hoist any SSTs up a level");
         }
+        if target_level == 0 {
+            bail!("can't merge into L0");
+        }
 
         if targets.is_empty() {
             return Ok(());
         }
 
+        let comparator = self.internal_comparator();
         let desired_targets: HashSet<_> = targets.into_iter().collect();
-        let mut affected_ranges = KeyspaceSubset::<(K, usize)>::new();
+        let mut affected_ranges = KeyspaceSubset::<(K, u64, usize)>::new();
         let mut targets = Vec::new();
 
-        for (level_index, level) in std::iter::once(&self.layout.l0)
-            .chain(self.layout.ssts.iter())
+        let layout = &self.families[cf].layout;
+        for (level_index, level) in std::iter::once(&layout.l0)
+            .chain(layout.ssts.iter())
             .enumerate()
         {
             for (index, sst) in level.iter().enumerate() {
@@ -375,79 +1447,146 @@ where
                 let my_range =
                     KeyspaceSubset::new_from_singleton((sst.min_key.clone(), sst.max_key.clone()));
 
-                if desired_targets.contains(&sst_name) || affected_ranges.intersects(&my_range) {
+                if desired_targets.contains(&sst_name)
+                    || affected_ranges.intersects(&*comparator, &my_range)
+                {
                     targets.push(sst_name);
-                    affected_ranges = affected_ranges.union(&my_range);
+                    affected_ranges = affected_ranges.union(&*comparator, &my_range);
                 }
             }
         }
 
+        // If nothing outside of the compaction overlaps it, there's nothing
+        // older for merge operands to apply to, or for range tombstones to
+        // delete.
+        let bottommost = std::iter::once(&layout.l0)
+            .chain(layout.ssts.iter())
+            .enumerate()
+            .flat_map(|(level_index, level)| {
+                level
+                    .iter()
+                    .enumerate()
+                    .map(move |(index, sst)| ((level_index, index), sst))
+            })
+            .filter(|(sst_name, _)| !targets.contains(sst_name))
+            .all(|(_, sst)| {
+                !affected_ranges.intersects(
+                    &*comparator,
+                    &KeyspaceSubset::new_from_singleton((sst.min_key.clone(), sst.max_key.clone())),
+                )
+            });
+
         // TODO: we need an async version of this.
         let ssts = targets
             .iter()
-            .map(|(level, idx)| self.retrieve_sst(*level, *idx))
+            .map(|(level, idx)| self.retrieve_sst(cf, *level, *idx))
             .collect::<Result<Vec<_>, _>>()?;
 
         // TODO: we should leveliter the ssts that are at the same level, rather than mergeiter.
         let readers = ssts
             .iter()
             .map(|sst| {
-                SstReader::<(K, usize), Option<V>, D>::load(
-                    self.dir
-                        .open(&sst.filename)
-                        .expect("sst file did not exist"),
+                Ok(
+                    SstReader::<(K, u64, usize), DbValue<V>, D>::load_with_key_format(
+                        self.dir
+                            .open(&sst.filename)
+                            .expect("sst file did not exist"),
+                        self.key_format(),
+                    )?
+                    .with_comparator(comparator.clone()),
                 )
             })
             .collect::<anyhow::Result<Vec<_>>>()?;
 
-        let mut merged = MergingIter::new(readers);
+        let range_tombstones: Vec<_> = ssts
+            .iter()
+            .flat_map(|sst| sst.range_tombstones.iter().cloned())
+            .collect();
+        let merged = MergingIter::new(readers).with_comparator(comparator.clone());
+        let user_comparator = self.options.comparator.clone();
+        let merge_operator = self.families[cf].options.merge_operator.clone();
+        let compaction_filter = self.families[cf].options.compaction_filter.clone();
+        let (mut entries, range_tombstones) = Compaction {
+            comparator: &*user_comparator,
+            merge_operator: merge_operator.as_deref(),
+            compaction_filter: compaction_filter.as_deref(),
+            bottommost,
+            now: self.options.clock.now(),
+            timestamp_low: self.root.data.timestamp_low,
+            retain_above: self.retain_above(),
+            oldest_snapshot: self.snapshots.oldest().unwrap_or(usize::MAX),
+        }
+        .compact(merged, range_tombstones);
+        let mut output = (&mut entries).peekable();
 
         // Don't write out an empty SST.
-        let new_sst_path = if merged.peek().is_none() {
+        let new_sst_meta = if output.peek().is_none() && range_tombstones.is_empty() {
             None
         } else {
-            let new_sst_path = format!("sst{}.sst", self.root.data.next_sst_id);
-            self.dir.unlink(&new_sst_path)?;
-            let sst_file = self
-                .dir
-                .create(&new_sst_path)?
-                .unwrap_or_else(|| panic!("sst file {} already existed", new_sst_path));
-            let sst_writer = SstWriter::new(merged, sst_file);
-            sst_writer.write()?;
-            Some(new_sst_path)
+            Some(self.write_sst(cf, &mut output, &range_tombstones)?)
         };
+        // The output stops early if one of the inputs couldn't be read, and
+        // then it's missing data.
+        if let Some(err) = entries.take_error() {
+            return Err(err);
+        }
+
+        // Work out where the new SST goes, and in paranoid mode check that the
+        // level it lands in is still in order, before changing anything.
+        let new_sst = new_sst_meta.as_ref().map(Sst::from_metadata).transpose()?;
+        let removed: HashSet<_> = ssts.iter().map(|sst| sst.filename.as_str()).collect();
+        let mut level: Vec<_> = self.families[cf]
+            .layout
+            .ssts
+            .get(target_level - 1)
+            .into_iter()
+            .flatten()
+            .filter(|sst| !removed.contains(sst.filename.as_str()))
+            .collect();
+        let index_to_insert_at = new_sst.as_ref().map_or(0, |new_sst| {
+            level
+                .binary_search_by(|sst| comparator.compare(&sst.max_key, &new_sst.max_key))
+                .unwrap_err()
+        });
+        if self.options.paranoid_checks {
+            if let Some(new_sst) = &new_sst {
+                level.insert(index_to_insert_at, new_sst);
+            }
+            if let Some((left, right)) = find_overlap(&*comparator, level) {
+                if let Some(new_sst) = &new_sst {
+                    self.dir.unlink(&new_sst.filename)?;
+                }
+                bail!("{} and {} overlap in level {}", left, right, target_level);
+            }
+        }
 
         // Reshape the in-memory and on-disk layouts.
 
         for sst in &ssts {
-            self.remove_sst_from_in_memory(&sst.filename);
+            self.remove_sst_from_in_memory(cf, &sst.filename);
         }
 
-        while self.layout.ssts.len() < target_level {
-            self.layout.ssts.push(Vec::new());
+        let layout = &mut self.families[cf].layout;
+        while layout.ssts.len() < target_level {
+            layout.ssts.push(Vec::new());
         }
-
-        let mut index_to_insert_at = 0;
-        if let Some(ref new_sst_path) = new_sst_path {
-            let new_sst = Sst::new(&mut self.dir, new_sst_path.to_owned());
-
-            index_to_insert_at = self.layout.ssts[target_level - 1]
-                .binary_search_by_key(&&new_sst.max_key, |sst| &sst.max_key)
-                .unwrap_err();
-
-            self.layout.ssts[target_level - 1].insert(index_to_insert_at, new_sst);
+        layout.range_tombstones = None;
+        if let Some(new_sst) = new_sst {
+            layout.ssts[target_level - 1].insert(index_to_insert_at, new_sst);
         }
 
-        self.root.transform(move |mut layout| {
-            layout.next_sst_id += 1;
-            for sst in ssts {
-                layout.remove_sst(&sst.filename);
-            }
-            if let Some(new_sst_path) = new_sst_path {
-                layout.add_sst(new_sst_path, target_level, index_to_insert_at);
-            }
-            layout
-        })?;
+        let mut edits = vec![VersionEdit::NextSstId(self.root.data.next_sst_id + 1)];
+        edits.extend(ssts.into_iter().map(|sst| VersionEdit::RemoveSst {
+            cf,
+            filename: sst.filename,
+        }));
+        edits.extend(new_sst_meta.map(|sst| VersionEdit::AddSst {
+            cf,
+            level: target_level,
+            index: index_to_insert_at,
+            sst,
+        }));
+        self.root.edit(edits)?;
 
         // TODO: now unlink the old ssts.
         Ok(())
@@ -478,96 +1617,735 @@ where
         }
     }
 
-    fn insert(&mut self, k: K, v: V) -> anyhow::Result<()> {
-        self.next_seqnum += 1;
-        self.apply_command(DBCommand::Write(self.next_seqnum, k, v))?;
+    // Assigns the next seqnum to the command built by `f`, makes it durable
+    // and then visible. This is the path every write takes.
+    fn write_command<F>(&mut self, f: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(usize) -> DBCommand<K, V>,
+    {
+        self.maybe_stall_write()?;
+        let mut command = f(self.next_seqnum + 1);
+        // With timestamps, writes that weren't given one are made now.
+        if self.options.timestamps {
+            command = command.with_default_timestamp(self.options.clock.now());
+        }
+        self.commit_command(command)
+    }
+
+    // Writes `command`, whose seqnum comes after every write so far. It's
+    // either a new write or one being replayed from another copy of the
+    // database's WALs.
+    fn commit_command(&mut self, command: DBCommand<K, V>) -> anyhow::Result<()> {
+        // Full memtables are flushed before the write rather than after it,
+        // so that a failed flush fails a write that hasn't happened.
+        for cf in 0..self.families.len() {
+            if self.should_flush_memtable(cf) {
+                self.flush_memtable_cf(cf)?;
+            }
+        }
+        self.update_write_buffer_usage();
+        if let Some(cf) = self.memtable_over_shared_budget() {
+            self.flush_memtable_cf(cf)?;
+        }
+        self.next_seqnum = command.seqnum();
+        self.apply_command(command)?;
         self.ratchet_visible_seqnum(self.next_seqnum);
+        self.update_write_buffer_usage();
         Ok(())
     }
 
-    fn delete(&mut self, k: K) -> anyhow::Result<()> {
-        self.next_seqnum += 1;
-        self.apply_command(DBCommand::Delete(self.next_seqnum, k))?;
-        self.ratchet_visible_seqnum(self.next_seqnum);
+    fn insert(&mut self, k: K, v: V) -> anyhow::Result<()> {
+        self.insert_cf(DEFAULT_COLUMN_FAMILY, k, v)
+    }
+
+    fn insert_cf(&mut self, cf: ColumnFamilyId, k: K, v: V) -> anyhow::Result<()> {
+        self.check_column_family(cf)?;
+        self.write_command(|seqnum| DBCommand::Write(seqnum, k, v).in_family(cf))
+    }
+
+    // Writes `v` to `k`, to be treated as deleted once `ttl` has passed
+    // according to the database's clock.
+    fn insert_with_ttl(&mut self, k: K, v: V, ttl: Duration) -> anyhow::Result<()> {
+        self.insert_with_ttl_cf(DEFAULT_COLUMN_FAMILY, k, v, ttl)
+    }
+
+    fn insert_with_ttl_cf(
+        &mut self,
+        cf: ColumnFamilyId,
+        k: K,
+        v: V,
+        ttl: Duration,
+    ) -> anyhow::Result<()> {
+        self.check_column_family(cf)?;
+        // A TTL too long to represent never expires.
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let deadline = self.options.clock.now().saturating_add(ttl);
+        self.write_command(|seqnum| DBCommand::ExpiringWrite(seqnum, k, v, deadline).in_family(cf))
+    }
+
+    // Writes `v` to `k` at timestamp `ts`. Reads at earlier timestamps don't
+    // see it. This needs `DbOptions::timestamps`.
+    fn insert_with_timestamp(&mut self, k: K, ts: u64, v: V) -> anyhow::Result<()> {
+        self.insert_with_timestamp_cf(DEFAULT_COLUMN_FAMILY, k, ts, v)
+    }
+
+    fn insert_with_timestamp_cf(
+        &mut self,
+        cf: ColumnFamilyId,
+        k: K,
+        ts: u64,
+        v: V,
+    ) -> anyhow::Result<()> {
+        self.check_column_family(cf)?;
+        self.check_timestamps_enabled()?;
+        self.write_command(|seqnum| {
+            DBCommand::Write(seqnum, k, v)
+                .at_timestamp(ts)
+                .in_family(cf)
+        })
+    }
+
+    fn delete_with_timestamp(&mut self, k: K, ts: u64) -> anyhow::Result<()> {
+        self.delete_with_timestamp_cf(DEFAULT_COLUMN_FAMILY, k, ts)
+    }
+
+    fn delete_with_timestamp_cf(
+        &mut self,
+        cf: ColumnFamilyId,
+        k: K,
+        ts: u64,
+    ) -> anyhow::Result<()> {
+        self.check_column_family(cf)?;
+        self.check_timestamps_enabled()?;
+        self.write_command(|seqnum| DBCommand::Delete(seqnum, k).at_timestamp(ts).in_family(cf))
+    }
+
+    fn timestamp_low(&self) -> u64 {
+        self.root.data.timestamp_low
+    }
+
+    // Gives up on reading below timestamp `ts`, so that compactions can trim
+    // the history that only such reads would see. The low timestamp can only
+    // go up.
+    fn set_timestamp_low(&mut self, ts: u64) -> anyhow::Result<()> {
+        if ts < self.timestamp_low() {
+            bail!(
+                "the low timestamp is already {}, past {}",
+                self.timestamp_low(),
+                ts
+            );
+        }
+        self.root.edit(vec![VersionEdit::SetTimestampLow(ts)])
+    }
+
+    fn check_timestamps_enabled(&self) -> anyhow::Result<()> {
+        if !self.options.timestamps {
+            bail!("keys don't have timestamps in this database");
+        }
+        Ok(())
+    }
+
+    fn check_timestamp(&self, ts: u64) -> anyhow::Result<()> {
+        self.check_timestamps_enabled()?;
+        if ts < self.timestamp_low() {
+            bail!(
+                "can't read at timestamp {}, below the low timestamp {}",
+                ts,
+                self.timestamp_low()
+            );
+        }
         Ok(())
     }
 
-    fn get(&mut self, k: &K) -> anyhow::Result<Option<V>>
+    fn delete(&mut self, k: K) -> anyhow::Result<()> {
+        self.delete_cf(DEFAULT_COLUMN_FAMILY, k)
+    }
+
+    fn delete_cf(&mut self, cf: ColumnFamilyId, k: K) -> anyhow::Result<()> {
+        self.check_column_family(cf)?;
+        self.write_command(|seqnum| DBCommand::Delete(seqnum, k).in_family(cf))
+    }
+
+    // Records `operand` against `k`, to be folded into its value by the
+    // configured `MergeOperator`.
+    fn merge_value(&mut self, k: K, operand: V) -> anyhow::Result<()> {
+        self.merge_value_cf(DEFAULT_COLUMN_FAMILY, k, operand)
+    }
+
+    fn merge_value_cf(&mut self, cf: ColumnFamilyId, k: K, operand: V) -> anyhow::Result<()> {
+        self.check_column_family(cf)?;
+        if self.families[cf].options.merge_operator.is_none() {
+            bail!("merge_value requires a merge operator");
+        }
+        self.write_command(|seqnum| DBCommand::Merge(seqnum, k, operand).in_family(cf))
+    }
+
+    // Replaces the value of `k` with `new` (where `None` deletes it), but only
+    // if its current value is `expected` (where `None` means absent). Returns
+    // whether the write happened. Since writes take `&mut self`, nothing can
+    // sneak in between the read and the write, and `k` is locked against
+    // pessimistic transactions for the duration, waiting up to the lock
+    // timeout for any which hold it.
+    fn compare_and_swap(
+        &mut self,
+        k: K,
+        expected: Option<V>,
+        new: Option<V>,
+    ) -> anyhow::Result<bool>
     where
-        K: 'static,
-        V: 'static,
+        K: Hash,
+        V: PartialEq,
     {
-        let scan = self.scan()?;
-        let mut iter = scan.iter;
-        iter.seek_ge(k);
-        if let Some(next) = iter.next() {
-            if next.0 == k {
-                Ok(Some(next.1.clone()))
-            } else {
-                Ok(None)
+        self.compare_and_swap_cf(DEFAULT_COLUMN_FAMILY, k, expected, new)
+    }
+
+    fn compare_and_swap_cf(
+        &mut self,
+        cf: ColumnFamilyId,
+        k: K,
+        expected: Option<V>,
+        new: Option<V>,
+    ) -> anyhow::Result<bool>
+    where
+        K: Hash,
+        V: PartialEq,
+    {
+        self.check_column_family(cf)?;
+        // Pessimistic transactions only write the default family, so that's
+        // the only one whose keys they lock.
+        let txn = (cf == DEFAULT_COLUMN_FAMILY).then(|| self.lock_manager.begin());
+        if let Some(txn) = txn {
+            self.lock_manager
+                .lock(txn, &k, LockMode::Exclusive, self.options.lock_timeout)?;
+        }
+        let swapped = (|| {
+            if self.get_cf(cf, &k)? != expected {
+                return Ok(false);
             }
-        } else {
-            Ok(None)
+            match new {
+                Some(v) => self.insert_cf(cf, k.clone(), v)?,
+                None => self.delete_cf(cf, k.clone())?,
+            }
+            Ok(true)
+        })();
+        if let Some(txn) = txn {
+            self.lock_manager.unlock_all(txn);
         }
+        swapped
     }
 
-    fn scan(&mut self) -> anyhow::Result<DbIterator<K, V, impl KVIter<K, V>>>
+    // Writes `v` to `k` only if it doesn't already have a value. Returns
+    // whether the write happened.
+    fn put_if_absent(&mut self, k: K, v: V) -> anyhow::Result<bool>
     where
-        K: 'static,
-        V: 'static,
+        K: Hash,
+        V: PartialEq,
     {
-        let tab = self.layout.active_memtable.scan();
+        self.put_if_absent_cf(DEFAULT_COLUMN_FAMILY, k, v)
+    }
 
-        // Every SST in L0 is read independently, but the lower-level ones get
-        // concatenated.
-        let mut level_readers = Vec::new();
-        for sst in &self.layout.l0 {
-            // TODO: kind of goofy this is a LevelIter that always has one thing in it.
-            level_readers.push(LevelIter::new([
-                SstReader::<(K, usize), Option<V>, D>::load(
-                    self.dir
-                        .open(&sst.filename)
-                        .expect("sst file did not exist"),
-                )?,
-            ]))
-        }
-
-        for level in &self.layout.ssts {
-            let readers = level
-                .iter()
-                .map(|sst| {
-                    SstReader::load(
-                        self.dir
-                            .open(&sst.filename)
-                            .expect("sst file did not exist"),
-                    )
-                })
-                .collect::<anyhow::Result<Vec<_>>>()?;
-            if !readers.is_empty() {
-                level_readers.push(LevelIter::new(readers))
+    fn put_if_absent_cf(&mut self, cf: ColumnFamilyId, k: K, v: V) -> anyhow::Result<bool>
+    where
+        K: Hash,
+        V: PartialEq,
+    {
+        self.compare_and_swap_cf(cf, k, None, Some(v))
+    }
+
+    // Deletes every key in `[start, end)` with a single range tombstone.
+    fn delete_range(&mut self, start: K, end: K) -> anyhow::Result<()> {
+        self.delete_range_cf(DEFAULT_COLUMN_FAMILY, start, end)
+    }
+
+    fn delete_range_cf(&mut self, cf: ColumnFamilyId, start: K, end: K) -> anyhow::Result<()> {
+        self.check_column_family(cf)?;
+        if !self.options.comparator.lt(&start, &end) {
+            bail!("delete_range requires start < end");
+        }
+        self.write_command(|seqnum| DBCommand::DeleteRange(seqnum, start, end).in_family(cf))
+    }
+
+    // Applies every write in `batch` atomically, at a single seqnum.
+    fn write(&mut self, batch: WriteBatch<K, V>) -> anyhow::Result<()> {
+        for cf in batch.column_families() {
+            self.check_column_family(cf)?;
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.write_command(|seqnum| DBCommand::Batch(seqnum, batch.into_commands(seqnum)))
+    }
+
+    // Pins the current state of the database for reading with `get_at` and
+    // `scan_at`, until the snapshot is dropped.
+    fn snapshot(&self) -> Snapshot {
+        self.snapshots.acquire(self.visible_seqnum())
+    }
+
+    // Starts a transaction which reads from the current state of the
+    // database.
+    fn transaction(&self) -> Transaction<K, V> {
+        Transaction::new(self.snapshot())
+    }
+
+    // Applies the writes in `txn` atomically, unless something it read has
+    // been written since its snapshot, in which case this fails with a
+    // `TransactionConflict` and nothing is written.
+    fn commit(&mut self, txn: Transaction<K, V>) -> anyhow::Result<()> {
+        let reads: Vec<_> = txn.reads().map(|(k, s)| (k.clone(), s)).collect();
+        for (k, read_at) in reads {
+            let latest = self.latest_seqnum(&k)?;
+            if latest > read_at {
+                return Err(TransactionConflict {
+                    snapshot: read_at,
+                    seqnum: latest,
+                }
+                .into());
             }
         }
+        if txn.is_read_only() {
+            return Ok(());
+        }
+        self.write_command(|seqnum| DBCommand::Batch(seqnum, txn.into_commands(seqnum)))
+    }
+
+    // Starts a transaction which locks the keys it touches.
+    fn pessimistic_transaction(&self) -> PessimisticTransaction<K, V>
+    where
+        K: Hash,
+    {
+        PessimisticTransaction::new(self.lock_manager.clone(), self.options.lock_timeout)
+    }
+
+    // Applies the writes in `txn` atomically and releases its locks.
+    fn commit_pessimistic(&mut self, mut txn: PessimisticTransaction<K, V>) -> anyhow::Result<()>
+    where
+        K: Hash,
+    {
+        if txn.is_read_only() {
+            return Ok(());
+        }
+        self.write_command(|seqnum| DBCommand::Batch(seqnum, txn.take_commands(seqnum)))
+    }
+
+    fn visible_seqnum(&self) -> usize {
+        self.visible_seqnum.load(Ordering::SeqCst)
+    }
 
-        let sst_merge = MergingIter::new(level_readers);
+    fn get(&mut self, k: &K) -> anyhow::Result<Option<V>> {
+        self.get_at(k, self.visible_seqnum())
+    }
+
+    fn get_cf(&mut self, cf: ColumnFamilyId, k: &K) -> anyhow::Result<Option<V>> {
+        self.get_cf_at(cf, k, self.visible_seqnum())
+    }
+
+    // Reads `k` as of `seqnum`.
+    fn get_at(&mut self, k: &K, seqnum: usize) -> anyhow::Result<Option<V>> {
+        self.get_cf_at(DEFAULT_COLUMN_FAMILY, k, seqnum)
+    }
+
+    fn get_cf_at(&mut self, cf: ColumnFamilyId, k: &K, seqnum: usize) -> anyhow::Result<Option<V>> {
+        self.get_cf_as_of(cf, k, seqnum, u64::MAX)
+    }
+
+    // Reads `k` as of timestamp `ts`: the newest version written at or
+    // before it.
+    fn get_at_timestamp(&mut self, k: &K, ts: u64) -> anyhow::Result<Option<V>> {
+        self.get_cf_at_timestamp(DEFAULT_COLUMN_FAMILY, k, ts)
+    }
+
+    fn get_cf_at_timestamp(
+        &mut self,
+        cf: ColumnFamilyId,
+        k: &K,
+        ts: u64,
+    ) -> anyhow::Result<Option<V>> {
+        self.check_timestamp(ts)?;
+        self.get_cf_as_of(cf, k, self.visible_seqnum(), ts)
+    }
+
+    fn get_cf_as_of(
+        &mut self,
+        cf: ColumnFamilyId,
+        k: &K,
+        seqnum: usize,
+        ts: u64,
+    ) -> anyhow::Result<Option<V>> {
+        let mut scan = self.scan_cf_as_of(cf, seqnum, ts)?;
+        scan.iter.seek_ge(k);
+        match scan.iter.next() {
+            Some((next, v)) if next == k => Ok(Some(v.clone())),
+            Some(_) => Ok(None),
+            None => scan.status().map(|_| None),
+        }
+    }
+
+    fn scan(&mut self) -> anyhow::Result<DbIterator<K, V, impl KVIter<K, V>>> {
+        self.scan_at(self.visible_seqnum.load(Ordering::SeqCst))
+    }
+
+    fn scan_cf(
+        &mut self,
+        cf: ColumnFamilyId,
+    ) -> anyhow::Result<DbIterator<K, V, impl KVIter<K, V>>> {
+        self.scan_cf_at(cf, self.visible_seqnum.load(Ordering::SeqCst))
+    }
+
+    // Scans the database as of `seqnum`.
+    fn scan_at(&mut self, seqnum: usize) -> anyhow::Result<DbIterator<K, V, impl KVIter<K, V>>> {
+        self.scan_cf_at(DEFAULT_COLUMN_FAMILY, seqnum)
+    }
+
+    fn scan_cf_at(
+        &mut self,
+        cf: ColumnFamilyId,
+        seqnum: usize,
+    ) -> anyhow::Result<DbIterator<K, V, impl KVIter<K, V>>> {
+        self.scan_cf_as_of(cf, seqnum, u64::MAX)
+    }
+
+    // Scans the database as of timestamp `ts`.
+    fn scan_at_timestamp(
+        &mut self,
+        ts: u64,
+    ) -> anyhow::Result<DbIterator<K, V, impl KVIter<K, V>>> {
+        self.scan_cf_at_timestamp(DEFAULT_COLUMN_FAMILY, ts)
+    }
+
+    fn scan_cf_at_timestamp(
+        &mut self,
+        cf: ColumnFamilyId,
+        ts: u64,
+    ) -> anyhow::Result<DbIterator<K, V, impl KVIter<K, V>>> {
+        self.check_timestamp(ts)?;
+        self.scan_cf_as_of(cf, self.visible_seqnum(), ts)
+    }
 
-        let lhs: Box<dyn KVIter<(K, usize), Option<V>>> = Box::new(sst_merge);
-        let merged = MergingIter::new([Box::new(tab), lhs]);
-        let scan = SeqnumIter::new(self.visible_seqnum.load(Ordering::SeqCst), merged);
+    fn scan_cf_as_of(
+        &mut self,
+        cf: ColumnFamilyId,
+        seqnum: usize,
+        ts: u64,
+    ) -> anyhow::Result<DbIterator<K, V, impl KVIter<K, V>>> {
+        self.check_column_family(cf)?;
+        let (merged, range_tombstones) = self.internal_iter(cf)?;
+        let scan = SeqnumIter::new(seqnum, merged)
+            .with_timestamp(ts)
+            .with_merge_operator(self.families[cf].options.merge_operator.clone())
+            .with_range_tombstones(range_tombstones)
+            .with_comparator(self.options.comparator.clone())
+            .with_expiry_time(self.options.clock.now());
         Ok(DbIterator {
             iter: scan,
             _marker: PhantomData,
         })
     }
 
-    fn flush_memtable(&mut self) -> anyhow::Result<()> {
-        let mut scan = self.layout.active_memtable.scan();
-        if scan.peek().is_none() {
-            // If the memtable is empty, don't do anything. It's simpler if we
-            // can assume that SSTs are non-empty (since they need to store
-            // their min and max keys).
-            return Ok(());
+    // Iterates over `ssts`, which mustn't overlap, only opening each one
+    // once the iterator reaches it.
+    fn level_iter(&self, ssts: &[Sst<K, V>]) -> BoxedInternalIter<K, V> {
+        let comparator = self.internal_comparator();
+        let bounds = ssts
+            .iter()
+            .map(|sst| (sst.min_key.clone(), sst.max_key.clone()))
+            .collect();
+        let filenames: Vec<_> = ssts.iter().map(|sst| sst.filename.clone()).collect();
+        let mut dir = self.dir.clone();
+        let open_comparator = comparator.clone();
+        let key_format = self.key_format();
+        Box::new(
+            LevelIter::new(bounds, move |i| {
+                let file = dir
+                    .open(&filenames[i])
+                    .ok_or_else(|| anyhow!("{} does not exist", filenames[i]))?;
+                Ok(
+                    SstReader::<_, _, D>::load_with_key_format(file, key_format.clone())?
+                        .with_comparator(open_comparator.clone()),
+                )
+            })
+            .with_comparator(comparator),
+        )
+    }
+
+    fn internal_iter(&mut self, cf: ColumnFamilyId) -> anyhow::Result<InternalIter<K, V>> {
+        let comparator = self.internal_comparator();
+        let range_tombstones = self.families[cf]
+            .layout
+            .range_tombstones(&*self.options.comparator);
+        let layout = &self.families[cf].layout;
+        let mut memtables: Vec<BoxedInternalIter<K, V>> = vec![layout.active_memtable.scan()];
+        for imm in &layout.immutable_memtables {
+            memtables.push(imm.scan());
+        }
+
+        // Every SST in L0 is read independently, but the lower-level ones get
+        // concatenated.
+        let mut level_readers: Vec<_> = layout
+            .l0
+            .iter()
+            .map(|sst| self.level_iter(std::slice::from_ref(sst)))
+            .collect();
+        for level in &layout.ssts {
+            if !level.is_empty() {
+                level_readers.push(self.level_iter(level));
+            }
+        }
+
+        let sst_merge = MergingIter::new(level_readers).with_comparator(comparator.clone());
+
+        memtables.push(Box::new(sst_merge));
+        Ok((
+            MergingIter::new(memtables).with_comparator(comparator),
+            range_tombstones,
+        ))
+    }
+
+    // Every version of `k` that is still around, newest (by seqnum) first.
+    // Range deletions which cover it show up as deletes. How far back this
+    // goes depends on `DbOptions::history_retention`.
+    fn history(&mut self, k: &K) -> anyhow::Result<Vec<KeyVersion<V>>> {
+        self.history_cf(DEFAULT_COLUMN_FAMILY, k)
+    }
+
+    fn history_cf(&mut self, cf: ColumnFamilyId, k: &K) -> anyhow::Result<Vec<KeyVersion<V>>> {
+        self.check_column_family(cf)?;
+        let visible = self.visible_seqnum();
+        let (mut iter, range_tombstones) = self.internal_iter(cf)?;
+        let mut versions: Vec<_> = range_tombstones
+            .covering(&*self.options.comparator, k)
+            .iter()
+            .map(|seqnum| KeyVersion {
+                seqnum: *seqnum,
+                timestamp: 0,
+                value: DbValue::Delete,
+            })
+            .collect();
+        iter.seek_ge(&(k.clone(), 0, 0));
+        while let Some(((next, timestamp, seqnum), value)) = iter.next() {
+            if next != k {
+                break;
+            }
+            versions.push(KeyVersion {
+                seqnum: *seqnum,
+                timestamp: *timestamp,
+                value: value.clone(),
+            });
         }
+        versions.retain(|version| version.seqnum <= visible);
+        versions.sort_by_key(|version| std::cmp::Reverse(version.seqnum));
+        Ok(versions)
+    }
+
+    // Writes a copy of the database, as it is now, into `target`, where it
+    // can be opened on its own. SSTs are hard-linked into it where possible
+    // and copied otherwise. WALs are always copied, since they're still being
+    // written to. The manifest goes last, so an unfinished checkpoint can't
+    // be opened.
+    fn checkpoint(&mut self, mut target: D) -> anyhow::Result<()> {
+        if Root::<DiskLayout, D>::exists(&mut target) {
+            bail!("there's already a database where the checkpoint was to go");
+        }
+        for sst in self.live_ssts() {
+            if !self.dir.hard_link(&sst, &mut target, &sst)? {
+                fs::copy_file(&mut self.dir, &sst, &mut target, &sst)?;
+            }
+        }
+        for wal in &self.root.data.wals {
+            fs::copy_file(&mut self.dir, wal, &mut target, wal)?;
+        }
+        self.root.copy_to(&mut target)?;
+        Ok(())
+    }
 
+    // The names of every SST in the database.
+    fn live_ssts(&self) -> Vec<String> {
+        self.root
+            .data
+            .families
+            .iter()
+            .flat_map(|family| family.l0.iter().chain(family.ssts.iter().flatten()))
+            .map(|sst| sst.filename.clone())
+            .collect()
+    }
+
+    // The seqnum of the newest write to `k`, including range deletions that
+    // cover it, or 0 if it has never been written.
+    fn latest_seqnum(&mut self, k: &K) -> anyhow::Result<usize> {
+        let (mut iter, range_tombstones) = self.internal_iter(DEFAULT_COLUMN_FAMILY)?;
+        let mut latest = range_tombstones.covering_seqnum(&*self.options.comparator, k, usize::MAX);
+        iter.seek_ge(&(k.clone(), 0, 0));
+        while let Some(((next, _, seqnum), _)) = iter.next() {
+            if self.options.comparator.compare(next, k) != std::cmp::Ordering::Equal {
+                break;
+            }
+            latest = std::cmp::max(latest, *seqnum);
+        }
+        // A version in an SST that couldn't be read might be the newest.
+        if let Some(err) = iter.take_error() {
+            return Err(err);
+        }
+        Ok(latest)
+    }
+
+    // Records the `edits` a flush makes in the manifest, along with the
+    // removal of any WALs they leave with nothing unflushed, and a flush time
+    // sample if history is retained by time.
+    fn edit_root_after_flush(&mut self, mut edits: Vec<VersionEdit>) -> anyhow::Result<()> {
+        if let HistoryRetention::Duration(retention) = self.options.history_retention {
+            let now = self.options.clock.now();
+            edits.push(VersionEdit::FlushTime {
+                seqnum: self.visible_seqnum(),
+                now,
+                horizon: now.saturating_sub(retention.as_millis() as u64),
+            });
+        }
+        let mut layout = self.root.data.clone();
+        for edit in &edits {
+            layout.apply(edit.clone())?;
+        }
+        let obsolete = layout.obsolete_wals(&self.unflushed_families());
+        if self.options.wal_archive == WalArchive::Disabled {
+            edits.extend(obsolete.into_iter().map(VersionEdit::RemoveWal));
+        } else {
+            edits.extend(self.archive_wals(&layout, obsolete)?);
+        }
+        self.root.edit(edits.clone())?;
+        // The files only move once the manifest says where they've gone.
+        for edit in edits {
+            match edit {
+                VersionEdit::ArchiveWal(wal) => {
+                    self.dir.rename(&wal.name, &archived_wal_path(&wal.name))?
+                }
+                VersionEdit::RemoveArchivedWal(wal) => {
+                    self.dir.unlink(&archived_wal_path(&wal))?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // The edits that move `wals` into the archive, and then drop the oldest
+    // WALs in it that are beyond its limit.
+    fn archive_wals(
+        &mut self,
+        layout: &DiskLayout,
+        wals: Vec<String>,
+    ) -> anyhow::Result<Vec<VersionEdit>> {
+        let now = self.options.clock.now();
+        let mut archived = layout.archived_wals.clone();
+        let mut edits = Vec::new();
+        for name in wals {
+            let num_bytes = self
+                .dir
+                .open(&name)
+                .ok_or_else(|| anyhow!("{} does not exist", name))?
+                .len();
+            let wal = ArchivedWal {
+                name,
+                num_bytes,
+                archived_at: now,
+            };
+            archived.push(wal.clone());
+            edits.push(VersionEdit::ArchiveWal(wal));
+        }
+        let expired = match self.options.wal_archive {
+            WalArchive::Disabled => archived.len(),
+            WalArchive::MaxBytes(max) => {
+                let mut total = 0;
+                let kept = archived
+                    .iter()
+                    .rev()
+                    .take_while(|wal| {
+                        total += wal.num_bytes;
+                        total <= max
+                    })
+                    .count();
+                archived.len() - kept
+            }
+            WalArchive::MaxAge(age) => {
+                let horizon = now.saturating_sub(age.as_millis() as u64);
+                archived
+                    .iter()
+                    .take_while(|wal| wal.archived_at < horizon)
+                    .count()
+            }
+        };
+        edits.extend(
+            archived[..expired]
+                .iter()
+                .map(|wal| VersionEdit::RemoveArchivedWal(wal.name.clone())),
+        );
+        Ok(edits)
+    }
+
+    // The commands with seqnums in (`after`, `upto`], from every WAL that's
+    // still around, archived or not, in the order they were written.
+    fn wal_commands(&mut self, after: usize, upto: usize) -> anyhow::Result<Vec<DBCommand<K, V>>> {
+        let data = &self.root.data;
+        let oldest = data
+            .archived_wals
+            .first()
+            .map(|wal| &wal.name)
+            .or(data.wals.first());
+        if oldest.is_none_or(|wal| wal_lower_bound(wal) > after) {
+            bail!("the WALs no longer go back to seqnum {}", after);
+        }
+        // Archived WALs are all older than the ones still in use.
+        let paths: Vec<_> = data
+            .archived_wals
+            .iter()
+            .map(|wal| archived_wal_path(&wal.name))
+            .chain(data.wals.iter().cloned())
+            .collect();
+        let mut commands = Vec::new();
+        for path in paths {
+            let wal = self
+                .dir
+                .open(&path)
+                .ok_or_else(|| anyhow!("{} does not exist", path))?;
+            commands.extend(
+                LogReader::<_, DBCommand<K, V>>::new(wal)?
+                    .filter(|command| (after + 1..=upto).contains(&command.seqnum())),
+            );
+        }
+        Ok(commands)
+    }
+
+    // Versions above this seqnum are within the history retention window.
+    fn retain_above(&self) -> usize {
+        match self.options.history_retention {
+            HistoryRetention::Disabled => usize::MAX,
+            HistoryRetention::Seqnums(n) => self.visible_seqnum().saturating_sub(n),
+            HistoryRetention::Duration(retention) => {
+                let horizon = self
+                    .options
+                    .clock
+                    .now()
+                    .saturating_sub(retention.as_millis() as u64);
+                self.root
+                    .data
+                    .flush_times
+                    .iter()
+                    .rev()
+                    .find(|(_, t)| *t <= horizon)
+                    .map_or(0, |(seqnum, _)| *seqnum)
+            }
+        }
+    }
+
+    // Writes the contents of `it`, along with `range_tombstones`, out to the
+    // next available SST name, as part of `cf`.
+    fn write_sst<I>(
+        &mut self,
+        cf: ColumnFamilyId,
+        it: I,
+        range_tombstones: &[RangeTombstone<K>],
+    ) -> anyhow::Result<SstMetadata>
+    where
+        I: Iterator<Item = ((K, u64, usize), DbValue<V>)>,
+    {
         let sst_path = format!("sst{}.sst", self.root.data.next_sst_id);
 
         // TODO: create a like, "create if not already exists"
@@ -577,50 +2355,236 @@ where
             .dir
             .create(&sst_path)?
             .expect("sst file already existed");
-        let writer = SstWriter::new(scan, sst_file);
-        writer.write()?;
+        let writer = SstWriter::new(it, sst_file)
+            .with_comparator(self.internal_comparator())
+            .with_key_format(self.key_format())
+            .with_column_family(cf, self.families[cf].name.clone())
+            .with_paranoid_checks(self.options.paranoid_checks)
+            .with_seqnums(|(_, _, seqnum)| *seqnum)
+            .with_range_tombstones(
+                range_tombstones
+                    .iter()
+                    .map(RangeTombstone::to_bounds)
+                    .collect(),
+            );
+        let (meta, stats) = writer.write()?;
 
-        self.layout.flush_memtable();
-        // Add it to L0.
-        self.layout
-            .l0
-            .push(Sst::new(&mut self.dir, sst_path.clone()));
+        if self.options.paranoid_checks {
+            let file = self
+                .dir
+                .open(&sst_path)
+                .ok_or_else(|| anyhow!("{} does not exist", sst_path))?;
+            SstReader::<(K, u64, usize), DbValue<V>, D>::load_with_key_format(
+                file,
+                self.key_format(),
+            )?
+            .with_comparator(self.internal_comparator())
+            .verify()?;
+        }
 
-        // TODO: include the lower bound?
+        Ok(SstMetadata {
+            filename: sst_path,
+            min_key: Bytes::encode(&meta.min_key),
+            max_key: Bytes::encode(&meta.max_key),
+            range_tombstones: Bytes::encode(&meta.range_tombstones),
+            num_bytes: meta.num_bytes,
+            num_entries: stats.num_entries,
+            min_seqnum: stats.seqnums.map_or(0, |(min, _)| min),
+            max_seqnum: stats.seqnums.map_or(0, |(_, max)| max),
+            created_at: self.options.clock.now(),
+        })
+    }
+
+    // Writes out the contents of one of `cf`'s memtables, after running them
+    // through its compaction filter, if it has one.
+    fn write_memtable_sst(
+        &mut self,
+        cf: ColumnFamilyId,
+        scan: BoxedInternalIter<K, V>,
+        range_tombstones: &[RangeTombstone<K>],
+    ) -> anyhow::Result<SstMetadata> {
+        // Nothing is folded together on the way out of a memtable, but the
+        // compaction filter still applies.
+        let comparator = self.options.comparator.clone();
+        let compaction_filter = self.families[cf].options.compaction_filter.clone();
+        let mut entries = Compaction {
+            compaction_filter: compaction_filter.as_deref(),
+            retain_above: self.retain_above(),
+            ..Compaction::new(&*comparator)
+        }
+        .compact(scan, Vec::new())
+        .0;
+        let meta = self.write_sst(cf, &mut entries, range_tombstones)?;
+        if let Some(err) = entries.take_error() {
+            return Err(err);
+        }
+        Ok(meta)
+    }
+
+    // Starts a new WAL, so that the current one can be dropped once
+    // everything in it has been flushed. Returns the new WAL's name, or `None`
+    // if nothing has been written to the current one since it was started.
+    fn roll_wal(&mut self) -> anyhow::Result<Option<String>> {
+        if wal_lower_bound(self.wal.fname()) == self.next_seqnum {
+            return Ok(None);
+        }
         self.wal = Log::new(self.dir.clone(), self.next_seqnum)?;
-        // When we open we create a fresh WAL, so we need to add that to the root.
-        let wal_name = self.wal.fname().to_owned();
-        let sst_name = sst_path.clone();
-        let max_used_seqnum = self.next_seqnum - 1;
-        self.root.transform(move |mut layout| {
-            layout.next_sst_id += 1;
+        Ok(Some(self.wal.fname().to_owned()))
+    }
+
+    fn freeze_memtable(&mut self) -> anyhow::Result<()> {
+        self.freeze_memtable_cf(DEFAULT_COLUMN_FAMILY)
+    }
+
+    // Sets the active memtable of `cf` aside and starts a new one (along with
+    // a new WAL). The frozen memtable continues to serve reads until it is
+    // flushed.
+    fn freeze_memtable_cf(&mut self, cf: ColumnFamilyId) -> anyhow::Result<()> {
+        self.check_column_family(cf)?;
+        if self.families[cf].layout.active_memtable.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(wal_name) = self.roll_wal()? {
+            self.root.edit(vec![VersionEdit::AddWal(wal_name)])?;
+        }
+
+        self.families[cf].layout.freeze_memtable();
+
+        Ok(())
+    }
+
+    // Flushes every frozen memtable of `cf`, oldest first, each into its own
+    // SST in L0. Any WALs this leaves with nothing unflushed are dropped in
+    // the same root update that adds the SST.
+    fn flush_immutable_memtables(&mut self, cf: ColumnFamilyId) -> anyhow::Result<()> {
+        while !self.families[cf].layout.immutable_memtables.is_empty() {
+            let memtable = &self.families[cf].layout.immutable_memtables[0];
+            let scan = memtable.scan();
+            let range_tombstones = memtable.range_tombstones().to_vec();
+            let sst_meta = self.write_memtable_sst(cf, scan, &range_tombstones)?;
+
+            let flushed = self.families[cf].layout.immutable_memtables.remove(0);
+            let sst = Sst::from_metadata(&sst_meta)?;
+            self.families[cf].layout.l0.push(sst);
+            self.families[cf].layout.range_tombstones = None;
+
+            let max_seqnum = std::cmp::max(
+                self.root.data.families[cf].max_sst_seqnum,
+                flushed.max_seqnum(),
+            );
+            self.edit_root_after_flush(vec![
+                VersionEdit::NextSstId(self.root.data.next_sst_id + 1),
+                VersionEdit::AddSst {
+                    cf,
+                    level: 0,
+                    index: 0,
+                    sst: sst_meta,
+                },
+                VersionEdit::MaxSstSeqnum {
+                    cf,
+                    seqnum: max_seqnum,
+                },
+            ])?;
+        }
+        self.update_write_buffer_usage();
+
+        Ok(())
+    }
+
+    fn flush_memtable(&mut self) -> anyhow::Result<()> {
+        self.flush_memtable_cf(DEFAULT_COLUMN_FAMILY)
+    }
+
+    fn flush_memtable_cf(&mut self, cf: ColumnFamilyId) -> anyhow::Result<()> {
+        self.check_column_family(cf)?;
+        self.flush_immutable_memtables(cf)?;
+
+        if self.families[cf].layout.active_memtable.is_empty() {
+            // If the memtable is empty, don't do anything. It's simpler if we
+            // can assume that SSTs are non-empty (since they need to store
+            // their min and max keys).
+            return Ok(());
+        }
 
-            layout.wals = vec![wal_name];
-            layout.l0.push(sst_name);
-            assert!(layout.max_sst_seqnum <= max_used_seqnum);
-            layout.max_sst_seqnum = max_used_seqnum;
+        let scan = self.families[cf].layout.active_memtable.scan();
+        let range_tombstones = self.families[cf]
+            .layout
+            .active_memtable
+            .range_tombstones()
+            .to_vec();
+        let sst_meta = self.write_memtable_sst(cf, scan, &range_tombstones)?;
 
-            layout
-        })?;
+        self.families[cf].layout.flush_memtable();
+        // Add it to L0.
+        let sst = Sst::from_metadata(&sst_meta)?;
+        self.families[cf].layout.l0.push(sst);
+        self.families[cf].layout.range_tombstones = None;
+
+        let wal_name = self.roll_wal()?;
+        // Every write to this family so far is now in an SST.
+        let max_used_seqnum = self.next_seqnum;
+        assert!(self.root.data.families[cf].max_sst_seqnum <= max_used_seqnum);
+        let mut edits = vec![VersionEdit::NextSstId(self.root.data.next_sst_id + 1)];
+        edits.extend(wal_name.map(VersionEdit::AddWal));
+        edits.extend([
+            VersionEdit::AddSst {
+                cf,
+                level: 0,
+                index: 0,
+                sst: sst_meta,
+            },
+            VersionEdit::MaxSstSeqnum {
+                cf,
+                seqnum: max_used_seqnum,
+            },
+        ]);
+        self.edit_root_after_flush(edits)?;
+        self.update_write_buffer_usage();
 
         Ok(())
     }
 }
 
+impl<D, K, V> Drop for Db<D, K, V>
+where
+    D: DbDir,
+    K: std::fmt::Debug + Ord + Clone + Encode + Decode + Default,
+    V: std::fmt::Debug + Clone + Encode + Decode + Default,
+{
+    fn drop(&mut self) {
+        // Hand our share of the write buffer budget back.
+        if let Some(manager) = self.options.write_buffer_manager.as_deref() {
+            manager.free(self.write_buffer_reserved);
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
 
-    use std::{collections::BTreeMap, fmt::Write, rc::Rc};
+    use std::{collections::BTreeMap, fmt::Write, sync::Arc, time::Duration};
 
     use rand::Rng;
 
     use crate::{
-        fs::{DbDir, MockDir},
-        memtable::{KVIter, VecIter},
+        comparator::ReverseComparator,
+        fs::{DbDir, DbFile, MockDir},
+        memtable::{DbValue, KVIter, MemtableRepKind},
         sst::{reader::SstReader, writer::SstWriter},
     };
 
-    use super::Db;
+    use super::{
+        compaction_filter::PrefixFilter,
+        lock_manager::LockError,
+        options::{DbOptions, HistoryRetention},
+        transaction::TransactionConflict,
+        write_batch::WriteBatch,
+        write_buffer_manager::WriteBufferManager,
+        write_stall::{WriteStall, WriteStallCause, WriteStopped},
+        Db, DiskLayout, KeyVersion, VersionEdit, DEFAULT_COLUMN_FAMILY,
+    };
+    use crate::root::Versioned;
 
     #[test]
     // This is really slow.
@@ -675,7 +2639,7 @@ mod test {
                 "flush" => {
                     let sst_fname = "/tmp/test_sst.sst";
                     let file = dir.create(&sst_fname).unwrap().unwrap();
-                    let writer = SstWriter::new(VecIter::new(Rc::new(data.clone())), file);
+                    let writer = SstWriter::new(data.clone().into_iter(), file);
                     writer.write().unwrap();
                     reader = Some(SstReader::load(dir.open(&sst_fname).unwrap()).unwrap());
                     "ok\n".into()
@@ -758,7 +2722,7 @@ mod test {
                 .unwrap();
         }
 
-        let _fname = db.flush_memtable().unwrap();
+        db.flush_memtable().unwrap();
 
         for i in 10..20 {
             db.insert(format!("memkey{}", i), format!("bar{}", i))
@@ -807,7 +2771,7 @@ mod test {
                 .unwrap();
         }
 
-        let _fname = db.flush_memtable().unwrap();
+        db.flush_memtable().unwrap();
 
         for i in 10..20 {
             db.insert(format!("memkey{}", i), format!("bar{}", i))
@@ -822,4 +2786,1040 @@ mod test {
 
         assert_eq!(prev_data, post_data);
     }
+
+    #[test]
+    fn test_write_stall() {
+        let dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::with_options(
+            dir,
+            DbOptions {
+                l0_slowdown_trigger: 1,
+                l0_stop_trigger: 2,
+                slowdown_delay: std::time::Duration::ZERO,
+                write_stop_timeout: std::time::Duration::ZERO,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        db.insert("a".into(), "1".into()).unwrap();
+        assert_eq!(db.last_write_stall, None);
+        db.flush_memtable().unwrap();
+
+        // One SST in L0 only slows writes down.
+        db.insert("b".into(), "2".into()).unwrap();
+        assert_eq!(
+            db.last_write_stall,
+            Some(WriteStall::Slowdown(WriteStallCause::L0FileCount(1)))
+        );
+        db.flush_memtable().unwrap();
+
+        // Two stops them outright, and says why.
+        let err = db.insert("c".into(), "3".into()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<WriteStopped>(),
+            Some(&WriteStopped(WriteStallCause::L0FileCount(2)))
+        );
+        assert_eq!(db.get(&"c".into()).unwrap(), None);
+
+        // Compacting L0 away lets writes through again.
+        db.merge(vec![(0, 0), (0, 1)], 1).unwrap();
+        assert_eq!(db.write_stall(), None);
+        db.insert("c".into(), "3".into()).unwrap();
+        assert_eq!(db.get(&"c".into()).unwrap(), Some("3".into()));
+
+        // Given time, a stopped write compacts L0 itself.
+        db.options.write_stop_timeout = std::time::Duration::from_secs(1);
+        db.flush_memtable().unwrap();
+        db.insert("d".into(), "4".into()).unwrap();
+        db.flush_memtable().unwrap();
+        db.insert("e".into(), "5".into()).unwrap();
+        assert!(db.families[0].layout.l0.is_empty());
+        assert_eq!(db.scan().unwrap().count(), 5);
+
+        // Frozen memtables are flushed the same way.
+        db.options.immutable_memtable_stop_trigger = 1;
+        db.freeze_memtable().unwrap();
+        db.insert("f".into(), "6".into()).unwrap();
+        assert!(db.families[0].layout.immutable_memtables.is_empty());
+        assert_eq!(db.scan().unwrap().count(), 6);
+    }
+
+    #[test]
+    fn test_pending_compaction_bytes() {
+        let dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::new(dir).unwrap();
+        for k in ["a", "b", "c"] {
+            db.insert(k.into(), "1".into()).unwrap();
+            db.flush_memtable().unwrap();
+        }
+        let l0 = db.families[0].layout.l0.clone();
+        let l0_bytes: usize = l0.iter().map(|sst| sst.num_bytes).sum();
+        let layout = &mut db.families[0].layout;
+        assert_eq!(layout.pending_compaction_bytes(100, 10), l0_bytes);
+
+        // Only what's over each level's target counts, along with what the
+        // levels above push into it, but the last level is never in debt.
+        layout.l0.clear();
+        layout.ssts = vec![l0[..2].to_vec(), l0[2..].to_vec()];
+        let l1_bytes = l0[0].num_bytes + l0[1].num_bytes;
+        assert_eq!(layout.pending_compaction_bytes(l1_bytes, 10), 0);
+        assert_eq!(layout.pending_compaction_bytes(l1_bytes - 5, 10), 5);
+        layout.ssts.reverse();
+        assert_eq!(layout.pending_compaction_bytes(l1_bytes - 5, 10), 0);
+    }
+
+    #[test]
+    fn test_write_buffer_size() {
+        let dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::with_options(
+            dir,
+            DbOptions {
+                write_buffer_size: 300,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        for i in 0..3 {
+            db.insert(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        assert!(db.families[0].layout.l0.is_empty());
+        assert!(db.families[0].layout.active_memtable.approximate_bytes() > 0);
+
+        // The fourth write pushes the memtable over its budget, and the fifth
+        // flushes it before going into a fresh one.
+        db.insert("key3".into(), "value3".into()).unwrap();
+        assert!(db.families[0].layout.l0.is_empty());
+        let full = db.families[0].layout.active_memtable.approximate_bytes();
+        assert!(full >= 300);
+        db.insert("key4".into(), "value4".into()).unwrap();
+        assert_eq!(db.families[0].layout.l0.len(), 1);
+        assert!(db.families[0].layout.active_memtable.approximate_bytes() < full);
+        assert_eq!(db.get(&"key0".into()).unwrap(), Some("value0".into()));
+        assert_eq!(db.get(&"key4".into()).unwrap(), Some("value4".into()));
+    }
+
+    #[test]
+    fn test_write_buffer_manager() {
+        let manager = Arc::new(WriteBufferManager::new(500));
+        let opts = DbOptions {
+            write_buffer_manager: Some(manager.clone()),
+            ..Default::default()
+        };
+        let mut a: Db<_, String, String> = Db::with_options(MockDir::new(), opts.clone()).unwrap();
+        let mut b: Db<_, String, String> = Db::with_options(MockDir::new(), opts).unwrap();
+
+        for i in 0..4 {
+            a.insert(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        assert!(a.families[0].layout.l0.is_empty());
+        assert_eq!(
+            manager.memory_usage(),
+            a.families[0].layout.memtable_bytes()
+        );
+
+        // Neither database is over budget on its own, but together they are,
+        // so the one doing the next write flushes its largest memtable, and
+        // leaves its nearly empty family alone.
+        let small = b
+            .create_column_family("small", DbOptions::default())
+            .unwrap();
+        b.insert_cf(small, "k".into(), "v".into()).unwrap();
+        for i in 0..5 {
+            b.insert(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        assert!(a.families[0].layout.l0.is_empty());
+        assert!(!b.families[0].layout.l0.is_empty());
+        assert!(b.families[small].layout.l0.is_empty());
+        let bytes = |db: &Db<_, String, String>| {
+            db.families
+                .iter()
+                .map(|family| family.layout.memtable_bytes())
+                .sum::<usize>()
+        };
+        assert_eq!(manager.memory_usage(), bytes(&a) + bytes(&b));
+
+        drop(a);
+        drop(b);
+        assert_eq!(manager.memory_usage(), 0);
+    }
+
+    #[test]
+    fn test_skiplist_memtable() {
+        let dir = MockDir::new();
+        let opts = DbOptions {
+            memtable_rep: MemtableRepKind::SkipList,
+            ..Default::default()
+        };
+        let mut db: Db<_, String, String> = Db::with_options(dir.clone(), opts.clone()).unwrap();
+        let mut map = BTreeMap::new();
+
+        let mut rng = rand::thread_rng();
+        for i in 0..200 {
+            let key = format!("key{}", rng.gen_range(0..50));
+            if rng.gen_range(0..4) == 0 {
+                db.delete(key.clone()).unwrap();
+                map.remove(&key);
+            } else {
+                let value = format!("value{}", i);
+                db.insert(key.clone(), value.clone()).unwrap();
+                map.insert(key, value);
+            }
+            if i == 100 {
+                db.flush_memtable().unwrap();
+            }
+        }
+
+        let expected: Vec<_> = map.into_iter().collect();
+        assert_eq!(db.scan().unwrap().collect::<Vec<_>>(), expected);
+
+        let mut reverse = db.scan().unwrap().iter;
+        reverse.end();
+        let mut reversed = Vec::new();
+        while let Some((k, v)) = reverse.prev() {
+            reversed.push((k.clone(), v.clone()));
+        }
+        reversed.reverse();
+        assert_eq!(reversed, expected);
+
+        let mut db: Db<_, String, String> = Db::with_options(dir, opts).unwrap();
+        assert_eq!(db.scan().unwrap().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_transaction() {
+        let dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::new(dir.clone()).unwrap();
+        db.insert("a".into(), "1".into()).unwrap();
+        db.insert("b".into(), "1".into()).unwrap();
+        db.flush_memtable().unwrap();
+
+        // Reads see the snapshot and the transaction's own writes, and
+        // nothing is visible outside until commit.
+        let mut txn = db.transaction();
+        assert_eq!(txn.get(&mut db, &"a".into()).unwrap(), Some("1".into()));
+        txn.insert("a".into(), "2".into());
+        txn.delete("b".into());
+        txn.insert("c".into(), "2".into());
+        assert_eq!(txn.get(&mut db, &"a".into()).unwrap(), Some("2".into()));
+        assert_eq!(txn.get(&mut db, &"b".into()).unwrap(), None);
+        assert_eq!(db.get(&"a".into()).unwrap(), Some("1".into()));
+        db.commit(txn).unwrap();
+
+        let expected = vec![("a".into(), "2".into()), ("c".into(), "2".into())];
+        assert_eq!(db.scan().unwrap().collect::<Vec<_>>(), expected);
+
+        // A key read by the transaction has been written since.
+        let mut txn = db.transaction();
+        assert_eq!(txn.get(&mut db, &"a".into()).unwrap(), Some("2".into()));
+        db.insert("a".into(), "3".into()).unwrap();
+        assert_eq!(txn.get(&mut db, &"a".into()).unwrap(), Some("2".into()));
+        txn.insert("c".into(), "3".into());
+        let err = db.commit(txn).unwrap_err();
+        assert!(err.downcast_ref::<TransactionConflict>().is_some());
+        assert_eq!(db.get(&"c".into()).unwrap(), Some("2".into()));
+
+        // Writes to keys the transaction didn't read don't conflict, including
+        // once they've been flushed.
+        let mut txn = db.transaction();
+        assert_eq!(txn.get(&mut db, &"b".into()).unwrap(), None);
+        db.insert("c".into(), "4".into()).unwrap();
+        db.flush_memtable().unwrap();
+        txn.insert("b".into(), "4".into());
+        db.commit(txn).unwrap();
+
+        // The batch survives a reload.
+        let mut db: Db<_, String, String> = Db::new(dir).unwrap();
+        let expected = vec![
+            ("a".into(), "3".into()),
+            ("b".into(), "4".into()),
+            ("c".into(), "4".into()),
+        ];
+        assert_eq!(db.scan().unwrap().collect::<Vec<_>>(), expected);
+
+        // Range deletions count as writes to every key they cover.
+        let mut txn = db.transaction();
+        assert_eq!(txn.get(&mut db, &"b".into()).unwrap(), Some("4".into()));
+        db.delete_range("a".into(), "c".into()).unwrap();
+        txn.insert("d".into(), "5".into());
+        assert!(db.commit(txn).is_err());
+    }
+
+    #[test]
+    fn test_pessimistic_transaction() {
+        let dir = MockDir::new();
+        let opts = DbOptions {
+            lock_timeout: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let mut db: Db<_, String, String> = Db::with_options(dir, opts).unwrap();
+        db.insert("a".into(), "1".into()).unwrap();
+
+        let mut t1 = db.pessimistic_transaction();
+        let mut t2 = db.pessimistic_transaction();
+        assert_eq!(t1.get(&mut db, &"a".into()).unwrap(), Some("1".into()));
+        assert_eq!(t2.get(&mut db, &"a".into()).unwrap(), Some("1".into()));
+
+        // Both hold shared locks, so neither can write.
+        let err = t2.insert("a".into(), "2".into()).unwrap_err();
+        assert_eq!(err.downcast_ref::<LockError>(), Some(&LockError::Timeout));
+        drop(t2);
+
+        let v = t1.get_for_update(&mut db, &"a".into()).unwrap().unwrap();
+        t1.insert("a".into(), format!("{}+", v)).unwrap();
+        let mut t3 = db.pessimistic_transaction();
+        assert!(t3.get(&mut db, &"a".into()).is_err());
+        db.commit_pessimistic(t1).unwrap();
+
+        // Once t1's locks are released, t3 reads its write.
+        assert_eq!(t3.get(&mut db, &"a".into()).unwrap(), Some("1+".into()));
+        t3.delete("a".into()).unwrap();
+        db.commit_pessimistic(t3).unwrap();
+        assert_eq!(db.get(&"a".into()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_conditional_writes() {
+        let dir = MockDir::new();
+        let opts = DbOptions {
+            lock_timeout: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let mut db: Db<_, String, String> = Db::with_options(dir, opts).unwrap();
+
+        assert!(db.put_if_absent("lease".into(), "a".into()).unwrap());
+        assert!(!db.put_if_absent("lease".into(), "b".into()).unwrap());
+        assert_eq!(db.get(&"lease".into()).unwrap(), Some("a".into()));
+
+        // The expected value has to match exactly, including through a flush.
+        db.flush_memtable().unwrap();
+        assert!(!db
+            .compare_and_swap("lease".into(), Some("b".into()), Some("c".into()))
+            .unwrap());
+        assert!(db
+            .compare_and_swap("lease".into(), Some("a".into()), Some("c".into()))
+            .unwrap());
+        assert_eq!(db.get(&"lease".into()).unwrap(), Some("c".into()));
+
+        // A `None` new value releases it...
+        assert!(db
+            .compare_and_swap("lease".into(), Some("c".into()), None)
+            .unwrap());
+        assert_eq!(db.get(&"lease".into()).unwrap(), None);
+        // ...after which it can be taken again.
+        assert!(db.put_if_absent("lease".into(), "d".into()).unwrap());
+        assert!(!db
+            .compare_and_swap("other".into(), Some("d".into()), Some("e".into()))
+            .unwrap());
+        assert_eq!(db.get(&"other".into()).unwrap(), None);
+
+        // A key locked by a pessimistic transaction can't be swapped until
+        // the transaction lets go of it.
+        let mut txn = db.pessimistic_transaction();
+        txn.get_for_update(&mut db, &"lease".into()).unwrap();
+        assert!(db
+            .compare_and_swap("lease".into(), Some("d".into()), Some("e".into()))
+            .is_err());
+        assert!(db.put_if_absent("other".into(), "e".into()).unwrap());
+        drop(txn);
+        assert!(db
+            .compare_and_swap("lease".into(), Some("d".into()), Some("e".into()))
+            .unwrap());
+
+        // Other column families are separate.
+        let cf = db
+            .create_column_family("leases", DbOptions::default())
+            .unwrap();
+        assert!(db.put_if_absent_cf(cf, "lease".into(), "x".into()).unwrap());
+        assert!(!db.put_if_absent_cf(cf, "lease".into(), "y".into()).unwrap());
+        assert!(db
+            .compare_and_swap_cf(cf, "lease".into(), Some("x".into()), None)
+            .unwrap());
+        assert_eq!(db.get_cf(cf, &"lease".into()).unwrap(), None);
+        assert_eq!(db.get(&"lease".into()).unwrap(), Some("e".into()));
+    }
+
+    #[test]
+    fn test_compaction_filter() {
+        let dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::with_options(
+            dir,
+            DbOptions {
+                compaction_filter: Some(Arc::new(PrefixFilter("deleted-tenant/"))),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        db.insert("deleted-tenant/a".into(), "1".into()).unwrap();
+        db.insert("tenant/a".into(), "2".into()).unwrap();
+        db.flush_memtable().unwrap();
+        db.insert("deleted-tenant/b".into(), "3".into()).unwrap();
+        // Nothing is filtered until a flush gets to it.
+        assert_eq!(
+            db.get(&"deleted-tenant/b".into()).unwrap(),
+            Some("3".into())
+        );
+        db.flush_memtable().unwrap();
+
+        assert_eq!(
+            db.scan().unwrap().collect::<Vec<_>>(),
+            vec![("tenant/a".into(), "2".into())]
+        );
+        // The removed values stay hidden through a compaction.
+        db.merge(vec![(0, 0), (0, 1)], 1).unwrap();
+        assert_eq!(db.get(&"deleted-tenant/a".into()).unwrap(), None);
+        assert_eq!(db.get(&"tenant/a".into()).unwrap(), Some("2".into()));
+    }
+
+    #[test]
+    fn test_column_families() {
+        let dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::new(dir.clone()).unwrap();
+        let meta = db
+            .create_column_family("meta", DbOptions::default())
+            .unwrap();
+        assert_eq!(
+            db.create_column_family("meta", DbOptions::default())
+                .unwrap(),
+            meta
+        );
+        assert!(db.insert_cf(meta + 1, "k".into(), "v".into()).is_err());
+
+        // The same key lives independently in each family.
+        db.insert("k".into(), "data".into()).unwrap();
+        db.insert_cf(meta, "k".into(), "meta".into()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.insert(DEFAULT_COLUMN_FAMILY, "j".into(), "data".into());
+        batch.insert(meta, "j".into(), "meta".into());
+        batch.delete(meta, "k".into());
+        db.write(batch).unwrap();
+        assert_eq!(db.get(&"k".into()).unwrap(), Some("data".into()));
+        assert_eq!(db.get_cf(meta, &"k".into()).unwrap(), None);
+        assert_eq!(db.get_cf(meta, &"j".into()).unwrap(), Some("meta".into()));
+
+        // Flushing one family leaves the WAL in place for the other...
+        db.flush_memtable_cf(meta).unwrap();
+        assert_eq!(db.root.data.wals.len(), 2);
+        let mut db: Db<_, String, String> = Db::new(dir.clone()).unwrap();
+        let meta = db.column_family("meta").unwrap();
+        let scan = |db: &mut Db<_, String, String>, cf| db.scan_cf(cf).unwrap().collect::<Vec<_>>();
+        assert_eq!(
+            scan(&mut db, DEFAULT_COLUMN_FAMILY),
+            vec![("j".into(), "data".into()), ("k".into(), "data".into())]
+        );
+        // ...without the replayed WAL adding anything to the flushed one.
+        assert_eq!(scan(&mut db, meta), vec![("j".into(), "meta".into())]);
+        assert!(db.families[meta].layout.active_memtable.is_empty());
+
+        // Once both are flushed, only the newest WAL is needed.
+        db.flush_memtable().unwrap();
+        assert_eq!(db.root.data.wals, vec![db.wal.fname().to_owned()]);
+    }
+
+    #[test]
+    fn test_bad_version_edits() {
+        let mut layout = DiskLayout::new();
+        assert!(layout
+            .apply(VersionEdit::MaxSstSeqnum { cf: 1, seqnum: 5 })
+            .is_err());
+        assert!(layout
+            .apply(VersionEdit::RemoveSst {
+                cf: 1,
+                filename: "sst-0".into()
+            })
+            .is_err());
+        layout.apply(VersionEdit::AddFamily("meta".into())).unwrap();
+        layout
+            .apply(VersionEdit::MaxSstSeqnum { cf: 1, seqnum: 5 })
+            .unwrap();
+        assert_eq!(layout.families[1].max_sst_seqnum, 5);
+    }
+
+    #[test]
+    fn test_missing_merge_operator() {
+        use super::merge_operator::ConcatOperator;
+
+        let dir = MockDir::new();
+        let options = DbOptions {
+            merge_operator: Some(Arc::new(ConcatOperator)),
+            ..Default::default()
+        };
+        let mut db: Db<_, String, String> = Db::with_options(dir.clone(), options).unwrap();
+        db.insert("a".into(), "1".into()).unwrap();
+        db.merge_value("b".into(), "2".into()).unwrap();
+        drop(db);
+
+        // The family records that it needs the operator, so opening without
+        // it fails up front rather than on the first read of an operand.
+        let Err(err) = Db::<_, String, String>::new(dir) else {
+            panic!("opened without the merge operator");
+        };
+        assert!(err.to_string().contains("merge operator concat"), "{}", err);
+    }
+
+    #[test]
+    fn test_column_family_options() {
+        use super::{compaction_filter::PrefixFilter, merge_operator::ConcatOperator};
+
+        let dir = MockDir::new();
+        let counters = DbOptions {
+            merge_operator: Some(Arc::new(ConcatOperator)),
+            compaction_filter: Some(Arc::new(PrefixFilter("tmp"))),
+            ..Default::default()
+        };
+        let mut db: Db<_, String, String> = Db::new(dir.clone()).unwrap();
+        let cf = db
+            .create_column_family("counters", counters.clone())
+            .unwrap();
+        db.merge_value_cf(cf, "a".into(), "1".into()).unwrap();
+        drop(db);
+
+        // The family has to be given the operator and filter it was created
+        // with, not the database's.
+        let open = |families: Vec<(String, DbOptions<String, String>)>| {
+            Db::<_, String, String>::with_column_families(
+                dir.clone(),
+                DbOptions::default(),
+                families,
+            )
+        };
+        let Err(err) = open(Vec::new()) else {
+            panic!("opened counters without its options");
+        };
+        assert!(err.to_string().contains("counters"), "{}", err);
+        let no_filter = DbOptions {
+            compaction_filter: None,
+            ..counters.clone()
+        };
+        assert!(open(vec![("counters".into(), no_filter.clone())]).is_err());
+        assert!(open(vec![("missing".into(), counters.clone())]).is_err());
+        let mut db = open(vec![("counters".into(), counters)]).unwrap();
+        assert_eq!(db.get_cf(cf, &"a".into()).unwrap(), Some("1".into()));
+
+        // Reconfiguring the family records its new options.
+        db.create_column_family("counters", no_filter.clone())
+            .unwrap();
+        drop(db);
+        open(vec![("counters".into(), no_filter)]).unwrap();
+    }
+
+    #[test]
+    fn test_missing_sst() {
+        let mut dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::new(dir.clone()).unwrap();
+        db.insert("a".into(), "1".into()).unwrap();
+        db.flush_memtable().unwrap();
+        let mut txn = db.transaction();
+        assert_eq!(txn.get(&mut db, &"a".into()).unwrap(), Some("1".into()));
+        for file in dir.clone().ls() {
+            if file.ends_with(".sst") {
+                dir.unlink(&file).unwrap();
+            }
+        }
+
+        // An SST that can't be opened is reported, not skipped over.
+        assert!(db.get(&"a".into()).is_err());
+        let mut scan = db.scan().unwrap();
+        assert_eq!(scan.next(), None);
+        assert!(scan.status().is_err());
+        // Nor can a transaction commit without checking the versions in it.
+        assert!(db.commit(txn).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_range_delete() {
+        let dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::new(dir).unwrap();
+        db.insert("a".into(), "1".into()).unwrap();
+        db.insert("b".into(), "1".into()).unwrap();
+        db.flush_memtable().unwrap();
+        let snapshot = db.snapshot();
+        db.delete_range("a".into(), "c".into()).unwrap();
+        db.flush_memtable().unwrap();
+
+        // The tombstone is newer than the snapshot, so compacting it with
+        // what it covers mustn't drop anything the snapshot can see.
+        db.merge(vec![(0, 0), (0, 1)], 1).unwrap();
+        assert_eq!(db.get(&"a".into()).unwrap(), None);
+        assert_eq!(
+            db.get_at(&"a".into(), snapshot.seqnum()).unwrap(),
+            Some("1".into())
+        );
+        assert_eq!(
+            db.scan_at(snapshot.seqnum()).unwrap().collect::<Vec<_>>(),
+            vec![("a".into(), "1".into()), ("b".into(), "1".into())]
+        );
+
+        // Once it's gone, they can be dropped.
+        drop(snapshot);
+        db.merge(vec![(1, 0)], 2).unwrap();
+        assert!(db.families[0]
+            .layout
+            .ssts
+            .iter()
+            .all(|level| level.is_empty()));
+        assert_eq!(db.scan().unwrap().collect::<Vec<_>>(), vec![]);
+    }
+
+    #[test]
+    fn test_corrupt_sst() {
+        let mut dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::new(dir.clone()).unwrap();
+        db.insert("a".into(), "1".into()).unwrap();
+        db.flush_memtable().unwrap();
+        for file in dir.clone().ls() {
+            if file.ends_with(".sst") {
+                // The first data block starts the file.
+                let mut buf = dir.open(&file).unwrap().read_all();
+                buf[8] ^= 0xff;
+                dir.unlink(&file).unwrap();
+                dir.create(&file).unwrap().unwrap().write(&buf).unwrap();
+            }
+        }
+
+        // A block that fails its checksum is reported, not panicked on.
+        let mut scan = db.scan().unwrap();
+        assert_eq!(scan.next(), None);
+        assert!(scan.status().is_err());
+        assert!(db.get(&"a".into()).is_err());
+    }
+
+    #[test]
+    fn test_write_batch_last_write_wins() {
+        let dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::new(dir.clone()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.insert(DEFAULT_COLUMN_FAMILY, "a".into(), "1".into());
+        batch.insert(DEFAULT_COLUMN_FAMILY, "a".into(), "2".into());
+        batch.insert(DEFAULT_COLUMN_FAMILY, "b".into(), "1".into());
+        batch.delete(DEFAULT_COLUMN_FAMILY, "b".into());
+        db.write(batch).unwrap();
+        assert_eq!(db.get(&"a".into()).unwrap(), Some("2".into()));
+        assert_eq!(db.get(&"b".into()).unwrap(), None);
+
+        db.flush_memtable().unwrap();
+        assert_eq!(db.get(&"a".into()).unwrap(), Some("2".into()));
+        assert_eq!(db.get(&"b".into()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_comparator() {
+        let dir = MockDir::new();
+        let options = || DbOptions {
+            comparator: Arc::new(ReverseComparator),
+            ..Default::default()
+        };
+        let mut db: Db<_, String, String> = Db::with_options(dir.clone(), options()).unwrap();
+        for k in ["a", "c", "e"] {
+            db.insert(k.into(), k.into()).unwrap();
+        }
+        db.flush_memtable().unwrap();
+        for k in ["b", "d", "f"] {
+            db.insert(k.into(), k.into()).unwrap();
+        }
+        // Under this order, "e" comes before "c".
+        assert!(db.delete_range("c".into(), "e".into()).is_err());
+        db.delete_range("e".into(), "c".into()).unwrap();
+        db.flush_memtable().unwrap();
+
+        let scan = |db: &mut Db<_, String, String>| {
+            db.scan().unwrap().map(|(k, _)| k).collect::<Vec<String>>()
+        };
+        let expected = vec!["f", "c", "b", "a"];
+        assert_eq!(scan(&mut db), expected);
+        db.merge(vec![(0, 0), (0, 1)], 1).unwrap();
+        assert_eq!(scan(&mut db), expected);
+        assert_eq!(db.get(&"d".into()).unwrap(), None);
+        assert_eq!(db.get(&"b".into()).unwrap(), Some("b".into()));
+
+        assert!(Db::<_, String, String>::new(dir.clone()).is_err());
+        assert!(db
+            .create_column_family("meta", DbOptions::default())
+            .is_err());
+        let mut db: Db<_, String, String> = Db::with_options(dir, options()).unwrap();
+        assert_eq!(scan(&mut db), expected);
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let dir = MockDir::new();
+        let options = || DbOptions {
+            timestamps: true,
+            ..Default::default()
+        };
+        let mut db: Db<_, String, String> = Db::with_options(dir.clone(), options()).unwrap();
+        let k = || "k".to_owned();
+        db.insert_with_timestamp(k(), 10, "v10".into()).unwrap();
+        db.insert_with_timestamp(k(), 20, "v20".into()).unwrap();
+        // Timestamps needn't arrive in order.
+        db.insert_with_timestamp(k(), 15, "v15".into()).unwrap();
+        db.delete_with_timestamp(k(), 30).unwrap();
+        db.insert_with_timestamp("j".into(), 16, "j".into())
+            .unwrap();
+
+        let read = |db: &mut Db<_, String, String>, ts| db.get_at_timestamp(&k(), ts).unwrap();
+        assert_eq!(read(&mut db, 5), None);
+        assert_eq!(read(&mut db, 10), Some("v10".into()));
+        assert_eq!(read(&mut db, 17), Some("v15".into()));
+        assert_eq!(read(&mut db, 25), Some("v20".into()));
+        assert_eq!(read(&mut db, 30), None);
+        assert_eq!(db.get(&k()).unwrap(), None);
+        assert_eq!(
+            db.scan_at_timestamp(17).unwrap().collect::<Vec<_>>(),
+            vec![("j".into(), "j".into()), (k(), "v15".into())]
+        );
+
+        // Once nothing reads below 18, only the newest version under it is
+        // kept.
+        db.flush_memtable().unwrap();
+        db.set_timestamp_low(18).unwrap();
+        db.merge(vec![(0, 0)], 1).unwrap();
+        assert!(db.set_timestamp_low(5).is_err());
+        assert!(db.get_at_timestamp(&k(), 17).is_err());
+        assert_eq!(read(&mut db, 18), Some("v15".into()));
+        assert_eq!(read(&mut db, 25), Some("v20".into()));
+
+        let mut db: Db<_, String, String> = Db::with_options(dir, options()).unwrap();
+        assert_eq!(db.timestamp_low(), 18);
+        let (mut iter, _) = db.internal_iter(DEFAULT_COLUMN_FAMILY).unwrap();
+        let mut versions = Vec::new();
+        while let Some(((key, ts, _), _)) = iter.next() {
+            if *key == k() {
+                versions.push(*ts);
+            }
+        }
+        assert_eq!(versions, vec![15, 20, 30]);
+    }
+
+    #[test]
+    fn test_timestamps_with_plain_writes() {
+        use super::clock::ManualClock;
+
+        let clock = Arc::new(ManualClock::default());
+        let mut db: Db<_, String, String> = Db::with_options(
+            MockDir::new(),
+            DbOptions {
+                timestamps: true,
+                clock: clock.clone(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let k = || "k".to_owned();
+        clock.advance(100);
+        db.insert_with_timestamp(k(), 50, "v50".into()).unwrap();
+        // A write without a timestamp is made at the clock's time, so it
+        // shadows the earlier timestamp rather than hiding under it.
+        db.insert(k(), "now".into()).unwrap();
+        assert_eq!(db.get(&k()).unwrap(), Some("now".into()));
+        assert_eq!(db.get_at_timestamp(&k(), 99).unwrap(), Some("v50".into()));
+        db.insert_with_timestamp(k(), 150, "v150".into()).unwrap();
+        assert_eq!(db.get(&k()).unwrap(), Some("v150".into()));
+        assert_eq!(db.get_at_timestamp(&k(), 100).unwrap(), Some("now".into()));
+
+        clock.advance(100);
+        db.delete(k()).unwrap();
+        db.flush_memtable().unwrap();
+        assert_eq!(db.get(&k()).unwrap(), None);
+        assert_eq!(db.get_at_timestamp(&k(), 199).unwrap(), Some("v150".into()));
+    }
+
+    #[test]
+    fn test_timestamps_disabled() {
+        let dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::new(dir.clone()).unwrap();
+        assert!(db
+            .insert_with_timestamp("k".into(), 10, "v".into())
+            .is_err());
+        assert!(db.delete_with_timestamp("k".into(), 10).is_err());
+        assert!(db.get_at_timestamp(&"k".to_owned(), 10).is_err());
+        assert!(db
+            .create_column_family(
+                "ts",
+                DbOptions {
+                    timestamps: true,
+                    ..Default::default()
+                }
+            )
+            .is_err());
+        drop(db);
+
+        // Whether keys have timestamps is fixed when the database is made.
+        assert!(Db::<_, String, String>::with_options(
+            dir,
+            DbOptions {
+                timestamps: true,
+                ..Default::default()
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_sst_without_timestamps() {
+        let sst_size = |timestamps| {
+            let mut db: Db<_, String, String> = Db::with_options(
+                MockDir::new(),
+                DbOptions {
+                    timestamps,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            for i in 0..100 {
+                db.insert(format!("k{:03}", i), "v".into()).unwrap();
+            }
+            db.flush_memtable().unwrap();
+            let sst = db.root.data.families[0].l0[0].clone();
+            assert_eq!(db.get(&"k042".to_owned()).unwrap(), Some("v".into()));
+            db.dir.open(&sst.filename).unwrap().len()
+        };
+        // Leaving out the timestamps saves at least a byte per key.
+        assert!(sst_size(false) + 100 <= sst_size(true));
+    }
+
+    #[test]
+    fn test_ttl_overflow() {
+        use super::clock::ManualClock;
+
+        let clock = Arc::new(ManualClock::default());
+        clock.advance(1000);
+        let mut db: Db<_, String, String> = Db::with_options(
+            MockDir::new(),
+            DbOptions {
+                clock: clock.clone(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        db.insert_with_ttl("k".into(), "v".into(), Duration::MAX)
+            .unwrap();
+        clock.advance(u64::MAX / 2);
+        assert_eq!(db.get(&"k".to_owned()).unwrap(), Some("v".into()));
+    }
+
+    #[test]
+    fn test_history() {
+        use super::{clock::ManualClock, merge_operator::ConcatOperator};
+
+        let k = || "k".to_owned();
+        let put = |seqnum, v: &str| KeyVersion {
+            seqnum,
+            timestamp: 0,
+            value: DbValue::Put(v.to_owned()),
+        };
+        let seqnums =
+            |history: Vec<KeyVersion<String>>| history.iter().map(|v| v.seqnum).collect::<Vec<_>>();
+
+        let mut db: Db<_, String, String> = Db::with_options(
+            MockDir::new(),
+            DbOptions {
+                merge_operator: Some(Arc::new(ConcatOperator)),
+                history_retention: HistoryRetention::Seqnums(4),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        db.insert(k(), "v1".into()).unwrap();
+        db.merge_value(k(), "x".into()).unwrap();
+        db.merge_value(k(), "y".into()).unwrap();
+        db.delete_range("j".into(), "l".into()).unwrap();
+        db.insert(k(), "v6".into()).unwrap();
+        db.insert("other".into(), "o".into()).unwrap();
+        assert_eq!(seqnums(db.history(&k()).unwrap()), vec![6, 5, 4, 3, 2]);
+        assert_eq!(db.history(&k()).unwrap()[0], put(6, "v6"));
+        assert_eq!(
+            db.history(&k()).unwrap()[1],
+            KeyVersion {
+                seqnum: 5,
+                timestamp: 0,
+                value: DbValue::Delete
+            }
+        );
+
+        // Only the last four seqnums are kept intact by compactions.
+        db.flush_memtable().unwrap();
+        db.merge(vec![(0, 0)], 1).unwrap();
+        assert_eq!(seqnums(db.history(&k()).unwrap()), vec![6, 5, 4]);
+        assert_eq!(db.get(&k()).unwrap(), Some("v6".into()));
+
+        let clock = Arc::new(ManualClock::default());
+        clock.advance(1000);
+        let mut db: Db<_, String, String> = Db::with_options(
+            MockDir::new(),
+            DbOptions {
+                merge_operator: Some(Arc::new(ConcatOperator)),
+                history_retention: HistoryRetention::Duration(Duration::from_millis(100)),
+                clock: clock.clone(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        db.insert(k(), "a".into()).unwrap();
+        db.merge_value(k(), "b".into()).unwrap();
+        db.flush_memtable().unwrap();
+        db.merge(vec![(0, 0)], 1).unwrap();
+        assert_eq!(seqnums(db.history(&k()).unwrap()), vec![3, 2]);
+        assert!(db.history(&k()).unwrap()[0].value.is_merge());
+
+        clock.advance(200);
+        db.merge(vec![(1, 0)], 1).unwrap();
+        assert_eq!(db.history(&k()).unwrap(), vec![put(3, "a,b"), put(2, "a")]);
+    }
+
+    #[test]
+    fn test_sst_metadata() {
+        use super::clock::ManualClock;
+        use crate::fs::Event;
+
+        let dir = MockDir::new();
+        let clock = Arc::new(ManualClock::default());
+        let options = || DbOptions {
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let mut db: Db<_, String, String> = Db::with_options(dir.clone(), options()).unwrap();
+        db.insert("a".into(), "1".into()).unwrap();
+        db.insert("c".into(), "3".into()).unwrap();
+        db.delete_range("b".into(), "d".into()).unwrap();
+        clock.advance(7);
+        db.flush_memtable().unwrap();
+
+        let sst = &db.root.data.families[0].l0[0];
+        assert_eq!(sst.num_entries, 2);
+        assert_eq!((sst.min_seqnum, sst.max_seqnum), (2, 4));
+        assert_eq!(sst.created_at, 7);
+        assert_eq!(sst.min_key.decode::<(String, u64, usize)>().unwrap().0, "a");
+        assert_eq!(sst.max_key.decode::<(String, u64, usize)>().unwrap().0, "d");
+
+        // Opening the database doesn't touch the SSTs.
+        (*dir.fs).borrow_mut().take_events();
+        let mut db: Db<_, String, String> = Db::with_options(dir.clone(), options()).unwrap();
+        assert!(!(*dir.fs)
+            .borrow_mut()
+            .take_events()
+            .iter()
+            .any(|e| matches!(e, Event::Open(f) if f.ends_with(".sst"))));
+        assert_eq!(db.families[0].layout.l0[0].range_tombstones.len(), 1);
+        assert_eq!(db.get(&"a".into()).unwrap(), Some("1".into()));
+        assert_eq!(db.get(&"c".into()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_scan_opens_ssts_lazily() {
+        use crate::fs::Event;
+
+        let dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::new(dir.clone()).unwrap();
+        for k in ["a", "m", "z"] {
+            db.insert(k.into(), "1".into()).unwrap();
+            db.flush_memtable().unwrap();
+            db.merge(vec![(0, 0)], 1).unwrap();
+        }
+
+        let opened = || {
+            (*dir.fs)
+                .borrow_mut()
+                .take_events()
+                .iter()
+                .filter(|e| matches!(e, Event::Open(f) if f.ends_with(".sst")))
+                .count()
+        };
+        opened();
+        let mut scan = db.scan().unwrap();
+        // Reading the first key only looks ahead as far as the second SST.
+        assert_eq!(scan.next(), Some(("a".into(), "1".into())));
+        assert_eq!(opened(), 2);
+        assert_eq!(scan.count(), 2);
+        assert_eq!(opened(), 1);
+    }
+
+    #[test]
+    fn test_paranoid_checks() {
+        let options = DbOptions {
+            paranoid_checks: true,
+            ..Default::default()
+        };
+        let dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::with_options(dir.clone(), options).unwrap();
+        for k in ["a", "m", "z"] {
+            db.insert(k.into(), "1".into()).unwrap();
+            db.flush_memtable().unwrap();
+            db.merge(vec![(0, 0)], 1).unwrap();
+        }
+        assert_eq!(db.families[0].layout.ssts[0].len(), 3);
+
+        // A level that's out of order fails the next merge into it, before
+        // the manifest records anything.
+        db.families[0].layout.ssts[0].swap(0, 2);
+        db.insert("b".into(), "2".into()).unwrap();
+        db.flush_memtable().unwrap();
+        let manifest = db.root.data.families[0].clone();
+        let in_memory = format!("{:?}", db.families[0].layout.ssts);
+        let files = dir.clone().ls();
+        let err = db.merge(vec![(0, 0)], 1).unwrap_err();
+        assert!(err.to_string().contains("overlap"), "{}", err);
+        assert_eq!(
+            format!("{:?}", db.root.data.families[0]),
+            format!("{:?}", manifest)
+        );
+        // Nothing else changed either, and the SST the merge wrote is gone.
+        assert_eq!(format!("{:?}", db.families[0].layout.ssts), in_memory);
+        assert_eq!(dir.clone().ls(), files);
+
+        // Adding an SST where it doesn't fit is refused by the manifest too.
+        let mut sst = manifest.ssts[0][1].clone();
+        sst.filename = "misplaced.sst".into();
+        let edit = VersionEdit::AddSst {
+            cf: DEFAULT_COLUMN_FAMILY,
+            level: 1,
+            index: 0,
+            sst,
+        };
+        let err = db.root.edit(vec![edit]).unwrap_err();
+        assert!(err.to_string().contains("overlap"), "{}", err);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        use crate::fs::Event;
+
+        let dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::new(dir.clone()).unwrap();
+        db.insert("a".into(), "1".into()).unwrap();
+        db.flush_memtable().unwrap();
+        db.insert("b".into(), "2".into()).unwrap();
+
+        (*dir.fs).borrow_mut().take_events();
+        db.checkpoint(dir.clone().cd(&"checkpoint")).unwrap();
+        let linked = (*dir.fs)
+            .borrow_mut()
+            .take_events()
+            .iter()
+            .filter(|e| matches!(e, Event::Link(..)))
+            .count();
+        assert_eq!(linked, 1);
+        // A directory elsewhere gets copies instead.
+        let elsewhere = MockDir::new();
+        db.checkpoint(elsewhere.clone()).unwrap();
+        assert!(db.checkpoint(elsewhere.clone()).is_err());
+
+        // Writes after the checkpoint don't show up in it.
+        db.insert("c".into(), "3".into()).unwrap();
+        db.flush_memtable().unwrap();
+        db.merge(vec![(0, 0), (0, 1)], 1).unwrap();
+        for checkpoint in [dir.clone().cd(&"checkpoint"), elsewhere] {
+            let mut copy: Db<_, String, String> = Db::new(checkpoint).unwrap();
+            assert_eq!(
+                copy.scan().unwrap().collect::<Vec<_>>(),
+                vec![("a".into(), "1".into()), ("b".into(), "2".into())]
+            );
+        }
+        assert_eq!(db.scan().unwrap().count(), 3);
+    }
 }
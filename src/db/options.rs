@@ -0,0 +1,149 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    comparator::{Comparator, OrdComparator},
+    memtable::MemtableRepKind,
+};
+
+use super::{
+    clock::{Clock, SystemClock},
+    compaction_filter::CompactionFilter,
+    merge_operator::MergeOperator,
+    write_buffer_manager::WriteBufferManager,
+};
+
+// Tunables for a `Db`. Everything here has a default that is suitable for
+// tests, so callers generally construct this with `..Default::default()`.
+#[derive(Debug, Clone)]
+pub struct DbOptions<K, V> {
+    // Once L0 has this many SSTs, each write is delayed by `slowdown_delay`.
+    pub l0_slowdown_trigger: usize,
+    // Once L0 has this many SSTs, writes are rejected until it is compacted.
+    pub l0_stop_trigger: usize,
+    // The same pair of thresholds, for memtables that have been frozen but not
+    // yet flushed.
+    pub immutable_memtable_slowdown_trigger: usize,
+    pub immutable_memtable_stop_trigger: usize,
+    // The same pair of thresholds, for the number of bytes compactions have to
+    // move down a level to bring every level within its target size.
+    pub pending_compaction_bytes_slowdown_trigger: usize,
+    pub pending_compaction_bytes_stop_trigger: usize,
+    // The target size of L1. Each level below it is `level_size_multiplier`
+    // times the size of the one above.
+    pub level1_target_bytes: usize,
+    pub level_size_multiplier: usize,
+    pub slowdown_delay: Duration,
+    // How long a stopped write spends working off the backlog itself, by
+    // flushing frozen memtables and compacting L0, before it's rejected.
+    pub write_stop_timeout: Duration,
+    // Once the active memtable is estimated to take up this many bytes, it is
+    // flushed to L0.
+    pub write_buffer_size: usize,
+    // If set, a budget shared with other databases. Whichever database is
+    // writing when the combined memtables exceed it flushes its own.
+    pub write_buffer_manager: Option<Arc<WriteBufferManager>>,
+    // Which data structure backs the memtables.
+    pub memtable_rep: MemtableRepKind,
+    // Folds the operands written by `Db::merge_value`. This must be set to
+    // open a database which contains any.
+    pub merge_operator: Option<Arc<dyn MergeOperator<K, V>>>,
+    // If set, sees every value as it's flushed or compacted, and can drop or
+    // rewrite it.
+    pub compaction_filter: Option<Arc<dyn CompactionFilter<K, V>>>,
+    // How long a pessimistic transaction waits for a lock before giving up.
+    pub lock_timeout: Duration,
+    // Decides when values written by `Db::insert_with_ttl` expire.
+    pub clock: Arc<dyn Clock>,
+    // The order keys are kept in. Its name is recorded when the database is
+    // created, and it can't be opened with a different one afterwards. Every
+    // column family shares it.
+    pub comparator: Arc<dyn Comparator<K>>,
+    // Whether keys carry timestamps, for writing and reading at a timestamp.
+    // Without them, SSTs don't spend any space on them. Writes which aren't
+    // given a timestamp are made at `clock`'s current time. This is recorded
+    // when the database is created, and every column family shares it.
+    pub timestamps: bool,
+    // How much history compactions must leave untouched for `Db::history`.
+    // Every column family shares it.
+    pub history_retention: HistoryRetention,
+    // Once the manifest grows past this many bytes, it's replaced with one
+    // that holds just the current state.
+    pub max_manifest_size: usize,
+    // Whether WALs are kept around once everything in them has been
+    // flushed, for point-in-time recovery.
+    pub wal_archive: WalArchive,
+    // Check invariants that should always hold as SSTs are written and
+    // installed, failing the operation rather than writing out bad data.
+    // Every new SST is read back in full, so this is slow.
+    pub paranoid_checks: bool,
+}
+
+// Compactions fold merge operands, expire puts, run the compaction filter and
+// drop range-deleted or timestamp-trimmed versions, all of which rewrite a
+// key's history. Versions within the retention window are exempt.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HistoryRetention {
+    #[default]
+    Disabled,
+    // Versions within this many seqnums of the newest write.
+    Seqnums(usize),
+    // Versions written within this long of now, according to `clock`.
+    // Write times are only sampled when memtables are flushed, so versions
+    // can be kept for somewhat longer than this.
+    Duration(Duration),
+}
+
+// Retired WALs are moved into the `archive` directory, and the oldest are
+// deleted once the archive is over its limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalArchive {
+    #[default]
+    Disabled,
+    // Keep as many of the newest WALs as fit in this many bytes.
+    MaxBytes(usize),
+    // Keep WALs for this long after they're archived, according to `clock`.
+    MaxAge(Duration),
+}
+
+impl<K, V> DbOptions<K, V> {
+    pub(crate) fn merge_operator_name(&self) -> Option<&str> {
+        self.merge_operator.as_ref().map(|op| op.name())
+    }
+
+    pub(crate) fn compaction_filter_name(&self) -> Option<&str> {
+        self.compaction_filter.as_ref().map(|filter| filter.name())
+    }
+}
+
+impl<K, V> Default for DbOptions<K, V>
+where
+    K: Ord + Clone,
+{
+    fn default() -> Self {
+        DbOptions {
+            l0_slowdown_trigger: 20,
+            l0_stop_trigger: 36,
+            immutable_memtable_slowdown_trigger: 4,
+            immutable_memtable_stop_trigger: 8,
+            pending_compaction_bytes_slowdown_trigger: 64 << 20,
+            pending_compaction_bytes_stop_trigger: 256 << 20,
+            level1_target_bytes: 256 << 20,
+            level_size_multiplier: 10,
+            slowdown_delay: Duration::from_millis(1),
+            write_stop_timeout: Duration::from_secs(1),
+            write_buffer_size: 4 << 20,
+            write_buffer_manager: None,
+            memtable_rep: MemtableRepKind::default(),
+            merge_operator: None,
+            compaction_filter: None,
+            lock_timeout: Duration::from_secs(1),
+            clock: Arc::new(SystemClock),
+            comparator: Arc::new(OrdComparator),
+            timestamps: false,
+            history_retention: HistoryRetention::Disabled,
+            max_manifest_size: 1 << 20,
+            wal_archive: WalArchive::Disabled,
+            paranoid_checks: false,
+        }
+    }
+}
@@ -0,0 +1,310 @@
+// Rebuilds a database's manifest from the files it refers to, for when the
+// manifest itself has been lost or damaged.
+//
+// Every SST that can still be read goes into L0 of the column family it
+// records, and the WALs are replayed and flushed just as they would be on
+// open. Families which only the WALs know about get a placeholder name. WALs
+// in the archive are kept there, as if they'd just been archived. The old
+// manifest, along with any SST or WAL that can't be read, is moved into
+// `lost/`.
+//
+// Some things are only in the manifest, and can't be recovered: the
+// timestamp low watermark goes back to 0, so it has to be set again before
+// reading at old timestamps, and the flush times are gone, so time-based
+// history retention keeps everything until new ones have been recorded.
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+
+use crate::{
+    encoding::{Bytes, Decode, Encode},
+    fs::{DbDir, DbFile},
+    log::file_log::LogReader,
+    memtable::{DbValue, KVIter},
+    root::{self, Root},
+    sst::reader::SstReader,
+};
+
+use super::{
+    internal_key_format, options::DbOptions, wal_lower_bound, ArchivedWal, ColumnFamilyId,
+    DBCommand, Db, DiskLayout, SstMetadata, VersionEdit, DEFAULT_COLUMN_FAMILY, WAL_ARCHIVE,
+};
+
+const LOST: &str = "lost";
+
+impl<D, K, V> Db<D, K, V>
+where
+    D: DbDir + std::fmt::Debug + 'static,
+    K: Ord + Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
+    V: Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
+{
+    // `options` has to be what the database was created with, since its
+    // comparator and whether it has timestamps were only in the manifest.
+    fn repair(mut dir: D, options: DbOptions<K, V>) -> anyhow::Result<()> {
+        let names = dir.ls();
+        for name in names.iter().filter(|name| root::is_manifest_file(name)) {
+            move_to_lost(&mut dir, name)?;
+        }
+
+        let mut next_sst_id = 0;
+        let mut family_names = BTreeMap::new();
+        let mut ssts = Vec::new();
+        for name in &names {
+            let Some(id) = sst_id(name) else {
+                continue;
+            };
+            // Don't reuse the name of an SST that's lost, either.
+            next_sst_id = std::cmp::max(next_sst_id, id + 1);
+            match Self::read_sst_metadata(&mut dir, name, &options) {
+                Ok((Some((cf, family)), sst)) => {
+                    family_names.entry(cf).or_insert(family);
+                    ssts.push((cf, sst));
+                }
+                // Without a family, there's nowhere to put it.
+                Ok((None, _)) | Err(_) => move_to_lost(&mut dir, name)?,
+            }
+        }
+        ssts.sort_by_key(|(_, sst)| sst.max_seqnum);
+
+        let mut wals: Vec<_> = names.iter().filter(|name| is_wal(name)).cloned().collect();
+        wals.sort_by_key(|wal| wal_lower_bound(wal));
+        // The WALs only know families by id, so recreate as many as they use.
+        let mut num_families = family_names.keys().max().map_or(1, |cf| cf + 1);
+        let mut readable_wals = Vec::new();
+        for wal in wals {
+            match Self::max_family_in_wal(&mut dir, &wal) {
+                Ok(cf) => {
+                    num_families = std::cmp::max(num_families, cf + 1);
+                    readable_wals.push(wal);
+                }
+                Err(_) => move_to_lost(&mut dir, &wal)?,
+            }
+        }
+
+        let now = options.clock.now();
+        let mut archive = dir.cd(&WAL_ARCHIVE);
+        let mut archived_wals: Vec<_> = archive
+            .ls()
+            .into_iter()
+            .filter(|name| is_wal(name))
+            .collect();
+        archived_wals.sort_by_key(|wal| wal_lower_bound(wal));
+
+        let mut edits = vec![
+            VersionEdit::SetComparator(options.comparator.name().to_owned()),
+            VersionEdit::NextSstId(next_sst_id),
+        ];
+        if options.timestamps {
+            edits.push(VersionEdit::EnableTimestamps);
+        }
+        edits.extend((1..num_families).map(|cf| {
+            VersionEdit::AddFamily(
+                family_names
+                    .remove(&cf)
+                    .unwrap_or_else(|| format!("recovered-{}", cf)),
+            )
+        }));
+        // Every family is recreated with `options`.
+        edits.extend((0..num_families).map(|cf| VersionEdit::set_family_options(cf, &options)));
+        edits.extend((0..num_families).map(|cf| {
+            VersionEdit::MaxSstSeqnum {
+                cf,
+                seqnum: ssts
+                    .iter()
+                    .filter(|(sst_cf, _)| *sst_cf == cf)
+                    .map(|(_, sst)| sst.max_seqnum)
+                    .max()
+                    .unwrap_or(0),
+            }
+        }));
+        edits.extend(ssts.into_iter().map(|(cf, sst)| VersionEdit::AddSst {
+            cf,
+            level: 0,
+            index: 0,
+            sst,
+        }));
+        edits.extend(readable_wals.into_iter().map(VersionEdit::AddWal));
+        for name in archived_wals {
+            let num_bytes = archive
+                .open(&name)
+                .ok_or_else(|| anyhow!("{} does not exist", name))?
+                .len();
+            edits.push(VersionEdit::ArchiveWal(ArchivedWal {
+                name,
+                num_bytes,
+                archived_at: now,
+            }));
+        }
+        Root::<DiskLayout, D>::load(dir.clone())?.edit(edits)?;
+
+        // Opening replays the WALs into memtables, which can then be flushed.
+        let mut db = Self::with_options(dir, options)?;
+        for cf in 0..db.families.len() {
+            db.flush_memtable_cf(cf)?;
+        }
+        Ok(())
+    }
+
+    // The highest column family written to in `wal`. Fails if any of it can't
+    // be decoded.
+    fn max_family_in_wal(dir: &mut D, wal: &str) -> anyhow::Result<ColumnFamilyId> {
+        let file = dir
+            .open(&wal)
+            .ok_or_else(|| anyhow!("{} does not exist", wal))?;
+        let mut reader = LogReader::<_, DBCommand<K, V>>::new(file)?;
+        let mut max = DEFAULT_COLUMN_FAMILY;
+        while let Some(command) = reader.try_next()? {
+            max = std::cmp::max(max, max_family(&command));
+        }
+        Ok(max)
+    }
+
+    // Reads all of the SST `name`, to make sure it's intact, and works out
+    // what the manifest needs to know about it, along with the column family
+    // it records.
+    pub(super) fn read_sst_metadata(
+        dir: &mut D,
+        name: &str,
+        options: &DbOptions<K, V>,
+    ) -> anyhow::Result<(Option<(ColumnFamilyId, String)>, SstMetadata)> {
+        let file = dir
+            .open(&name)
+            .ok_or_else(|| anyhow!("{} does not exist", name))?;
+        let mut reader = SstReader::<(K, u64, usize), DbValue<V>, D>::load_with_key_format(
+            file,
+            internal_key_format(options.timestamps),
+        )?;
+        let mut num_entries = 0;
+        let mut seqnums: Vec<_> = reader
+            .sst_meta
+            .range_tombstones
+            .iter()
+            .map(|(start, _)| start.2)
+            .collect();
+        while let Some(((_, _, seqnum), _)) = reader.next() {
+            num_entries += 1;
+            seqnums.push(*seqnum);
+        }
+        let meta = &reader.sst_meta;
+        let sst = SstMetadata {
+            filename: name.to_owned(),
+            min_key: Bytes::encode(&meta.min_key),
+            max_key: Bytes::encode(&meta.max_key),
+            range_tombstones: Bytes::encode(&meta.range_tombstones),
+            num_bytes: meta.num_bytes,
+            num_entries,
+            min_seqnum: seqnums.iter().min().copied().unwrap_or(0),
+            max_seqnum: seqnums.iter().max().copied().unwrap_or(0),
+            created_at: options.clock.now(),
+        };
+        Ok((meta.column_family.clone(), sst))
+    }
+}
+
+fn is_wal(name: &str) -> bool {
+    name.strip_prefix("wal")
+        .is_some_and(|n| n.parse::<usize>().is_ok())
+}
+
+fn sst_id(name: &str) -> Option<usize> {
+    name.strip_prefix("sst")?.strip_suffix(".sst")?.parse().ok()
+}
+
+// The highest column family `command` writes to.
+fn max_family<K, V>(command: &DBCommand<K, V>) -> usize
+where
+    K: std::fmt::Debug + Clone + Encode + Decode,
+    V: std::fmt::Debug + Clone + Encode + Decode,
+{
+    match command {
+        DBCommand::Batch(_, commands) => commands.iter().map(max_family).max().unwrap_or(0),
+        DBCommand::Family(cf, command) => std::cmp::max(*cf, max_family(command)),
+        _ => DEFAULT_COLUMN_FAMILY,
+    }
+}
+
+fn move_to_lost<D: DbDir>(dir: &mut D, name: &str) -> anyhow::Result<()> {
+    dir.rename(&name, &format!("{}/{}", LOST, name))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Seek, SeekFrom};
+
+    use crate::fs::{DbDir, DbFile, MockDir};
+
+    use super::super::{
+        options::{DbOptions, WalArchive},
+        Db,
+    };
+
+    #[test]
+    fn test_repair() {
+        let mut dir = MockDir::new();
+        let options = || DbOptions {
+            wal_archive: WalArchive::MaxBytes(usize::MAX),
+            ..Default::default()
+        };
+        let mut db: Db<_, String, String> = Db::with_options(dir.clone(), options()).unwrap();
+        let other = db.create_column_family("other", options()).unwrap();
+        db.insert("a".into(), "1".into()).unwrap();
+        db.flush_memtable().unwrap();
+        db.insert_cf(other, "c".into(), "3".into()).unwrap();
+        db.flush_memtable_cf(other).unwrap();
+        db.insert("b".into(), "2".into()).unwrap();
+        db.flush_memtable().unwrap();
+        db.delete("a".into()).unwrap();
+        db.insert_cf(other, "d".into(), "4".into()).unwrap();
+        drop(db);
+        let mut archive = dir.cd(&"archive");
+        let mut archived = archive.ls();
+        archived.sort();
+        assert!(!archived.is_empty());
+
+        // Damage the manifest, and leave an SST and a WAL that can't be read.
+        let manifest = String::from_utf8(dir.open(&"CURRENT").unwrap().read_all()).unwrap();
+        let mut f = dir.open(&manifest).unwrap();
+        f.seek(SeekFrom::Start(10)).unwrap();
+        f.write(b"X").unwrap();
+        assert!(Db::<_, String, String>::new(dir.clone()).is_err());
+        dir.create(&"sst9.sst")
+            .unwrap()
+            .unwrap()
+            .write(b"junk")
+            .unwrap();
+        let mut wal = dir.create(&"wal1000").unwrap().unwrap();
+        wal.write(&1_u32.to_le_bytes()).unwrap();
+        wal.write(&[9]).unwrap();
+
+        Db::<_, String, String>::repair(dir.clone(), options()).unwrap();
+        assert!(dir.open(&"lost/sst9.sst").is_some());
+        assert!(dir.open(&"lost/wal1000").is_some());
+        assert!(dir.open(&format!("lost/{}", manifest)).is_some());
+
+        let mut db: Db<_, String, String> = Db::with_options(dir.clone(), options()).unwrap();
+        assert_eq!(
+            db.scan().unwrap().collect::<Vec<_>>(),
+            vec![("b".into(), "2".into())]
+        );
+        // The family's name came from its SST.
+        let other = db.column_family("other").unwrap();
+        assert_eq!(
+            db.scan_cf(other).unwrap().collect::<Vec<_>>(),
+            vec![("c".into(), "3".into()), ("d".into(), "4".into())]
+        );
+        // What was in the WALs went to sst10 and sst11, rather than taking
+        // the lost SST's name.
+        assert_eq!(db.root.data.next_sst_id, 12);
+        let mut recovered: Vec<_> = db
+            .root
+            .data
+            .archived_wals
+            .iter()
+            .map(|wal| wal.name.clone())
+            .collect();
+        recovered.sort();
+        for wal in archived {
+            assert!(recovered.contains(&wal), "{} wasn't kept", wal);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+// Snapshots pin a seqnum for reading. While one is held, compactions keep
+// anything a read at that seqnum could see, which range deletions written
+// after it would otherwise let them drop.
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+// The seqnums of every live snapshot, with how many are held at each.
+#[derive(Debug, Default)]
+pub(crate) struct SnapshotList {
+    live: Mutex<BTreeMap<usize, usize>>,
+}
+
+impl SnapshotList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn acquire(self: &Arc<Self>, seqnum: usize) -> Snapshot {
+        *self.live.lock().unwrap().entry(seqnum).or_default() += 1;
+        Snapshot {
+            seqnum,
+            list: self.clone(),
+        }
+    }
+
+    // The seqnum of the oldest live snapshot, if there are any.
+    pub fn oldest(&self) -> Option<usize> {
+        self.live.lock().unwrap().keys().next().copied()
+    }
+
+    fn release(&self, seqnum: usize) {
+        let mut live = self.live.lock().unwrap();
+        let count = live.get_mut(&seqnum).expect("released an unknown snapshot");
+        *count -= 1;
+        if *count == 0 {
+            live.remove(&seqnum);
+        }
+    }
+}
+
+// Released when dropped.
+#[derive(Debug)]
+pub struct Snapshot {
+    seqnum: usize,
+    list: Arc<SnapshotList>,
+}
+
+impl Snapshot {
+    pub fn seqnum(&self) -> usize {
+        self.seqnum
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.list.release(self.seqnum);
+    }
+}
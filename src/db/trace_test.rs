@@ -1,13 +1,23 @@
 use crate::fs::MockDir;
-use std::fmt::Write;
+use std::{fmt::Write, sync::Arc, time::Duration};
 
-use super::Db;
+use super::{clock::ManualClock, merge_operator::ConcatOperator, options::DbOptions, Db};
+
+fn trace_options(clock: &Arc<ManualClock>) -> DbOptions<String, String> {
+    DbOptions {
+        merge_operator: Some(Arc::new(ConcatOperator)),
+        clock: clock.clone(),
+        ..Default::default()
+    }
+}
 
 #[test]
 fn test_db_trace() {
     datadriven::walk("src/db/testdata/", |f| {
         let dir = MockDir::new();
-        let mut db: Db<_, String, String> = Db::new(dir.clone()).unwrap();
+        let clock = Arc::new(ManualClock::default());
+        let mut db: Db<_, String, String> =
+            Db::with_options(dir.clone(), trace_options(&clock)).unwrap();
         f.run(|test_case| match test_case.directive.as_str() {
             "insert" => {
                 for line in test_case.input.lines() {
@@ -18,6 +28,28 @@ fn test_db_trace() {
                 }
                 "ok\n".into()
             }
+            "insert-with-ttl" => {
+                let ttl = Duration::from_millis(test_case.args["ttl"][0].parse().unwrap());
+                for line in test_case.input.lines() {
+                    let (key, val) = line.split_once('=').unwrap();
+                    db.insert_with_ttl(key.to_owned(), val.to_owned(), ttl)
+                        .unwrap();
+                }
+                "ok\n".into()
+            }
+            "advance-time" => {
+                clock.advance(test_case.input.trim().parse().unwrap());
+                "ok\n".into()
+            }
+            "merge-value" => {
+                for line in test_case.input.lines() {
+                    let eq_idx = line.find("+=").unwrap();
+                    let key = line[0..eq_idx].to_owned();
+                    let val = line[eq_idx + 2..].to_owned();
+                    db.merge_value(key, val).unwrap();
+                }
+                "ok\n".into()
+            }
             "delete" => {
                 for line in test_case.input.lines() {
                     let key = line.to_owned();
@@ -25,6 +57,13 @@ fn test_db_trace() {
                 }
                 "ok\n".into()
             }
+            "delete-range" => {
+                for line in test_case.input.lines() {
+                    let (start, end) = line.split_once(',').unwrap();
+                    db.delete_range(start.to_owned(), end.to_owned()).unwrap();
+                }
+                "ok\n".into()
+            }
             "get" => {
                 let key = test_case.input.trim();
                 let iter = db.get(&key.to_owned()).unwrap();
@@ -41,6 +80,10 @@ fn test_db_trace() {
                 db.flush_memtable().unwrap();
                 "ok\n".into()
             }
+            "freeze-memtable" => {
+                db.freeze_memtable().unwrap();
+                "ok\n".into()
+            }
             "merge" => {
                 let targets = test_case
                     .input
@@ -73,14 +116,14 @@ fn test_db_trace() {
                 for line in test_case.input.lines() {
                     match line.trim() {
                         "root" => writeln!(&mut out, "{:#?}", db.root.data).unwrap(),
-                        "layout" => writeln!(&mut out, "{:#?}", db.layout).unwrap(),
+                        "layout" => writeln!(&mut out, "{:#?}", db.families[0].layout).unwrap(),
                         _ => writeln!(&mut out, "can't dump {:?}", line.trim()).unwrap(),
                     }
                 }
                 out
             }
             "reload" => {
-                db = Db::new(dir.clone()).unwrap();
+                db = Db::with_options(dir.clone(), trace_options(&clock)).unwrap();
                 "ok\n".into()
             }
             _ => {
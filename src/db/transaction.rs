@@ -0,0 +1,219 @@
+// Optimistic transactions. A transaction reads from a snapshot of the database
+// and buffers its writes locally. Nothing is checked until commit, at which
+// point the transaction is rejected if anything it read has been written since
+// its snapshot. Otherwise its writes go in as a single batch, at a single
+// seqnum.
+//
+// Pessimistic transactions instead lock every key they touch as they go, via
+// the database's `LockManager`, and hold those locks until they commit or are
+// dropped. A key is read at whatever seqnum is visible once its lock is held,
+// so (among transactions) nobody can write it underneath us, and commit can't
+// conflict.
+use std::{collections::BTreeMap, hash::Hash, sync::Arc, time::Duration};
+
+use crate::{
+    encoding::{Decode, Encode},
+    fs::DbDir,
+};
+
+use super::{
+    lock_manager::{LockManager, LockMode, TxnId},
+    snapshot::Snapshot,
+    DBCommand, Db,
+};
+
+// The writes buffered by a transaction, as commands at `seqnum`. A `None`
+// value is a delete.
+fn commands<K, V>(writes: BTreeMap<K, Option<V>>, seqnum: usize) -> Vec<DBCommand<K, V>>
+where
+    K: std::fmt::Debug + Encode,
+    V: std::fmt::Debug + Encode,
+{
+    writes
+        .into_iter()
+        .map(|(k, v)| match v {
+            Some(v) => DBCommand::Write(seqnum, k, v),
+            None => DBCommand::Delete(seqnum, k),
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct Transaction<K, V>
+where
+    K: Ord,
+{
+    // What reads are served from.
+    snapshot: Snapshot,
+    // Every key read from the database, along with the seqnum it was read at.
+    reads: BTreeMap<K, usize>,
+    // A `None` value is a delete.
+    writes: BTreeMap<K, Option<V>>,
+}
+
+impl<K, V> Transaction<K, V>
+where
+    K: Ord + Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
+    V: Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
+{
+    pub(super) fn new(snapshot: Snapshot) -> Self {
+        Transaction {
+            snapshot,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn snapshot(&self) -> usize {
+        self.snapshot.seqnum()
+    }
+
+    // Reads `k`, seeing this transaction's own writes.
+    pub fn get<D>(&mut self, db: &mut Db<D, K, V>, k: &K) -> anyhow::Result<Option<V>>
+    where
+        D: DbDir + std::fmt::Debug + 'static,
+    {
+        if let Some(v) = self.writes.get(k) {
+            return Ok(v.clone());
+        }
+        self.reads.insert(k.clone(), self.snapshot.seqnum());
+        db.get_at(k, self.snapshot.seqnum())
+    }
+
+    pub fn insert(&mut self, k: K, v: V) {
+        self.writes.insert(k, Some(v));
+    }
+
+    pub fn delete(&mut self, k: K) {
+        self.writes.insert(k, None);
+    }
+
+    pub(super) fn reads(&self) -> impl Iterator<Item = (&K, usize)> {
+        self.reads.iter().map(|(k, seqnum)| (k, *seqnum))
+    }
+
+    pub(super) fn is_read_only(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    // The transaction's writes as commands at `seqnum`.
+    pub(super) fn into_commands(self, seqnum: usize) -> Vec<DBCommand<K, V>> {
+        commands(self.writes, seqnum)
+    }
+}
+
+#[derive(Debug)]
+pub struct PessimisticTransaction<K, V>
+where
+    K: Ord + Hash + Clone,
+{
+    id: TxnId,
+    lock_manager: Arc<LockManager<K>>,
+    lock_timeout: Duration,
+    // A `None` value is a delete.
+    writes: BTreeMap<K, Option<V>>,
+}
+
+impl<K, V> PessimisticTransaction<K, V>
+where
+    K: Ord + Hash + Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
+    V: Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
+{
+    pub(super) fn new(lock_manager: Arc<LockManager<K>>, lock_timeout: Duration) -> Self {
+        PessimisticTransaction {
+            id: lock_manager.begin(),
+            lock_manager,
+            lock_timeout,
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn id(&self) -> TxnId {
+        self.id
+    }
+
+    fn lock(&self, k: &K, mode: LockMode) -> anyhow::Result<()> {
+        self.lock_manager
+            .lock(self.id, k, mode, self.lock_timeout)
+            .map_err(Into::into)
+    }
+
+    // Reads `k` under a shared lock, seeing this transaction's own writes.
+    pub fn get<D>(&mut self, db: &mut Db<D, K, V>, k: &K) -> anyhow::Result<Option<V>>
+    where
+        D: DbDir + std::fmt::Debug + 'static,
+    {
+        self.read(db, k, LockMode::Shared)
+    }
+
+    // Reads `k` under an exclusive lock, for a transaction which intends to
+    // write it back.
+    pub fn get_for_update<D>(&mut self, db: &mut Db<D, K, V>, k: &K) -> anyhow::Result<Option<V>>
+    where
+        D: DbDir + std::fmt::Debug + 'static,
+    {
+        self.read(db, k, LockMode::Exclusive)
+    }
+
+    fn read<D>(&mut self, db: &mut Db<D, K, V>, k: &K, mode: LockMode) -> anyhow::Result<Option<V>>
+    where
+        D: DbDir + std::fmt::Debug + 'static,
+    {
+        if let Some(v) = self.writes.get(k) {
+            return Ok(v.clone());
+        }
+        self.lock(k, mode)?;
+        db.get_at(k, db.visible_seqnum())
+    }
+
+    pub fn insert(&mut self, k: K, v: V) -> anyhow::Result<()> {
+        self.lock(&k, LockMode::Exclusive)?;
+        self.writes.insert(k, Some(v));
+        Ok(())
+    }
+
+    pub fn delete(&mut self, k: K) -> anyhow::Result<()> {
+        self.lock(&k, LockMode::Exclusive)?;
+        self.writes.insert(k, None);
+        Ok(())
+    }
+
+    pub(super) fn take_commands(&mut self, seqnum: usize) -> Vec<DBCommand<K, V>> {
+        commands(std::mem::take(&mut self.writes), seqnum)
+    }
+
+    pub(super) fn is_read_only(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+// Dropping a transaction without committing it rolls it back.
+impl<K, V> Drop for PessimisticTransaction<K, V>
+where
+    K: Ord + Hash + Clone,
+{
+    fn drop(&mut self) {
+        self.lock_manager.unlock_all(self.id);
+    }
+}
+
+// Returned from `Db::commit` when a key the transaction read has since been
+// written. The transaction can be retried from scratch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionConflict {
+    pub snapshot: usize,
+    // The seqnum of the write which conflicted.
+    pub seqnum: usize,
+}
+
+impl std::fmt::Display for TransactionConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "transaction conflict: a key read at seqnum {} was written at seqnum {}",
+            self.snapshot, self.seqnum
+        )
+    }
+}
+
+impl std::error::Error for TransactionConflict {}
@@ -0,0 +1,211 @@
+// Typed views of a database which only holds `Bytes`. A view encodes keys
+// and values on the way in and decodes them on the way out. Each view lives
+// in its own column family, so one database can host several, each with
+// different types.
+//
+// The storage engine underneath is still the generic `Db`: `ByteDb` is just
+// `Db<D, Bytes, Bytes>`, not an engine over `&[u8]`. A scan here decodes
+// straight from the database's iterator without cloning, and that iterator
+// copies each key and value into buffers it reuses, so reading doesn't
+// allocate per entry. It still copies, though: resolving a key's versions
+// moves the iterators underneath past it. `Db::scan` itself hands out owned
+// clones.
+//
+// Keys are encoded with `Bytes::encode_ordered` and ordered by their
+// encoding, which for strings, integers and tuples of them is the same as
+// their `Ord`.
+use std::{marker::PhantomData, sync::Arc};
+
+use crate::{
+    comparator::BytewiseComparator,
+    encoding::{Bytes, Decode, Encode},
+    fs::DbDir,
+    memtable::KVIter,
+};
+
+use super::{options::DbOptions, ColumnFamilyId, Db, DbIterator, DEFAULT_COLUMN_FAMILY};
+
+pub type ByteDb<D> = Db<D, Bytes, Bytes>;
+
+// Options for a `ByteDb` that orders its keys with `BytewiseComparator`,
+// which is the same order as `Bytes`'s `Ord` but lets SSTs shorten the keys
+// in their indexes.
+pub fn byte_db_options() -> DbOptions<Bytes, Bytes> {
+    DbOptions {
+        comparator: Arc::new(BytewiseComparator),
+        ..Default::default()
+    }
+}
+
+#[derive(Debug)]
+pub struct TypedDb<K, V> {
+    cf: ColumnFamilyId,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K, V> TypedDb<K, V>
+where
+    K: Encode + Decode,
+    V: Encode + Decode,
+{
+    // A view of the default column family.
+    pub fn new() -> Self {
+        TypedDb {
+            cf: DEFAULT_COLUMN_FAMILY,
+            _marker: PhantomData,
+        }
+    }
+
+    // A view of the column family called `name`, which is created with the
+    // database's options if it doesn't exist yet.
+    pub fn open<D>(db: &mut ByteDb<D>, name: &str) -> anyhow::Result<Self>
+    where
+        D: DbDir + std::fmt::Debug + 'static,
+    {
+        let cf = match db.column_family(name) {
+            Some(cf) => cf,
+            None => db.create_column_family(name, db.options.clone())?,
+        };
+        Ok(TypedDb {
+            cf,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn column_family(&self) -> ColumnFamilyId {
+        self.cf
+    }
+
+    pub fn insert<D>(&self, db: &mut ByteDb<D>, k: &K, v: &V) -> anyhow::Result<()>
+    where
+        D: DbDir + std::fmt::Debug + 'static,
+    {
+        db.insert_cf(self.cf, Bytes::encode_ordered(k), Bytes::encode(v))
+    }
+
+    pub fn delete<D>(&self, db: &mut ByteDb<D>, k: &K) -> anyhow::Result<()>
+    where
+        D: DbDir + std::fmt::Debug + 'static,
+    {
+        db.delete_cf(self.cf, Bytes::encode_ordered(k))
+    }
+
+    pub fn delete_range<D>(&self, db: &mut ByteDb<D>, start: &K, end: &K) -> anyhow::Result<()>
+    where
+        D: DbDir + std::fmt::Debug + 'static,
+    {
+        db.delete_range_cf(
+            self.cf,
+            Bytes::encode_ordered(start),
+            Bytes::encode_ordered(end),
+        )
+    }
+
+    pub fn get<D>(&self, db: &mut ByteDb<D>, k: &K) -> anyhow::Result<Option<V>>
+    where
+        D: DbDir + std::fmt::Debug + 'static,
+    {
+        db.get_cf(self.cf, &Bytes::encode_ordered(k))?
+            .map(|v| v.decode())
+            .transpose()
+    }
+
+    pub fn scan<D>(
+        &self,
+        db: &mut ByteDb<D>,
+    ) -> anyhow::Result<TypedIterator<K, V, impl KVIter<Bytes, Bytes>>>
+    where
+        D: DbDir + std::fmt::Debug + 'static,
+    {
+        Ok(TypedIterator {
+            inner: db.scan_cf(self.cf)?,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<K, V> Default for TypedDb<K, V>
+where
+    K: Encode + Decode,
+    V: Encode + Decode,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct TypedIterator<K, V, I>
+where
+    I: KVIter<Bytes, Bytes>,
+{
+    inner: DbIterator<Bytes, Bytes, I>,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K, V, I> Iterator for TypedIterator<K, V, I>
+where
+    K: Decode,
+    V: Decode,
+    I: KVIter<Bytes, Bytes>,
+{
+    type Item = anyhow::Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Some((k, v)) = self.inner.iter.next() else {
+            return self.inner.status().err().map(Err);
+        };
+        Some(k.decode_ordered().and_then(|k| Ok((k, v.decode()?))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fs::MockDir;
+
+    use super::{byte_db_options, ByteDb, TypedDb};
+
+    #[test]
+    fn test_typed_views() {
+        let dir = MockDir::new();
+        let mut db: ByteDb<_> = ByteDb::with_options(dir.clone(), byte_db_options()).unwrap();
+        let names: TypedDb<String, String> = TypedDb::new();
+        let counts: TypedDb<(String, usize), u64> = TypedDb::open(&mut db, "counts").unwrap();
+
+        names.insert(&mut db, &"b".into(), &"bee".into()).unwrap();
+        names.insert(&mut db, &"a".into(), &"ay".into()).unwrap();
+        // Integers sort numerically, not by their first byte.
+        for (i, n) in [10_usize, 2, 256].into_iter().enumerate() {
+            counts
+                .insert(&mut db, &("x".into(), n), &(i as u64))
+                .unwrap();
+        }
+        counts.insert(&mut db, &("".into(), 1000), &7).unwrap();
+        db.flush_memtable_cf(counts.column_family()).unwrap();
+        counts.delete(&mut db, &("x".into(), 2)).unwrap();
+
+        assert_eq!(
+            names.get(&mut db, &"a".into()).unwrap(),
+            Some("ay".to_owned())
+        );
+        assert_eq!(names.get(&mut db, &"c".into()).unwrap(), None);
+        let scanned = |db: &mut ByteDb<_>, counts: &TypedDb<(String, usize), u64>| {
+            counts
+                .scan(db)
+                .unwrap()
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap()
+        };
+        let expected = vec![
+            (("".to_owned(), 1000), 7),
+            (("x".to_owned(), 10), 0),
+            (("x".to_owned(), 256), 2),
+        ];
+        assert_eq!(scanned(&mut db, &counts), expected);
+
+        // The views are just column families, so they come back on reopening.
+        let mut db: ByteDb<_> = ByteDb::with_options(dir, byte_db_options()).unwrap();
+        let counts: TypedDb<(String, usize), u64> = TypedDb::open(&mut db, "counts").unwrap();
+        assert_eq!(scanned(&mut db, &counts), expected);
+        assert_eq!(names.scan(&mut db).unwrap().count(), 2);
+    }
+}
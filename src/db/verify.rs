@@ -0,0 +1,189 @@
+// A consistency check of everything a database's manifest refers to. Nothing
+// is changed, and a problem with one file doesn't stop the others from being
+// checked.
+use std::{cmp::Ordering, sync::Arc};
+
+use anyhow::bail;
+
+use crate::{
+    comparator::Comparator,
+    encoding::{Decode, Encode},
+    fs::DbDir,
+    memtable::DbValue,
+    sst::{reader::SstReader, KeyFormat},
+};
+
+use super::{archived_wal_path, ColumnFamilyId, Db, SstMetadata};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    // The manifest refers to a file that doesn't exist.
+    MissingFile(String),
+    // The SST can't be read, or doesn't agree with itself or the manifest.
+    CorruptSst {
+        filename: String,
+        reason: String,
+    },
+    // Neighbouring SSTs in a level below L0 are out of order or overlap.
+    OverlappingSsts {
+        cf: ColumnFamilyId,
+        level: usize,
+        left: String,
+        right: String,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub ssts_checked: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl<D, K, V> Db<D, K, V>
+where
+    D: DbDir + std::fmt::Debug + 'static,
+    K: Ord + Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
+    V: Default + Clone + std::fmt::Debug + Decode + Encode + Send + Sync + 'static,
+{
+    fn verify(&mut self) -> VerifyReport {
+        let mut report = VerifyReport::default();
+        let comparator = self.internal_comparator();
+        let key_format = self.key_format();
+
+        let data = &self.root.data;
+        let wals = data.wals.iter().cloned().chain(
+            data.archived_wals
+                .iter()
+                .map(|wal| archived_wal_path(&wal.name)),
+        );
+        for wal in wals {
+            if self.dir.open(&wal).is_none() {
+                report.problems.push(Problem::MissingFile(wal));
+            }
+        }
+
+        for family in &data.families {
+            for sst in family.l0.iter().chain(family.ssts.iter().flatten()) {
+                if self.dir.open(&sst.filename).is_none() {
+                    report
+                        .problems
+                        .push(Problem::MissingFile(sst.filename.clone()));
+                    continue;
+                }
+                report.ssts_checked += 1;
+                if let Err(e) =
+                    Self::verify_sst(&mut self.dir, sst, comparator.clone(), key_format.clone())
+                {
+                    report.problems.push(Problem::CorruptSst {
+                        filename: sst.filename.clone(),
+                        reason: e.to_string(),
+                    });
+                }
+            }
+        }
+
+        for (cf, family) in self.families.iter().enumerate() {
+            for level in 1..=family.layout.ssts.len() {
+                if let Some((left, right)) = family.layout.overlap_in_level(&*comparator, level) {
+                    report.problems.push(Problem::OverlappingSsts {
+                        cf,
+                        level,
+                        left,
+                        right,
+                    });
+                }
+            }
+        }
+        report
+    }
+
+    fn verify_sst(
+        dir: &mut D,
+        sst: &SstMetadata,
+        comparator: Arc<dyn Comparator<(K, u64, usize)>>,
+        key_format: Arc<dyn KeyFormat<(K, u64, usize)>>,
+    ) -> anyhow::Result<()> {
+        let file = dir.open(&sst.filename).unwrap();
+        let mut reader =
+            SstReader::<(K, u64, usize), DbValue<V>, D>::load_with_key_format(file, key_format)?
+                .with_comparator(comparator.clone());
+        reader.verify()?;
+        let meta = &reader.sst_meta;
+        let min_key: (K, u64, usize) = sst.min_key.decode()?;
+        let max_key: (K, u64, usize) = sst.max_key.decode()?;
+        if comparator.compare(&min_key, &meta.min_key) != Ordering::Equal
+            || comparator.compare(&max_key, &meta.max_key) != Ordering::Equal
+        {
+            bail!(
+                "manifest says the sst spans {:?} to {:?}, but its metadata says {:?} to {:?}",
+                min_key,
+                max_key,
+                meta.min_key,
+                meta.max_key
+            );
+        }
+        if sst.num_bytes != meta.num_bytes {
+            bail!(
+                "manifest says the sst is {} bytes, but it's {}",
+                sst.num_bytes,
+                meta.num_bytes
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Seek, SeekFrom};
+
+    use crate::fs::{DbDir, DbFile, MockDir};
+
+    use super::{super::Db, Problem};
+
+    #[test]
+    fn test_verify() {
+        let mut dir = MockDir::new();
+        let mut db: Db<_, String, String> = Db::new(dir.clone()).unwrap();
+        for k in ["a", "b", "y", "z"] {
+            db.insert(k.into(), "1".into()).unwrap();
+            db.flush_memtable().unwrap();
+            db.merge(vec![(0, 0)], 1).unwrap();
+        }
+        db.insert("c".into(), "2".into()).unwrap();
+        db.flush_memtable().unwrap();
+        let report = db.verify();
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.ssts_checked, db.live_ssts().len());
+
+        // Flip a byte in the middle of one SST, and delete another.
+        let ssts = db.live_ssts();
+        let mut f = dir.open(&ssts[0]).unwrap();
+        f.seek(SeekFrom::Start(10)).unwrap();
+        f.write(b"X").unwrap();
+        dir.unlink(&ssts[1]).unwrap();
+        // Put the first level out of order.
+        db.families[0].layout.ssts[0].reverse();
+
+        let problems = db.verify().problems;
+        assert!(
+            matches!(&problems[0], Problem::CorruptSst { filename, reason }
+            if *filename == ssts[0] && reason.contains("corrupt"))
+        );
+        assert_eq!(problems[1], Problem::MissingFile(ssts[1].clone()));
+        assert!(matches!(
+            problems[2],
+            Problem::OverlappingSsts {
+                cf: 0,
+                level: 1,
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,66 @@
+// A group of writes, possibly spanning several column families, which
+// `Db::write` applies atomically at a single seqnum.
+use std::collections::BTreeMap;
+
+use crate::encoding::Encode;
+
+use super::{ColumnFamilyId, DBCommand};
+
+#[derive(Debug)]
+pub struct WriteBatch<K, V> {
+    // A `None` value is a delete. Every write in the batch shares a seqnum,
+    // so only the last one to each key is kept.
+    writes: BTreeMap<(ColumnFamilyId, K), Option<V>>,
+}
+
+impl<K, V> WriteBatch<K, V>
+where
+    K: std::fmt::Debug + Ord + Encode,
+    V: std::fmt::Debug + Encode,
+{
+    pub fn new() -> Self {
+        WriteBatch {
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, cf: ColumnFamilyId, k: K, v: V) {
+        self.writes.insert((cf, k), Some(v));
+    }
+
+    pub fn delete(&mut self, cf: ColumnFamilyId, k: K) {
+        self.writes.insert((cf, k), None);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    pub(super) fn column_families(&self) -> impl Iterator<Item = ColumnFamilyId> + '_ {
+        self.writes.keys().map(|(cf, _)| *cf)
+    }
+
+    // The batch's writes as commands at `seqnum`, ordered by family and key.
+    pub(super) fn into_commands(self, seqnum: usize) -> Vec<DBCommand<K, V>> {
+        self.writes
+            .into_iter()
+            .map(|((cf, k), v)| {
+                match v {
+                    Some(v) => DBCommand::Write(seqnum, k, v),
+                    None => DBCommand::Delete(seqnum, k),
+                }
+                .in_family(cf)
+            })
+            .collect()
+    }
+}
+
+impl<K, V> Default for WriteBatch<K, V>
+where
+    K: std::fmt::Debug + Ord + Encode,
+    V: std::fmt::Debug + Encode,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,42 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Tracks memtable memory across every `Db` in the process that shares it, so
+// that a group of databases can be held to one overall budget. Each database
+// reserves the memory its memtables use and releases it when they are flushed
+// (or when the database is closed).
+#[derive(Debug)]
+pub struct WriteBufferManager {
+    buffer_size: usize,
+    memory_used: AtomicUsize,
+}
+
+impl WriteBufferManager {
+    pub fn new(buffer_size: usize) -> Self {
+        WriteBufferManager {
+            buffer_size,
+            memory_used: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.memory_used.load(Ordering::SeqCst)
+    }
+
+    pub fn reserve(&self, bytes: usize) {
+        self.memory_used.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub fn free(&self, bytes: usize) {
+        self.memory_used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+
+    // Whether the combined memtables have outgrown the budget, and whoever is
+    // writing should flush.
+    pub fn should_flush(&self) -> bool {
+        self.memory_usage() >= self.buffer_size
+    }
+}
@@ -0,0 +1,102 @@
+// Write stalls are how the database pushes back on writers when background
+// work (flushing and compacting) has fallen behind. Without them, a sustained
+// stream of writes can grow L0 without bound, and every read has to consult
+// every one of those SSTs.
+//
+// There are two severities. A _slowdown_ delays each write a little, giving
+// whoever is driving compactions a chance to catch up. A _stop_ holds the
+// write back: since flushes and compactions are driven by the caller rather
+// than a background thread, waiting alone would never make progress, so the
+// writer flushes and compacts L0 itself until the stop clears, and the write
+// is rejected if it hasn't within `DbOptions::write_stop_timeout`.
+use std::fmt;
+
+use super::options::DbOptions;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStallCause {
+    L0FileCount(usize),
+    ImmutableMemtableCount(usize),
+    PendingCompactionBytes(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStall {
+    Slowdown(WriteStallCause),
+    Stop(WriteStallCause),
+}
+
+impl WriteStall {
+    pub fn cause(&self) -> WriteStallCause {
+        match self {
+            WriteStall::Slowdown(cause) | WriteStall::Stop(cause) => *cause,
+        }
+    }
+
+    // Determines which stall, if any, applies to a database in the given
+    // shape. Stops take precedence over slowdowns.
+    pub fn compute<K, V>(
+        opts: &DbOptions<K, V>,
+        l0_files: usize,
+        immutable_memtables: usize,
+        pending_compaction_bytes: usize,
+    ) -> Option<WriteStall> {
+        let checks = [
+            (
+                WriteStallCause::L0FileCount(l0_files),
+                l0_files,
+                opts.l0_slowdown_trigger,
+                opts.l0_stop_trigger,
+            ),
+            (
+                WriteStallCause::ImmutableMemtableCount(immutable_memtables),
+                immutable_memtables,
+                opts.immutable_memtable_slowdown_trigger,
+                opts.immutable_memtable_stop_trigger,
+            ),
+            (
+                WriteStallCause::PendingCompactionBytes(pending_compaction_bytes),
+                pending_compaction_bytes,
+                opts.pending_compaction_bytes_slowdown_trigger,
+                opts.pending_compaction_bytes_stop_trigger,
+            ),
+        ];
+
+        let mut result = None;
+        for (cause, value, slowdown, stop) in checks {
+            if value >= stop {
+                return Some(WriteStall::Stop(cause));
+            }
+            if value >= slowdown && result.is_none() {
+                result = Some(WriteStall::Slowdown(cause));
+            }
+        }
+        result
+    }
+}
+
+impl fmt::Display for WriteStallCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteStallCause::L0FileCount(n) => write!(f, "{} SSTs in L0", n),
+            WriteStallCause::ImmutableMemtableCount(n) => {
+                write!(f, "{} immutable memtables awaiting flush", n)
+            }
+            WriteStallCause::PendingCompactionBytes(n) => {
+                write!(f, "{} bytes pending compaction", n)
+            }
+        }
+    }
+}
+
+// Returned from writes that were rejected because of a `WriteStall::Stop`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteStopped(pub WriteStallCause);
+
+impl fmt::Display for WriteStopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "writes are stopped: {}", self.0)
+    }
+}
+
+impl std::error::Error for WriteStopped {}
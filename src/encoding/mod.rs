@@ -1,3 +1,5 @@
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
 const SEPARATOR: [u8; 2] = [0x00, 0x01];
 const ESCAPED_00: [u8; 2] = [0x00, 0xff];
 
@@ -37,11 +39,25 @@ fn copy_unescaped(mut from: &[u8], to: &mut Vec<u8>) {
 #[derive(Debug)]
 pub struct KeyWriter {
     pub(crate) buf: Vec<u8>,
+    // Whether integers are written big-endian, so that they sort the same way
+    // encoded as they do as numbers. They're little-endian otherwise, which
+    // is how everything already on disk has them.
+    ordered: bool,
 }
 
 impl KeyWriter {
     pub fn new() -> Self {
-        KeyWriter { buf: Vec::new() }
+        KeyWriter {
+            buf: Vec::new(),
+            ordered: false,
+        }
+    }
+
+    pub fn ordered() -> Self {
+        KeyWriter {
+            buf: Vec::new(),
+            ordered: true,
+        }
     }
 
     pub fn clear(&mut self) {
@@ -57,10 +73,18 @@ impl KeyWriter {
         copy_escaped(buf, &mut self.buf);
     }
 
-    fn write_fixed_size(&mut self, buf: &[u8]) {
+    pub fn write_fixed_size(&mut self, buf: &[u8]) {
         self.buf.extend(buf);
     }
 
+    // Writes an integer, given as its big-endian bytes.
+    fn write_int<const N: usize>(&mut self, mut be_bytes: [u8; N]) {
+        if !self.ordered {
+            be_bytes.reverse();
+        }
+        self.buf.extend(be_bytes);
+    }
+
     fn separator(&mut self) {
         self.buf.extend([0x00, 0x01]);
     }
@@ -72,6 +96,8 @@ pub struct KeyReader {
     buf: Vec<u8>,
     from: usize,
     scratch: Vec<u8>,
+    // See `KeyWriter::ordered`.
+    ordered: bool,
 }
 
 impl KeyReader {
@@ -80,6 +106,14 @@ impl KeyReader {
             buf: Vec::new(),
             from: 0,
             scratch: Vec::new(),
+            ordered: false,
+        }
+    }
+
+    pub fn ordered() -> Self {
+        KeyReader {
+            ordered: true,
+            ..KeyReader::new()
         }
     }
 
@@ -117,6 +151,15 @@ impl KeyReader {
         self.from += n;
         &self.buf[from..from + n]
     }
+
+    // Reads an integer, returning its big-endian bytes.
+    fn next_int<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        let mut be_bytes: [u8; N] = self.next_fixed_size(N).try_into()?;
+        if !self.ordered {
+            be_bytes.reverse();
+        }
+        Ok(be_bytes)
+    }
 }
 
 pub trait Encode: std::fmt::Debug {
@@ -130,14 +173,29 @@ pub trait Decode: Sized {
     fn decode(kr: &mut KeyReader) -> anyhow::Result<Self>;
 }
 
+// Vecs are length-prefixed, so unlike most of the encodings here, this one
+// doesn't preserve order. Use `Bytes` for a string of bytes that should.
 impl<T: Encode> Encode for Vec<T> {
     fn write_bytes(&self, kw: &mut KeyWriter) {
-        // TODO: could we do this with separators somehow?
-        kw.write(&self.len().to_le_bytes());
+        self.len().write_bytes(kw);
         for v in self {
             v.write_bytes(kw);
+            if v.needs_delimiter() {
+                kw.separator();
+            }
         }
     }
+
+    fn needs_delimiter(&self) -> bool {
+        false
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(kr: &mut KeyReader) -> anyhow::Result<Self> {
+        let len = usize::decode(kr)?;
+        (0..len).map(|_| T::decode(kr)).collect()
+    }
 }
 
 impl Encode for () {
@@ -166,9 +224,95 @@ impl Decode for String {
     }
 }
 
+// A string of bytes, which (unlike a `Vec<u8>`) is written out as-is, so
+// that it sorts the same way encoded as it does in memory. This is what the
+// database stores when it holds values of several types, with each one
+// encoded into `Bytes` on the way in. It's serialized as a hex string.
+#[derive(Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Bytes(pub Vec<u8>);
+
+// Iterators copy each key and value into a buffer they hold on to, so
+// `clone_from` reuses the buffer's allocation rather than making a new one.
+impl Clone for Bytes {
+    fn clone(&self) -> Self {
+        Bytes(self.0.clone())
+    }
+
+    fn clone_from(&mut self, source: &Self) {
+        self.0.clone_from(&source.0);
+    }
+}
+
+impl std::fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Bytes(\"{}\")", self.0.escape_ascii())
+    }
+}
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = self.0.iter().map(|b| format!("{:02x}", b)).collect();
+        serializer.serialize_str(&hex)
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 {
+            return Err(D::Error::custom("odd number of hex digits"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(D::Error::custom))
+            .collect::<Result<_, _>>()
+            .map(Bytes)
+    }
+}
+
+impl Bytes {
+    pub fn encode<T: Encode>(t: &T) -> Self {
+        let mut kw = KeyWriter::new();
+        t.write_bytes(&mut kw);
+        Bytes(kw.buf)
+    }
+
+    pub fn decode<T: Decode>(&self) -> anyhow::Result<T> {
+        let mut kr = KeyReader::new();
+        kr.load(&self.0);
+        T::decode(&mut kr)
+    }
+
+    // Encodes `t` so that its encoding sorts the same way it does, for types
+    // (strings, integers and tuples of them) where that's possible.
+    pub fn encode_ordered<T: Encode>(t: &T) -> Self {
+        let mut kw = KeyWriter::ordered();
+        t.write_bytes(&mut kw);
+        Bytes(kw.buf)
+    }
+
+    pub fn decode_ordered<T: Decode>(&self) -> anyhow::Result<T> {
+        let mut kr = KeyReader::ordered();
+        kr.load(&self.0);
+        T::decode(&mut kr)
+    }
+}
+
+impl Encode for Bytes {
+    fn write_bytes(&self, kw: &mut KeyWriter) {
+        kw.write(&self.0)
+    }
+}
+
+impl Decode for Bytes {
+    fn decode(kr: &mut KeyReader) -> anyhow::Result<Self> {
+        Ok(Bytes(kr.next().to_vec()))
+    }
+}
+
 impl Encode for usize {
     fn write_bytes(&self, kw: &mut KeyWriter) {
-        kw.write_fixed_size(&self.to_le_bytes())
+        kw.write_int(self.to_be_bytes())
     }
 
     fn needs_delimiter(&self) -> bool {
@@ -178,8 +322,7 @@ impl Encode for usize {
 
 impl Decode for usize {
     fn decode(kr: &mut KeyReader) -> anyhow::Result<Self> {
-        let next = kr.next_fixed_size((usize::BITS / 8).try_into()?);
-        Ok(Self::from_le_bytes(next.try_into()?))
+        Ok(Self::from_be_bytes(kr.next_int()?))
     }
 }
 
@@ -198,7 +341,7 @@ impl Decode for u8 {
 
 impl Encode for u32 {
     fn write_bytes(&self, kw: &mut KeyWriter) {
-        kw.write_fixed_size(&self.to_le_bytes())
+        kw.write_int(self.to_be_bytes())
     }
 
     fn needs_delimiter(&self) -> bool {
@@ -208,7 +351,23 @@ impl Encode for u32 {
 
 impl Decode for u32 {
     fn decode(kr: &mut KeyReader) -> anyhow::Result<Self> {
-        Ok(Self::from_le_bytes(kr.next_fixed_size(4).try_into()?))
+        Ok(Self::from_be_bytes(kr.next_int()?))
+    }
+}
+
+impl Encode for u64 {
+    fn write_bytes(&self, kw: &mut KeyWriter) {
+        kw.write_int(self.to_be_bytes())
+    }
+
+    fn needs_delimiter(&self) -> bool {
+        false
+    }
+}
+
+impl Decode for u64 {
+    fn decode(kr: &mut KeyReader) -> anyhow::Result<Self> {
+        Ok(Self::from_be_bytes(kr.next_int()?))
     }
 }
 
@@ -255,6 +414,33 @@ where
     }
 }
 
+impl<A, B, C> Encode for (A, B, C)
+where
+    A: Encode,
+    B: Encode,
+    C: Encode,
+{
+    fn write_bytes(&self, kw: &mut KeyWriter) {
+        (&self.0, (&self.1, &self.2)).write_bytes(kw)
+    }
+
+    fn needs_delimiter(&self) -> bool {
+        self.2.needs_delimiter()
+    }
+}
+
+impl<A, B, C> Decode for (A, B, C)
+where
+    A: Decode + std::fmt::Debug,
+    B: Decode + std::fmt::Debug,
+    C: Decode + std::fmt::Debug,
+{
+    fn decode(kr: &mut KeyReader) -> anyhow::Result<Self> {
+        let (a, (b, c)) = <(A, (B, C))>::decode(kr)?;
+        Ok((a, b, c))
+    }
+}
+
 impl<A> Encode for Option<A>
 where
     A: Encode,
@@ -293,6 +479,21 @@ where
     }
 }
 
+#[test]
+fn test_vec_roundtrip() {
+    let v = vec![
+        ("a".to_owned(), 1_usize),
+        ("".to_owned(), 2),
+        ("c".to_owned(), 3),
+    ];
+    let mut kw = KeyWriter::new();
+    (&v, "after".to_owned()).write_bytes(&mut kw);
+    let mut kr = KeyReader::new();
+    kr.load(&kw.buf);
+    let decoded = <(Vec<(String, usize)>, String)>::decode(&mut kr).unwrap();
+    assert_eq!(decoded, (v, "after".to_owned()));
+}
+
 #[test]
 fn test_escaping() {
     for str in [
@@ -307,3 +508,36 @@ fn test_escaping() {
         assert_eq!(str, out2);
     }
 }
+
+#[test]
+fn test_order_preserving() {
+    let encoded = |t: &(String, usize)| Bytes::encode_ordered(t);
+    let mut keys = vec![
+        ("a".to_owned(), 10),
+        ("a".to_owned(), 2),
+        ("a\0".to_owned(), 0),
+        ("".to_owned(), 300),
+        ("ab".to_owned(), 1),
+    ];
+    let mut by_encoding = keys.clone();
+    keys.sort();
+    by_encoding.sort_by_key(encoded);
+    assert_eq!(keys, by_encoding);
+    for k in keys {
+        assert_eq!(encoded(&k).decode_ordered::<(String, usize)>().unwrap(), k);
+    }
+
+    // Everywhere else, integers are little-endian, as they always have been.
+    assert_eq!(Bytes::encode(&258_u64).0, 258_u64.to_le_bytes());
+    assert_eq!(Bytes::encode_ordered(&258_u64).0, 258_u64.to_be_bytes());
+}
+
+#[test]
+fn test_bytes_serde() {
+    let b = Bytes(vec![0x00, 0xff, b'a']);
+    let json = serde_json::to_string(&b).unwrap();
+    assert_eq!(json, "\"00ff61\"");
+    assert_eq!(serde_json::from_str::<Bytes>(&json).unwrap(), b);
+    assert!(serde_json::from_str::<Bytes>("\"0\"").is_err());
+    assert_eq!(format!("{:?}", b), "Bytes(\"\\x00\\xffa\")");
+}
@@ -6,7 +6,7 @@ use std::{
     rc::Rc,
 };
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 
 pub trait DbFile: std::fmt::Debug + Read + Seek {
     fn write(&mut self, buf: &[u8]) -> anyhow::Result<()>;
@@ -18,6 +18,7 @@ pub trait DbFile: std::fmt::Debug + Read + Seek {
 pub trait DbDir: Clone {
     type DbFile: DbFile;
 
+    #[allow(unused)]
     fn cd<P>(&mut self, dir_name: &P) -> Self
     where
         P: AsRef<Path>;
@@ -26,6 +27,8 @@ pub trait DbDir: Clone {
     where
         P: AsRef<Path>;
 
+    // The names of the files in this directory, not counting any in the
+    // directories below it.
     fn ls(&mut self) -> Vec<String>;
 
     fn create<P>(&mut self, fname: &P) -> anyhow::Result<Option<Self::DbFile>>
@@ -40,6 +43,35 @@ pub trait DbDir: Clone {
     where
         P: AsRef<Path>,
         Q: AsRef<Path>;
+
+    // Makes `to`, in `target`, another name for the file `from`. Returns
+    // whether that was possible; directories which can't share files with
+    // one another needn't support it.
+    fn hard_link<P, Q>(&mut self, _from: &P, _target: &mut Self, _to: &Q) -> anyhow::Result<bool>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        Ok(false)
+    }
+}
+
+// Copies the file `from` to `to`, in `target`, and syncs the copy.
+pub fn copy_file<D, P, Q>(dir: &mut D, from: &P, target: &mut D, to: &Q) -> anyhow::Result<()>
+where
+    D: DbDir,
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let contents = dir
+        .open(from)
+        .ok_or_else(|| anyhow!("{} does not exist", from.as_ref().display()))?
+        .read_all();
+    let mut file = target
+        .create(to)?
+        .ok_or_else(|| anyhow!("{} already exists", to.as_ref().display()))?;
+    file.write(&contents)?;
+    file.sync()
 }
 
 // Mock Implementation
@@ -65,24 +97,17 @@ impl MockFile {
 
 impl Seek for MockFile {
     fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
-        match pos {
-            io::SeekFrom::Start(i) => {
-                self.idx = i.try_into().unwrap();
-            }
-            io::SeekFrom::End(i) => {
-                // TODO: don't read the whole thing here
-                // TODO: these numeral types are effed
-                self.idx = ((self.read_all().len() as i64) + i).try_into().unwrap();
-            }
-            io::SeekFrom::Current(x) => {
-                if x > 0 {
-                    self.idx += x as usize;
-                } else {
-                    self.idx -= x as usize
-                }
-            }
-        }
-        Ok(self.idx.try_into().unwrap())
+        let idx = match pos {
+            io::SeekFrom::Start(i) => i as i64,
+            // TODO: don't read the whole thing here
+            io::SeekFrom::End(i) => self.read_all().len() as i64 + i,
+            io::SeekFrom::Current(x) => self.idx as i64 + x,
+        };
+        // Like a real file, refuse to seek to before the start.
+        self.idx = idx.try_into().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.idx as u64)
     }
 }
 
@@ -184,13 +209,20 @@ impl DbDir for MockDir {
     }
 
     fn ls(&mut self) -> Vec<String> {
-        let fnames: Vec<String> = (*self.fs)
+        let prefix = self
+            .prefix
+            .iter()
+            .map(|p| format!("{}/", p))
+            .collect::<String>();
+        let mut fnames: Vec<String> = (*self.fs)
             .borrow_mut()
             .names
             .keys()
-            .filter(|f| f.starts_with(&self.prefix.join("/")))
-            .cloned()
+            .filter_map(|f| f.strip_prefix(&prefix))
+            .filter(|f| !f.contains('/'))
+            .map(|f| f.to_owned())
             .collect();
+        fnames.sort();
         (*self.fs).borrow_mut().record(Event::Ls(fnames.clone()));
         fnames
     }
@@ -232,6 +264,20 @@ impl DbDir for MockDir {
             .borrow_mut()
             .rename(&self.full_path(from), &self.full_path(to))
     }
+
+    fn hard_link<P, Q>(&mut self, from: &P, target: &mut Self, to: &Q) -> anyhow::Result<bool>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        if !Rc::ptr_eq(&self.fs, &target.fs) {
+            return Ok(false);
+        }
+        (*self.fs)
+            .borrow_mut()
+            .link(&self.full_path(from), &target.full_path(to))?;
+        Ok(true)
+    }
 }
 
 type FileId = usize;
@@ -244,6 +290,8 @@ pub enum Event {
     Rename(String, String),
     Unlink(String),
     Open(String),
+    Link(String, String),
+    #[allow(unused)]
     Ls(Vec<String>),
 }
 
@@ -280,6 +328,9 @@ impl Event {
             Event::Open(name) => {
                 write!(w, "Open({})", name)?;
             }
+            Event::Link(from, to) => {
+                write!(w, "Link({}, {})", from, to)?;
+            }
             Event::Ls(names) => {
                 write!(w, "Ls() -> {:?}", names)?;
             }
@@ -479,6 +530,29 @@ impl MockFs {
         Ok(())
     }
 
+    fn link<P, Q>(&mut self, from: &P, to: &Q) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        self.perform_op()?;
+
+        let from = from.as_ref().to_str().unwrap().to_owned();
+        let to = to.as_ref().to_str().unwrap().to_owned();
+
+        self.record(Event::Link(from.clone(), to.clone()));
+
+        let Some(id) = self.names.get(&from).copied() else {
+            bail!("{} does not exist", from);
+        };
+        if self.names.contains_key(&to) {
+            bail!("{} already exists", to);
+        }
+        self.names.insert(to, id);
+
+        Ok(())
+    }
+
     fn write(&mut self, file: FileId, idx: usize, mut data: Vec<u8>) -> anyhow::Result<()> {
         self.perform_op()?;
 
@@ -496,7 +570,7 @@ impl MockFs {
             self.data[file].unsynced.push(0);
         }
 
-        self.data[file].unsynced[idx..].copy_from_slice(&data);
+        self.data[file].unsynced[idx..idx + data.len()].copy_from_slice(&data);
 
         self.record(Event::Write(file, idx, data));
         Ok(())
@@ -1,3 +1,4 @@
+mod comparator;
 mod db;
 mod encoding;
 mod fs;
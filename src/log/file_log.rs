@@ -32,21 +32,15 @@ where
             _marker: PhantomData,
         })
     }
-}
 
-impl<R, E> Iterator for LogReader<R, E>
-where
-    R: Read + Seek,
-    E: LogEntry,
-{
-    type Item = E;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        // TODO: careful error handling needs to occur here.
+    // Like `next`, but an entry that can't be decoded is an error rather
+    // than a panic. A torn entry at the end still just ends the log.
+    pub fn try_next(&mut self) -> anyhow::Result<Option<E>> {
         // First, get the u32 that denotes this entry's length.
         let mut buf = [0_u8; 4];
-        // TODO: signal this error upwards somehow.
-        self.file.read_exact(&mut buf).ok()?;
+        if self.file.read_exact(&mut buf).is_err() {
+            return Ok(None);
+        }
         let data_len = u32::from_le_bytes(buf);
 
         // TODO: this is probably not the right way to fill out the buffer to
@@ -54,12 +48,25 @@ where
         // it over? needs benchmarking.
         let buf = self.reader.buf_mut();
         buf.clear();
-        buf.extend(std::iter::repeat(0).take(data_len.try_into().unwrap()));
-        self.file.read_exact(buf).ok()?;
+        buf.extend(std::iter::repeat_n(0, data_len.try_into()?));
+        if self.file.read_exact(buf).is_err() {
+            return Ok(None);
+        }
 
-        let v = E::decode(&mut self.reader).unwrap();
+        Ok(Some(E::decode(&mut self.reader)?))
+    }
+}
+
+impl<R, E> Iterator for LogReader<R, E>
+where
+    R: Read + Seek,
+    E: LogEntry,
+{
+    type Item = E;
 
-        Some(v)
+    fn next(&mut self) -> Option<Self::Item> {
+        // TODO: signal this error upwards somehow.
+        self.try_next().unwrap()
     }
 }
 
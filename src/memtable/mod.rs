@@ -1,8 +1,21 @@
 #![allow(dead_code)]
 
-use crate::db::DBCommand;
-use crate::encoding::{Decode, Encode};
-use std::{marker::PhantomData, rc::Rc};
+use crate::comparator::{Comparator, InternalKeyComparator, OrdComparator};
+use crate::db::{merge_operator::MergeOperator, DBCommand};
+use crate::encoding::{Decode, Encode, KeyReader, KeyWriter};
+use anyhow::bail;
+use std::sync::Arc;
+
+use self::{
+    skiplist::{SkipList, SkipListIter},
+    slab::SlabRep,
+};
+
+mod merging_iter;
+mod skiplist;
+mod slab;
+
+pub use merging_iter::MergingIter;
 
 pub trait KVIter<K, V>
 where
@@ -19,6 +32,12 @@ where
 
     fn start(&mut self);
     fn end(&mut self);
+
+    // If the iterator stopped early because something went wrong, what it
+    // was. Each error is only handed out once.
+    fn take_error(&mut self) -> Option<anyhow::Error> {
+        None
+    }
 }
 
 impl<K, V, T: KVIter<K, V> + ?Sized> KVIter<K, V> for Box<T>
@@ -46,6 +65,9 @@ where
     fn end(&mut self) {
         (**self).end()
     }
+    fn take_error(&mut self) -> Option<anyhow::Error> {
+        (**self).take_error()
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -58,110 +80,447 @@ enum PhysicalState {
     AtEnd,
 }
 
+// The value half of an internal entry.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DbValue<V> {
+    #[default]
+    Delete,
+    Put(V),
+    // An operand for the database's `MergeOperator`.
+    Merge(V),
+    // A put which reads as a delete from the given time onwards.
+    ExpiringPut(V, u64),
+}
+
+impl<V> DbValue<V> {
+    pub fn is_merge(&self) -> bool {
+        matches!(self, DbValue::Merge(_))
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self, DbValue::ExpiringPut(_, deadline) if *deadline <= now)
+    }
+}
+
+// This shares its encoding with `Option<V>` for deletes and puts.
+impl<V> Encode for DbValue<V>
+where
+    V: Encode,
+{
+    fn write_bytes(&self, kw: &mut KeyWriter) {
+        match self {
+            DbValue::Delete => {
+                kw.write_fixed_size(&[0]);
+            }
+            DbValue::Put(v) => {
+                kw.write_fixed_size(&[1]);
+                v.write_bytes(kw);
+            }
+            DbValue::Merge(v) => {
+                kw.write_fixed_size(&[2]);
+                v.write_bytes(kw);
+            }
+            DbValue::ExpiringPut(v, deadline) => {
+                kw.write_fixed_size(&[3]);
+                deadline.write_bytes(kw);
+                v.write_bytes(kw);
+            }
+        }
+    }
+
+    fn needs_delimiter(&self) -> bool {
+        match self {
+            DbValue::Put(v) | DbValue::Merge(v) | DbValue::ExpiringPut(v, _) => v.needs_delimiter(),
+            DbValue::Delete => false,
+        }
+    }
+}
+
+impl<V> Decode for DbValue<V>
+where
+    V: Decode,
+{
+    fn decode(kr: &mut KeyReader) -> anyhow::Result<Self> {
+        let buf = kr.next_fixed_size(1);
+        match buf[0] {
+            0 => Ok(DbValue::Delete),
+            1 => Ok(DbValue::Put(V::decode(kr)?)),
+            2 => Ok(DbValue::Merge(V::decode(kr)?)),
+            3 => {
+                let deadline = u64::decode(kr)?;
+                Ok(DbValue::ExpiringPut(V::decode(kr)?, deadline))
+            }
+            x => bail!("invalid value tag {}", x),
+        }
+    }
+}
+
+// Deletes every key in `[start, end)` which was written before `seqnum`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeTombstone<K> {
+    pub start: K,
+    pub end: K,
+    pub seqnum: usize,
+}
+
+impl<K> RangeTombstone<K>
+where
+    K: Ord + Clone,
+{
+    // The tombstone as a pair of internal keys, which is how SSTs store them.
+    pub fn to_bounds(&self) -> ((K, u64, usize), (K, u64, usize)) {
+        (
+            (self.start.clone(), 0, self.seqnum),
+            (self.end.clone(), 0, self.seqnum),
+        )
+    }
+
+    pub fn from_bounds(
+        ((start, _, seqnum), (end, _, _)): ((K, u64, usize), (K, u64, usize)),
+    ) -> Self {
+        RangeTombstone { start, end, seqnum }
+    }
+
+    pub fn contains(&self, comparator: &dyn Comparator<K>, k: &K) -> bool {
+        comparator.le(&self.start, k) && comparator.lt(k, &self.end)
+    }
+}
+
+// A set of range tombstones, cut at every start and end into fragments which
+// don't overlap, so that the ones covering a key can be found with a binary
+// search.
+#[derive(Debug, Clone, Default)]
+pub struct RangeTombstones<K> {
+    // In order, each with the seqnums of every tombstone covering it, newest
+    // first.
+    fragments: Vec<(K, K, Vec<usize>)>,
+}
+
+impl<K> RangeTombstones<K>
+where
+    K: Ord + Clone,
+{
+    pub fn new(comparator: &dyn Comparator<K>, range_tombstones: &[RangeTombstone<K>]) -> Self {
+        let mut bounds: Vec<&K> = range_tombstones
+            .iter()
+            .flat_map(|t| [&t.start, &t.end])
+            .collect();
+        bounds.sort_by(|a, b| comparator.compare(a, b));
+        bounds.dedup_by(|a, b| comparator.compare(a, b).is_eq());
+        let mut by_start: Vec<_> = range_tombstones.iter().collect();
+        by_start.sort_by(|a, b| comparator.compare(&a.start, &b.start));
+
+        let mut fragments = Vec::new();
+        let mut active: Vec<&RangeTombstone<K>> = Vec::new();
+        let mut next = 0;
+        for pair in bounds.windows(2) {
+            let (start, end) = (pair[0], pair[1]);
+            while next < by_start.len() && comparator.le(&by_start[next].start, start) {
+                active.push(by_start[next]);
+                next += 1;
+            }
+            active.retain(|t| comparator.lt(start, &t.end));
+            if active.is_empty() {
+                continue;
+            }
+            let mut seqnums: Vec<_> = active.iter().map(|t| t.seqnum).collect();
+            seqnums.sort_unstable_by(|a, b| b.cmp(a));
+            fragments.push((start.clone(), end.clone(), seqnums));
+        }
+        RangeTombstones { fragments }
+    }
+
+    // The seqnums of every tombstone covering `k`, newest first.
+    pub fn covering(&self, comparator: &dyn Comparator<K>, k: &K) -> &[usize] {
+        let idx = self
+            .fragments
+            .partition_point(|(start, _, _)| comparator.le(start, k));
+        match idx.checked_sub(1).map(|idx| &self.fragments[idx]) {
+            Some((_, end, seqnums)) if comparator.lt(k, end) => seqnums,
+            _ => &[],
+        }
+    }
+
+    // The newest seqnum, no greater than `seqnum`, at which `k` was range
+    // deleted, or 0 if it never was. Versions of `k` older than this are
+    // hidden.
+    pub fn covering_seqnum(&self, comparator: &dyn Comparator<K>, k: &K, seqnum: usize) -> usize {
+        let seqnums = self.covering(comparator, k);
+        let idx = seqnums.partition_point(|s| *s > seqnum);
+        seqnums.get(idx).copied().unwrap_or(0)
+    }
+}
+
+// Accumulates the visible versions of a single key, to work out what value
+// (if any) a reader should see for it.
+#[derive(Debug)]
+struct Resolution<V> {
+    // The newest put, if `has_base`. The buffer is kept between keys so that
+    // copying a value in doesn't need a new allocation each time.
+    base: V,
+    has_base: bool,
+    // Merge operands to apply on top of `base`. These are in the order they
+    // were visited, which is newest first when moving in reverse.
+    operands: Vec<V>,
+    // When visiting versions newest first, whether we've hit a put or delete,
+    // which shadows everything older.
+    complete: bool,
+    // Values which expire at or before this time read as deletes.
+    now: u64,
+}
+
+impl<V> Resolution<V>
+where
+    V: Default + Clone,
+{
+    fn new() -> Self {
+        Resolution {
+            base: V::default(),
+            has_base: false,
+            operands: Vec::new(),
+            complete: false,
+            now: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.has_base = false;
+        self.operands.clear();
+        self.complete = false;
+    }
+
+    // Applies a version which is newer than any seen so far.
+    fn apply_newer(&mut self, v: &DbValue<V>) {
+        match v {
+            DbValue::Delete => {
+                self.has_base = false;
+                self.operands.clear();
+            }
+            v if v.is_expired(self.now) => {
+                self.has_base = false;
+                self.operands.clear();
+            }
+            DbValue::Put(v) | DbValue::ExpiringPut(v, _) => {
+                self.base.clone_from(v);
+                self.has_base = true;
+                self.operands.clear();
+            }
+            DbValue::Merge(v) => self.operands.push(v.clone()),
+        }
+    }
+
+    // Applies a version which is older than any seen so far.
+    fn apply_older(&mut self, v: &DbValue<V>) {
+        if self.complete {
+            return;
+        }
+        match v {
+            DbValue::Delete => self.complete = true,
+            v if v.is_expired(self.now) => self.complete = true,
+            DbValue::Put(v) | DbValue::ExpiringPut(v, _) => {
+                self.base.clone_from(v);
+                self.has_base = true;
+                self.complete = true;
+            }
+            DbValue::Merge(v) => self.operands.push(v.clone()),
+        }
+    }
+
+    // Leaves the key's value in `out`, returning false if it has none. `out`
+    // and `base` swap buffers, so both keep their allocations.
+    fn finish<K>(
+        &mut self,
+        key: &K,
+        reversed: bool,
+        merge_operator: Option<&dyn MergeOperator<K, V>>,
+        out: &mut V,
+    ) -> anyhow::Result<bool> {
+        if self.operands.is_empty() {
+            if self.has_base {
+                std::mem::swap(out, &mut self.base);
+            }
+            return Ok(self.has_base);
+        }
+        if reversed {
+            self.operands.reverse();
+        }
+        let Some(merge_operator) = merge_operator else {
+            bail!("found a merge operand without a merge operator");
+        };
+        let base = self.has_base.then_some(&self.base);
+        *out = merge_operator.full_merge(key, base, &self.operands);
+        Ok(true)
+    }
+}
+
 pub struct SeqnumIter<I, K, V>
 where
     K: Ord,
-    I: KVIter<(K, usize), Option<V>>,
+    I: KVIter<(K, u64, usize), DbValue<V>>,
 {
     iter: I,
     seqnum: usize,
+    // Versions written after this timestamp are hidden.
+    timestamp: u64,
     state: PhysicalState,
+    // The current key and value. Each key is copied in from `iter`, since
+    // `iter` has moved past it by the time its versions are resolved.
     buf: (K, V),
+    // Where `seek_ge` last sought to, kept to reuse its allocation.
+    seek_key: (K, u64, usize),
+    resolution: Resolution<V>,
+    merge_operator: Option<Arc<dyn MergeOperator<K, V>>>,
+    range_tombstones: Arc<RangeTombstones<K>>,
+    comparator: Arc<dyn Comparator<K>>,
+    // Set if a key couldn't be resolved, which ends the iteration.
+    error: Option<anyhow::Error>,
 }
 
 impl<I, K, V> SeqnumIter<I, K, V>
 where
     K: Default + Eq + Ord + Clone + std::fmt::Debug,
     V: Default + Clone + std::fmt::Debug,
-    I: KVIter<(K, usize), Option<V>>,
+    I: KVIter<(K, u64, usize), DbValue<V>>,
 {
     pub fn new(seqnum: usize, iter: I) -> Self {
         SeqnumIter {
             state: PhysicalState::AtStart,
             iter,
             seqnum,
+            timestamp: u64::MAX,
             buf: <(K, V)>::default(),
+            seek_key: <(K, u64, usize)>::default(),
+            resolution: Resolution::new(),
+            merge_operator: None,
+            range_tombstones: Arc::default(),
+            comparator: Arc::new(OrdComparator),
+            error: None,
         }
     }
 
+    // The tombstones must be fragmented with the same ordering as
+    // `with_comparator`.
+    pub fn with_range_tombstones(mut self, range_tombstones: Arc<RangeTombstones<K>>) -> Self {
+        self.range_tombstones = range_tombstones;
+        self
+    }
+
+    // The ordering used to decide which keys a range tombstone covers.
+    pub fn with_comparator(mut self, comparator: Arc<dyn Comparator<K>>) -> Self {
+        self.comparator = comparator;
+        self
+    }
+
+    // Reads as of timestamp `ts`, rather than the latest one.
+    pub fn with_timestamp(mut self, ts: u64) -> Self {
+        self.timestamp = ts;
+        self
+    }
+
+    // Hides values which expire at or before `now`.
+    pub fn with_expiry_time(mut self, now: u64) -> Self {
+        self.resolution.now = now;
+        self
+    }
+
+    pub fn with_merge_operator(
+        mut self,
+        merge_operator: Option<Arc<dyn MergeOperator<K, V>>>,
+    ) -> Self {
+        self.merge_operator = merge_operator;
+        self
+    }
+
+    // Moves past every version of the next key, leaving its value in `buf`.
+    // Keys which have no visible value are skipped.
     fn physical_forwards(&mut self) -> bool {
-        let mut valid = false;
-        while !valid {
-            let (mut ks, mut v) = match self.iter.next() {
+        if self.error.is_some() {
+            return false;
+        }
+        loop {
+            let (ks, v) = match self.iter.next() {
                 Some((k, v)) => (k, v),
                 None => {
                     return false;
                 }
             };
-            while ks.1 > self.seqnum {
-                let (nks, nv) = match self.iter.next() {
-                    Some((k, v)) => (k, v),
-                    None => {
-                        return false;
-                    }
-                };
-                ks = nks;
-                v = nv;
-            }
-            if let Some(v) = v {
-                self.buf.0.clone_from(&(*ks).0);
-                self.buf.1.clone_from(v);
-                valid = true;
+            self.buf.0.clone_from(&ks.0);
+            self.resolution.reset();
+            let covered =
+                self.range_tombstones
+                    .covering_seqnum(&*self.comparator, &self.buf.0, self.seqnum);
+            if covered < ks.2 && ks.2 <= self.seqnum && ks.1 <= self.timestamp {
+                self.resolution.apply_newer(v);
             }
 
             while let Some((nks, nv)) = self.iter.peek() {
                 if nks.0 != self.buf.0 {
                     break;
                 }
-                if nks.1 <= self.seqnum {
-                    if let Some(v) = nv {
-                        self.buf.1.clone_from(v);
-                        valid = true;
-                    } else {
-                        valid = false;
-                    }
+                if covered < nks.2 && nks.2 <= self.seqnum && nks.1 <= self.timestamp {
+                    self.resolution.apply_newer(nv);
                 }
                 self.iter.next();
             }
-        }
 
-        true
+            let merge_operator = self.merge_operator.as_deref();
+            match self
+                .resolution
+                .finish(&self.buf.0, false, merge_operator, &mut self.buf.1)
+            {
+                Ok(true) => return true,
+                Ok(false) => {}
+                Err(e) => {
+                    self.error = Some(e);
+                    return false;
+                }
+            }
+        }
     }
 
     fn physical_reverse(&mut self) -> bool {
-        let mut valid = false;
-        while !valid {
-            let (mut ks, mut v) = match self.iter.prev() {
+        if self.error.is_some() {
+            return false;
+        }
+        loop {
+            let (ks, v) = match self.iter.prev() {
                 Some((k, v)) => (k, v),
                 None => {
                     return false;
                 }
             };
-            while ks.1 > self.seqnum {
-                let (nks, nv) = match self.iter.prev() {
-                    Some((k, v)) => (k, v),
-                    None => {
-                        return false;
-                    }
-                };
-                ks = nks;
-                v = nv;
-            }
-            self.buf.0.clone_from(&(*ks).0);
-            if let Some(v) = v {
-                self.buf.1.clone_from(v);
-                valid = true;
+            self.buf.0.clone_from(&ks.0);
+            self.resolution.reset();
+            let covered =
+                self.range_tombstones
+                    .covering_seqnum(&*self.comparator, &self.buf.0, self.seqnum);
+            if covered < ks.2 && ks.2 <= self.seqnum && ks.1 <= self.timestamp {
+                self.resolution.apply_older(v);
             }
 
-            while let Some((nks, _)) = self.iter.peek_prev() {
+            while let Some((nks, nv)) = self.iter.peek_prev() {
                 if nks.0 != self.buf.0 {
                     break;
                 }
+                if covered < nks.2 && nks.2 <= self.seqnum && nks.1 <= self.timestamp {
+                    self.resolution.apply_older(nv);
+                }
                 self.iter.prev();
             }
-        }
 
-        true
+            let merge_operator = self.merge_operator.as_deref();
+            match self
+                .resolution
+                .finish(&self.buf.0, true, merge_operator, &mut self.buf.1)
+            {
+                Ok(true) => return true,
+                Ok(false) => {}
+                Err(e) => {
+                    self.error = Some(e);
+                    return false;
+                }
+            }
+        }
     }
 }
 
@@ -169,7 +528,7 @@ impl<I, K, V> KVIter<K, V> for SeqnumIter<I, K, V>
 where
     K: Default + Eq + Ord + Clone + std::fmt::Debug,
     V: Default + Clone + std::fmt::Debug,
-    I: KVIter<(K, usize), Option<V>>,
+    I: KVIter<(K, u64, usize), DbValue<V>>,
 {
     fn next(&mut self) -> Option<(&K, &V)> {
         match self.state {
@@ -288,8 +647,8 @@ where
     }
 
     fn seek_ge(&mut self, key: &K) {
-        // TODO: we should use a buffer to clone_into the key here.
-        self.iter.seek_ge(&(key.clone(), 0));
+        self.seek_key.0.clone_from(key);
+        self.iter.seek_ge(&self.seek_key);
         if self.physical_forwards() {
             self.state = PhysicalState::FwdBehind;
         } else {
@@ -308,25 +667,37 @@ where
         self.state = PhysicalState::RevBehind;
         self.physical_reverse();
     }
+
+    fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.error.take().or_else(|| self.iter.take_error())
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct VecIter<K, V> {
     idx: usize,
-    contents: Rc<Vec<(K, V)>>,
+    // Sorted according to `comparator`.
+    contents: Arc<Vec<(K, V)>>,
+    comparator: Arc<dyn Comparator<K>>,
 }
 
 impl<K, V> VecIter<K, V>
 where
-    K: std::fmt::Debug,
+    K: Ord + Clone + std::fmt::Debug,
     V: std::fmt::Debug,
 {
-    pub fn new(v: Rc<Vec<(K, V)>>) -> Self {
+    pub fn new(v: Arc<Vec<(K, V)>>) -> Self {
         Self {
             idx: 0,
             contents: v,
+            comparator: Arc::new(OrdComparator),
         }
     }
+
+    pub fn with_comparator(mut self, comparator: Arc<dyn Comparator<K>>) -> Self {
+        self.comparator = comparator;
+        self
+    }
 }
 
 impl<K, V> KVIter<K, V> for VecIter<K, V>
@@ -372,11 +743,9 @@ where
     }
 
     fn seek_ge(&mut self, key: &K) {
-        let idx = match self.contents.binary_search_by_key(&key, |(k, _)| k) {
-            Ok(x) => x,
-            Err(x) => x,
-        };
-        self.idx = idx;
+        self.idx = self
+            .contents
+            .partition_point(|(k, _)| self.comparator.lt(k, key));
     }
 
     fn start(&mut self) {
@@ -388,127 +757,106 @@ where
     }
 }
 
-#[derive(Debug)]
-pub struct MergingIter<I, K, V>
-where
-    K: Ord,
-    I: KVIter<K, V>,
-{
-    iters: Vec<I>,
-    _marker: PhantomData<(K, V)>,
-}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
 
-impl<I, K, V> MergingIter<I, K, V>
-where
-    K: Ord + std::fmt::Debug,
-    V: std::fmt::Debug,
-    I: KVIter<K, V>,
-{
-    pub fn new<J>(j: J) -> Self
-    where
-        J: IntoIterator<Item = I>,
-    {
-        Self {
-            iters: j.into_iter().collect(),
-            _marker: PhantomData,
-        }
-    }
+    use rand::Rng;
 
-    fn lowest(&mut self) -> Option<usize> {
-        let mut lowest = None;
+    use crate::{
+        comparator::{InternalKeyComparator, OrdComparator},
+        db::merge_operator::ConcatOperator,
+        encoding::Bytes,
+        memtable::{
+            DbValue, KVIter, Memtable, MemtableRep, MemtableRepKind, RangeTombstone,
+            RangeTombstones, SeqnumIter, VecIter,
+        },
+    };
 
-        for (idx, it) in self.iters.iter_mut().enumerate() {
-            match lowest {
-                None => {
-                    lowest = it.peek().map(|(k, _v)| (idx, k));
-                }
-                Some((_, k)) => {
-                    if let Some((k2, _)) = it.peek() {
-                        if k2 < k {
-                            lowest = Some((idx, k2));
+    #[test]
+    fn test_shared_memtable_rep() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Memtable<String, String>>();
+
+        for kind in [MemtableRepKind::Slab, MemtableRepKind::SkipList] {
+            let rep: Arc<dyn MemtableRep<usize, usize>> = kind
+                .build(InternalKeyComparator::wrap(Arc::new(OrdComparator)))
+                .into();
+            let threads: Vec<_> = (0..4)
+                .map(|t| {
+                    let rep = rep.clone();
+                    std::thread::spawn(move || {
+                        for i in 0..250 {
+                            let seqnum = t * 250 + i;
+                            rep.insert((seqnum % 100, 0, seqnum), DbValue::Put(seqnum));
                         }
-                    }
-                }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
             }
-        }
 
-        lowest.map(|(x, _)| x)
+            let mut scan = rep.scan();
+            let mut entries = Vec::new();
+            while let Some((k, _)) = scan.next() {
+                entries.push(*k);
+            }
+            let mut expected: Vec<_> = (0..1000).map(|s| (s % 100, 0, s)).collect();
+            expected.sort();
+            assert_eq!(entries, expected, "{:?}", kind);
+        }
     }
 
-    fn highest(&mut self) -> Option<usize> {
-        let mut highest = None;
-
-        for (idx, it) in self.iters.iter_mut().enumerate() {
-            match highest {
-                None => {
-                    highest = it.peek_prev().map(|(k, _v)| (idx, k));
-                }
-                Some((_, k)) => {
-                    if let Some((k2, _)) = it.peek_prev() {
-                        if k2 > k {
-                            highest = Some((idx, k2));
-                        }
+    #[test]
+    fn test_fragmented_range_tombstones() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let tombstones: Vec<_> = (1..rng.gen_range(1..10))
+                .map(|seqnum| {
+                    let start = rng.gen_range(0..20);
+                    RangeTombstone {
+                        start,
+                        end: start + rng.gen_range(1..10),
+                        seqnum,
                     }
+                })
+                .collect();
+            let fragmented = RangeTombstones::new(&OrdComparator, &tombstones);
+            for k in 0..30 {
+                for seqnum in 0..10 {
+                    let expected = tombstones
+                        .iter()
+                        .filter(|t| t.seqnum <= seqnum && t.contains(&OrdComparator, &k))
+                        .map(|t| t.seqnum)
+                        .max()
+                        .unwrap_or(0);
+                    assert_eq!(
+                        fragmented.covering_seqnum(&OrdComparator, &k, seqnum),
+                        expected,
+                        "{:?} at {}@{}",
+                        tombstones,
+                        k,
+                        seqnum
+                    );
                 }
             }
         }
-
-        highest.map(|(x, _)| x)
-    }
-}
-
-impl<I, K, V> KVIter<K, V> for MergingIter<I, K, V>
-where
-    I: KVIter<K, V>,
-    K: Ord + std::fmt::Debug,
-    V: std::fmt::Debug,
-{
-    fn peek(&mut self) -> Option<(&K, &V)> {
-        let lowest = self.lowest()?;
-        self.iters[lowest].peek()
-    }
-
-    fn next(&mut self) -> Option<(&K, &V)> {
-        let i = self.lowest()?;
-        self.iters[i].next()
-    }
-
-    fn peek_prev(&mut self) -> Option<(&K, &V)> {
-        let highest = self.highest()?;
-        self.iters[highest].peek_prev()
-    }
-
-    fn prev(&mut self) -> Option<(&K, &V)> {
-        let i = self.highest()?;
-        self.iters[i].prev()
-    }
-
-    fn seek_ge(&mut self, key: &K) {
-        // TODO: in the case where we're just searching for a single key _ever_,
-        // we can optimize this by not looking at the lower levels.
-        for it in self.iters.iter_mut() {
-            it.seek_ge(key);
-        }
-    }
-
-    fn start(&mut self) {
-        for it in self.iters.iter_mut() {
-            it.start();
-        }
     }
 
-    fn end(&mut self) {
-        for it in self.iters.iter_mut() {
-            it.end();
-        }
+    #[test]
+    fn test_seqnum_iter_reuses_buffers() {
+        let entry = |k: &str, v: &str| ((Bytes(k.into()), 0, 1), DbValue::Put(Bytes(v.into())));
+        let data = vec![entry("a", "1"), entry("b", "2"), entry("c", "3")];
+        let mut iter = SeqnumIter::new(1, VecIter::new(Arc::new(data)));
+        let (k, v) = iter.next().unwrap();
+        let ptrs = (k.0.as_ptr(), v.0.as_ptr());
+        // Reading the next entry copies it into the same buffers.
+        iter.next().unwrap();
+        let (k, v) = iter.next().unwrap();
+        assert_eq!((k, v), (&Bytes("c".into()), &Bytes("3".into())));
+        assert_eq!((k.0.as_ptr(), v.0.as_ptr()), ptrs);
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::rc::Rc;
-
-    use crate::memtable::{KVIter, SeqnumIter, VecIter};
 
     #[test]
     fn test_seqnum_iter() {
@@ -520,16 +868,19 @@ mod tests {
                     for line in test_case.input.lines() {
                         let eq_idx = line.find('=').unwrap();
                         let at_idx = line.find('@').unwrap();
-                        let key = line[0..eq_idx].to_owned();
                         let val = line[eq_idx + 1..at_idx].to_owned();
                         let seqnum: usize = line[at_idx + 1..].parse().unwrap();
-                        if val == "<DELETE>" {
-                            data.push(((key, seqnum), None));
+                        // `key+=val` writes a merge operand.
+                        let (key, val) = if let Some(key) = line[0..eq_idx].strip_suffix('+') {
+                            (key.to_owned(), DbValue::Merge(val))
+                        } else if val == "<DELETE>" {
+                            (line[0..eq_idx].to_owned(), DbValue::Delete)
                         } else {
-                            data.push(((key, seqnum), Some(val)));
-                        }
+                            (line[0..eq_idx].to_owned(), DbValue::Put(val))
+                        };
+                        data.push(((key, 0, seqnum), val));
                     }
-                    data.sort();
+                    data.sort_by(|a, b| a.0.cmp(&b.0));
                     "ok\n".into()
                 }
                 "read" => {
@@ -537,11 +888,14 @@ mod tests {
                         .args
                         .get("ts")
                         .expect("read requires ts argument")
-                        .get(0)
+                        .first()
                         .unwrap()
                         .parse()
                         .unwrap();
-                    iter = Some(SeqnumIter::new(ts, VecIter::new(Rc::new(data.clone()))));
+                    iter = Some(
+                        SeqnumIter::new(ts, VecIter::new(Arc::new(data.clone())))
+                            .with_merge_operator(Some(Arc::new(ConcatOperator))),
+                    );
                     "ok\n".into()
                 }
                 "scan" => {
@@ -592,7 +946,7 @@ mod tests {
                         .args
                         .get("key")
                         .expect("seek-ge requires key argument")
-                        .get(0)
+                        .first()
                         .unwrap();
                     iter.as_mut().unwrap().seek_ge(key);
                     "ok\n".into()
@@ -605,7 +959,72 @@ mod tests {
     }
 }
 
-type DBEntry<K, V> = ((K, usize), Option<V>);
+type DBEntry<K, V> = ((K, u64, usize), DbValue<V>);
+
+// What we charge each entry on top of its encoded size: the in-memory tuple
+// itself, plus whatever structure it lives in.
+const ENTRY_OVERHEAD: usize = 64;
+
+// The data structure backing a memtable. Both implementations hold entries
+// keyed on `(key, timestamp, seqnum)`, and can be shared between threads,
+// which can insert into them at the same time.
+pub trait MemtableRep<K, V>: std::fmt::Debug + Send + Sync
+where
+    K: Ord,
+{
+    fn insert(&self, key: (K, u64, usize), v: DbValue<V>);
+    fn scan(&self) -> Box<dyn KVIter<(K, u64, usize), DbValue<V>>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemtableRepKind {
+    // A set of sorted slabs that are merged together as they grow. Cheap to
+    // snapshot, but inserts pay for the merges and reads have to consult up
+    // to log(n) slabs.
+    #[default]
+    Slab,
+    // A skiplist, which inserts only lock while splicing in the new node.
+    // Scans read it in place rather than from a snapshot, and don't block
+    // inserts.
+    SkipList,
+}
+
+impl MemtableRepKind {
+    pub fn build<K, V>(
+        self,
+        comparator: Arc<dyn Comparator<(K, u64, usize)>>,
+    ) -> Box<dyn MemtableRep<K, V>>
+    where
+        K: Ord + Clone + std::fmt::Debug + Send + Sync + 'static,
+        V: Clone + std::fmt::Debug + Send + Sync + 'static,
+    {
+        match self {
+            MemtableRepKind::Slab => Box::new(SlabRep::new(comparator)),
+            MemtableRepKind::SkipList => Box::new(SkipListRep {
+                list: Arc::new(SkipList::new().with_comparator(comparator)),
+            }),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SkipListRep<K, V> {
+    list: Arc<SkipList<(K, u64, usize), DbValue<V>>>,
+}
+
+impl<K, V> MemtableRep<K, V> for SkipListRep<K, V>
+where
+    K: Ord + Clone + std::fmt::Debug + Send + Sync + 'static,
+    V: std::fmt::Debug + Send + Sync + 'static,
+{
+    fn insert(&self, key: (K, u64, usize), v: DbValue<V>) {
+        self.list.insert(key, v);
+    }
+
+    fn scan(&self) -> Box<dyn KVIter<(K, u64, usize), DbValue<V>>> {
+        Box::new(SkipListIter::new(self.list.clone()))
+    }
+}
 
 #[derive(Debug)]
 pub struct Memtable<K, V>
@@ -613,107 +1032,143 @@ where
     K: Ord,
 {
     prev_seqnum: usize,
-    entries: Vec<Rc<Vec<DBEntry<K, V>>>>,
+    kind: MemtableRepKind,
+    comparator: Arc<dyn Comparator<K>>,
+    rep: Box<dyn MemtableRep<K, V>>,
+    // Kept apart from the point entries, since they're read in their
+    // entirety.
+    range_tombstones: Vec<RangeTombstone<K>>,
+    // An estimate of how much memory the entries are taking up.
+    approximate_bytes: usize,
 }
 
 impl<K, V> Memtable<K, V>
 where
-    K: Default + Ord + Clone + std::fmt::Debug + Encode + Decode,
-    V: Default + Clone + std::fmt::Debug + Encode,
+    K: Default + Ord + Clone + std::fmt::Debug + Encode + Decode + Send + Sync + 'static,
+    V: Default + Clone + std::fmt::Debug + Encode + Send + Sync + 'static,
 {
     pub fn new() -> Self {
+        Self::with_rep(MemtableRepKind::default())
+    }
+
+    pub fn with_rep(kind: MemtableRepKind) -> Self {
+        Self::with_rep_and_comparator(kind, Arc::new(OrdComparator))
+    }
+
+    pub fn with_rep_and_comparator(
+        kind: MemtableRepKind,
+        comparator: Arc<dyn Comparator<K>>,
+    ) -> Self {
+        let internal = InternalKeyComparator::wrap(comparator.clone());
         Memtable {
             prev_seqnum: 0,
-            entries: Vec::new(),
+            kind,
+            comparator,
+            rep: kind.build(internal),
+            range_tombstones: Vec::new(),
+            approximate_bytes: 0,
         }
     }
 
-    pub fn apply_command(&mut self, cmd: DBCommand<K, V>) {
+    // A new, empty memtable backed by the same kind of structure as this one,
+    // with the same comparator.
+    pub fn new_like(&self) -> Self {
+        Self::with_rep_and_comparator(self.kind, self.comparator.clone())
+    }
+
+    pub fn apply_command(&mut self, cmd: DBCommand<K, V>) -> anyhow::Result<()> {
+        self.apply_command_at(0, cmd)
+    }
+
+    // Applies `cmd`, with its point writes at timestamp `ts` unless it says
+    // otherwise. Range deletions don't have a timestamp.
+    fn apply_command_at(&mut self, ts: u64, cmd: DBCommand<K, V>) -> anyhow::Result<()> {
         match cmd {
             DBCommand::Write(seqnum, k, v) => {
-                self.insert(seqnum, k, v);
+                self.insert_val(seqnum, ts, k, DbValue::Put(v));
             }
             DBCommand::Delete(seqnum, k) => {
-                self.delete(seqnum, k);
+                self.insert_val(seqnum, ts, k, DbValue::Delete);
             }
-        }
-    }
-
-    // TODO: replace this with an iterator.
-    fn merge(lhs: Rc<Vec<DBEntry<K, V>>>, rhs: Rc<Vec<DBEntry<K, V>>>) -> Rc<Vec<DBEntry<K, V>>> {
-        let mut out = Vec::new();
-        let mut lhs = (*lhs).iter();
-        let mut rhs = (*rhs).iter();
-        let mut left = lhs.next();
-        let mut right = rhs.next();
-        loop {
-            match (left, right) {
-                (None, None) => {
-                    break;
-                }
-                (Some(l), None) => {
-                    out.push(l.clone());
-                    out.extend(lhs.cloned());
-                    break;
-                }
-                (None, Some(r)) => {
-                    out.push(r.clone());
-                    out.extend(rhs.cloned());
-                    break;
-                }
-                (Some(((k1, s1), v1)), Some(((k2, s2), v2))) => {
-                    if (k1, s1) < (k2, s2) {
-                        out.push(((k1.clone(), *s1), v1.clone()));
-                        left = lhs.next();
-                    } else {
-                        // In this case, k2 must be < k1, because by
-                        // construction a seqnum in a more-right slab must be
-                        // greater than any in a more-left slab.
-                        out.push(((k2.clone(), *s2), v2.clone()));
-                        right = rhs.next();
-                    }
+            DBCommand::Merge(seqnum, k, v) => {
+                self.insert_val(seqnum, ts, k, DbValue::Merge(v));
+            }
+            DBCommand::ExpiringWrite(seqnum, k, v, deadline) => {
+                self.insert_val(seqnum, ts, k, DbValue::ExpiringPut(v, deadline));
+            }
+            DBCommand::DeleteRange(seqnum, start, end) => {
+                self.delete_range(seqnum, start, end);
+            }
+            DBCommand::Batch(_, commands) => {
+                for command in commands {
+                    self.apply_command_at(ts, command)?;
                 }
             }
+            DBCommand::AtTimestamp(ts, command) => {
+                self.apply_command_at(ts, *command)?;
+            }
+            DBCommand::Family(cf, _) => {
+                bail!("command for column family {} given to a memtable", cf)
+            }
         }
+        Ok(())
+    }
 
-        Rc::new(out)
+    fn insert_val(&mut self, s: usize, ts: u64, k: K, v: DbValue<V>) {
+        self.prev_seqnum = s;
+        let mut kw = KeyWriter::new();
+        ((&k, ts, s), &v).write_bytes(&mut kw);
+        self.approximate_bytes += kw.buf.len() + ENTRY_OVERHEAD;
+        self.rep.insert((k, ts, s), v);
     }
 
-    fn maybe_fix_at(&mut self, idx: usize) {
-        if self.entries[idx].len() < self.entries[idx + 1].len() * 2 {
-            let lhs = self.entries[idx].clone();
-            let rhs = self.entries[idx + 1].clone();
-            let merged = Self::merge(lhs, rhs);
-            self.entries
-                .splice(idx..idx + 2, vec![merged])
-                .for_each(drop);
-        }
+    pub fn approximate_bytes(&self) -> usize {
+        self.approximate_bytes
     }
 
-    fn insert_val(&mut self, s: usize, k: K, v: Option<V>) {
-        self.prev_seqnum = s;
-        self.entries.push(Rc::new(vec![((k, s), v)]));
-        for i in (0..(self.entries.len() - 1)).rev() {
-            self.maybe_fix_at(i);
-        }
+    // The largest seqnum written to this memtable.
+    pub fn max_seqnum(&self) -> usize {
+        self.prev_seqnum
     }
 
     pub fn insert(&mut self, s: usize, k: K, v: V) {
-        self.insert_val(s, k, Some(v))
+        self.insert_val(s, 0, k, DbValue::Put(v))
     }
 
     pub fn delete(&mut self, s: usize, k: K) {
-        self.insert_val(s, k, None)
+        self.insert_val(s, 0, k, DbValue::Delete)
     }
 
-    pub fn scan(&self) -> impl KVIter<(K, usize), Option<V>> {
-        MergingIter::new(self.entries.iter().map(|e| VecIter::new(e.clone())))
+    pub fn delete_range(&mut self, s: usize, start: K, end: K) {
+        self.prev_seqnum = s;
+        let mut kw = KeyWriter::new();
+        ((&start, s), &end).write_bytes(&mut kw);
+        self.approximate_bytes += kw.buf.len() + ENTRY_OVERHEAD;
+        self.range_tombstones.push(RangeTombstone {
+            start,
+            end,
+            seqnum: s,
+        });
+    }
+
+    pub fn range_tombstones(&self) -> &[RangeTombstone<K>] {
+        &self.range_tombstones
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.range_tombstones.is_empty() && self.rep.scan().peek().is_none()
+    }
+
+    pub fn scan(&self) -> Box<dyn KVIter<(K, u64, usize), DbValue<V>>> {
+        self.rep.scan()
     }
 
     pub fn read_at(&self, seqnum: usize) -> impl KVIter<K, V> {
-        SeqnumIter::new(
-            seqnum,
-            MergingIter::new(self.entries.iter().map(|e| VecIter::new(e.clone()))),
-        )
+        SeqnumIter::new(seqnum, self.rep.scan())
+            .with_range_tombstones(Arc::new(RangeTombstones::new(
+                &*self.comparator,
+                &self.range_tombstones,
+            )))
+            .with_comparator(self.comparator.clone())
     }
 }
@@ -0,0 +1,343 @@
+// A skiplist which supports concurrent inserts and reads.
+//
+// Readers never lock: every link is an `AtomicPtr`, and a node is fully
+// initialized before it is published by the `Release` store which links it
+// into level 0. Writers are serialized by `write_lock`, which only protects
+// the splicing itself, so an insert holds it for O(log n) pointer hops.
+//
+// Level 0 is also linked backwards, and the list keeps a pointer to its last
+// node, so iterators can step back in O(1). Those links are updated after the
+// forward ones, so a reader going backwards may skip over a node that's just
+// been inserted, but it never sees one out of order.
+//
+// Nodes are never removed, so a pointer to a node stays valid for as long as
+// the list itself is alive. Iterators hold an `Arc` to the list, which is what
+// lets them hand out references into it.
+use std::{
+    cmp::Ordering as CmpOrdering,
+    marker::PhantomData,
+    ptr,
+    sync::{
+        atomic::{AtomicPtr, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use rand::Rng;
+
+use crate::comparator::{Comparator, OrdComparator};
+
+use super::KVIter;
+
+const MAX_HEIGHT: usize = 12;
+// Each level has 1/BRANCHING as many nodes as the one below it.
+const BRANCHING: u32 = 4;
+
+struct Node<K, V> {
+    key: K,
+    value: V,
+    next: Vec<AtomicPtr<Node<K, V>>>,
+    // The node before this one on level 0, or null if it's the first.
+    prev: AtomicPtr<Node<K, V>>,
+}
+
+pub struct SkipList<K, V> {
+    head: Vec<AtomicPtr<Node<K, V>>>,
+    // The last node, or null if the list is empty.
+    tail: AtomicPtr<Node<K, V>>,
+    height: AtomicUsize,
+    len: AtomicUsize,
+    write_lock: Mutex<()>,
+    comparator: Arc<dyn Comparator<K>>,
+    _marker: PhantomData<Box<Node<K, V>>>,
+}
+
+impl<K, V> SkipList<K, V>
+where
+    K: Ord + Clone,
+{
+    pub fn new() -> Self {
+        SkipList {
+            head: (0..MAX_HEIGHT)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+            tail: AtomicPtr::new(ptr::null_mut()),
+            height: AtomicUsize::new(1),
+            len: AtomicUsize::new(0),
+            write_lock: Mutex::new(()),
+            comparator: Arc::new(OrdComparator),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn with_comparator(mut self, comparator: Arc<dyn Comparator<K>>) -> Self {
+        self.comparator = comparator;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    // The links out of `node`, where a null `node` denotes the head.
+    fn tower(&self, node: *mut Node<K, V>) -> &[AtomicPtr<Node<K, V>>] {
+        if node.is_null() {
+            &self.head
+        } else {
+            // Safety: nodes live as long as the list.
+            unsafe { &(*node).next }
+        }
+    }
+
+    fn random_height() -> usize {
+        let mut rng = rand::thread_rng();
+        let mut height = 1;
+        while height < MAX_HEIGHT && rng.gen_ratio(1, BRANCHING) {
+            height += 1;
+        }
+        height
+    }
+
+    // Returns the first node whose key is >= `key` (or null if there is none).
+    // If `prev` is provided, it is filled in with the last node before that
+    // position on every level.
+    fn find_greater_or_equal(
+        &self,
+        key: &K,
+        mut prev: Option<&mut [*mut Node<K, V>; MAX_HEIGHT]>,
+    ) -> *mut Node<K, V> {
+        let mut x = ptr::null_mut();
+        let mut level = self.height.load(Ordering::Acquire) - 1;
+        loop {
+            let next = self.tower(x)[level].load(Ordering::Acquire);
+            // Safety: nodes live as long as the list.
+            if !next.is_null() && self.comparator.lt(unsafe { &(*next).key }, key) {
+                x = next;
+            } else {
+                if let Some(prev) = prev.as_mut() {
+                    prev[level] = x;
+                }
+                if level == 0 {
+                    return next;
+                }
+                level -= 1;
+            }
+        }
+    }
+
+    // Inserts `key`, unless it is already present, in which case this is a
+    // no-op. Safe to call from many threads at once.
+    pub fn insert(&self, key: K, value: V) {
+        let _guard = self.write_lock.lock().unwrap();
+
+        let mut prev = [ptr::null_mut(); MAX_HEIGHT];
+        let existing = self.find_greater_or_equal(&key, Some(&mut prev));
+        // Safety: nodes live as long as the list.
+        if !existing.is_null()
+            && self.comparator.compare(unsafe { &(*existing).key }, &key) == CmpOrdering::Equal
+        {
+            return;
+        }
+
+        let height = Self::random_height();
+        // Any levels above the current height have the head (null) as their
+        // predecessor, which is what `prev` was initialized to. Readers that
+        // see the new height before the new links just fall through from the
+        // head to the level below.
+        let current_height = self.height.load(Ordering::Acquire);
+        if height > current_height {
+            self.height.store(height, Ordering::Release);
+        }
+
+        let node = Box::into_raw(Box::new(Node {
+            key,
+            value,
+            next: (0..height)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+            prev: AtomicPtr::new(prev[0]),
+        }));
+
+        for (level, prev) in prev.iter().enumerate().take(height) {
+            let prev_tower = self.tower(*prev);
+            // Safety: `node` isn't visible to anyone else until the store
+            // below.
+            let new_tower = unsafe { &(*node).next };
+            new_tower[level].store(prev_tower[level].load(Ordering::Acquire), Ordering::Relaxed);
+            prev_tower[level].store(node, Ordering::Release);
+        }
+        // Now that the node can be reached going forwards, link it in going
+        // backwards.
+        if existing.is_null() {
+            self.tail.store(node, Ordering::Release);
+        } else {
+            // Safety: nodes live as long as the list.
+            unsafe { &(*existing).prev }.store(node, Ordering::Release);
+        }
+
+        self.len.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl<K, V> Default for SkipList<K, V>
+where
+    K: Ord + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Drop for SkipList<K, V> {
+    fn drop(&mut self) {
+        let mut x = self.head[0].load(Ordering::Acquire);
+        while !x.is_null() {
+            // Safety: we have exclusive access, and every node was allocated
+            // by `Box::into_raw` in `insert`.
+            let node = unsafe { Box::from_raw(x) };
+            x = node.next[0].load(Ordering::Acquire);
+        }
+    }
+}
+
+impl<K, V> std::fmt::Debug for SkipList<K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SkipList")
+            .field("len", &self.len.load(Ordering::Acquire))
+            .finish()
+    }
+}
+
+// An iterator over a skiplist. Entries inserted after the iterator was created
+// may or may not be observed by it.
+#[derive(Debug)]
+pub struct SkipListIter<K, V> {
+    list: Arc<SkipList<K, V>>,
+    // The node immediately to the right of the cursor, or null if the cursor
+    // is at the end.
+    next: *mut Node<K, V>,
+}
+
+impl<K, V> SkipListIter<K, V>
+where
+    K: Ord + Clone,
+{
+    pub fn new(list: Arc<SkipList<K, V>>) -> Self {
+        let next = list.head[0].load(Ordering::Acquire);
+        SkipListIter { list, next }
+    }
+
+    fn entry<'a>(node: *mut Node<K, V>) -> Option<(&'a K, &'a V)> {
+        if node.is_null() {
+            None
+        } else {
+            // Safety: the caller holds an `Arc` to the list, so the node is
+            // alive for as long as they borrow it.
+            let node = unsafe { &*node };
+            Some((&node.key, &node.value))
+        }
+    }
+
+    fn prev_node(&self) -> *mut Node<K, V> {
+        if self.next.is_null() {
+            self.list.tail.load(Ordering::Acquire)
+        } else {
+            // Safety: nodes live as long as the list.
+            unsafe { &(*self.next).prev }.load(Ordering::Acquire)
+        }
+    }
+}
+
+impl<K, V> KVIter<K, V> for SkipListIter<K, V>
+where
+    K: Ord + Clone,
+{
+    fn next(&mut self) -> Option<(&K, &V)> {
+        let node = self.next;
+        if !node.is_null() {
+            // Safety: nodes live as long as the list.
+            self.next = unsafe { &(*node).next }[0].load(Ordering::Acquire);
+        }
+        Self::entry(node)
+    }
+
+    fn peek(&mut self) -> Option<(&K, &V)> {
+        Self::entry(self.next)
+    }
+
+    fn prev(&mut self) -> Option<(&K, &V)> {
+        let node = self.prev_node();
+        if node.is_null() {
+            return None;
+        }
+        self.next = node;
+        Self::entry(node)
+    }
+
+    fn peek_prev(&mut self) -> Option<(&K, &V)> {
+        Self::entry(self.prev_node())
+    }
+
+    fn seek_ge(&mut self, key: &K) {
+        self.next = self.list.find_greater_or_equal(key, None);
+    }
+
+    fn start(&mut self) {
+        self.next = self.list.head[0].load(Ordering::Acquire);
+    }
+
+    fn end(&mut self) {
+        self.next = ptr::null_mut();
+    }
+}
+
+#[test]
+fn test_concurrent_inserts() {
+    let list = Arc::new(SkipList::new());
+    let threads: Vec<_> = (0..4)
+        .map(|t| {
+            let list = list.clone();
+            std::thread::spawn(move || {
+                for i in 0..250 {
+                    list.insert(i * 4 + t, i);
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(list.len(), 1000);
+
+    let mut iter = SkipListIter::new(list.clone());
+    for expected in 0..1000 {
+        assert_eq!(iter.next().map(|(k, _)| *k), Some(expected));
+    }
+    assert_eq!(iter.next(), None);
+    for expected in (0..1000).rev() {
+        assert_eq!(iter.prev().map(|(k, _)| *k), Some(expected));
+    }
+    assert_eq!(iter.prev(), None);
+
+    iter.seek_ge(&500);
+    assert_eq!(iter.peek_prev().map(|(k, _)| *k), Some(499));
+    assert_eq!(iter.next().map(|(k, _)| *k), Some(500));
+
+    // Duplicate keys are ignored.
+    list.insert(10, 0);
+    assert_eq!(list.len(), 1000);
+
+    // Stepping back picks up nodes inserted at the front, middle and end.
+    list.insert(-1, 0);
+    list.insert(1000, 0);
+    iter.end();
+    assert_eq!(iter.prev().map(|(k, _)| *k), Some(1000));
+    for k in [-1, 1000] {
+        iter.seek_ge(&k);
+        iter.next();
+        assert_eq!(iter.prev().map(|(k, _)| *k), Some(k));
+    }
+    iter.seek_ge(&0);
+    assert_eq!(iter.prev().map(|(k, _)| *k), Some(-1));
+    assert_eq!(iter.prev(), None);
+}
@@ -0,0 +1,122 @@
+use std::sync::{Arc, Mutex};
+
+use crate::comparator::Comparator;
+
+use super::{DBEntry, DbValue, KVIter, MemtableRep, MergingIter, VecIter};
+
+type Slab<K, V> = Arc<Vec<DBEntry<K, V>>>;
+
+// Entries are kept in a sequence of sorted slabs. Each insert appends a new
+// one-element slab, and then adjacent slabs are merged until each is at least
+// twice the size of the one after it, so there are only ever O(log n) of them.
+// Inserts hold the lock for the whole of that merging, while scans only hold
+// it long enough to take a reference to each slab.
+pub struct SlabRep<K, V>
+where
+    K: Ord,
+{
+    entries: Mutex<Vec<Slab<K, V>>>,
+    comparator: Arc<dyn Comparator<(K, u64, usize)>>,
+}
+
+impl<K, V> std::fmt::Debug for SlabRep<K, V>
+where
+    K: Ord + std::fmt::Debug,
+    V: std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SlabRep")
+            .field("entries", &*self.entries.lock().unwrap())
+            .field("comparator", &self.comparator)
+            .finish()
+    }
+}
+
+impl<K, V> SlabRep<K, V>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn new(comparator: Arc<dyn Comparator<(K, u64, usize)>>) -> Self {
+        SlabRep {
+            entries: Mutex::new(Vec::new()),
+            comparator,
+        }
+    }
+
+    // TODO: replace this with an iterator.
+    fn merge(&self, lhs: Slab<K, V>, rhs: Slab<K, V>) -> Slab<K, V> {
+        let mut out = Vec::new();
+        let mut lhs = (*lhs).iter();
+        let mut rhs = (*rhs).iter();
+        let mut left = lhs.next();
+        let mut right = rhs.next();
+        loop {
+            match (left, right) {
+                (None, None) => {
+                    break;
+                }
+                (Some(l), None) => {
+                    out.push(l.clone());
+                    out.extend(lhs.cloned());
+                    break;
+                }
+                (None, Some(r)) => {
+                    out.push(r.clone());
+                    out.extend(rhs.cloned());
+                    break;
+                }
+                (Some((k1, v1)), Some((k2, v2))) => {
+                    if self.comparator.lt(k1, k2) {
+                        out.push((k1.clone(), v1.clone()));
+                        left = lhs.next();
+                    } else {
+                        // In this case, k2 must be < k1, because by
+                        // construction a seqnum in a more-right slab must be
+                        // greater than any in a more-left slab.
+                        out.push((k2.clone(), v2.clone()));
+                        right = rhs.next();
+                    }
+                }
+            }
+        }
+
+        Arc::new(out)
+    }
+
+    fn maybe_fix_at(&self, entries: &mut Vec<Slab<K, V>>, idx: usize) {
+        if entries[idx].len() < entries[idx + 1].len() * 2 {
+            let lhs = entries[idx].clone();
+            let rhs = entries[idx + 1].clone();
+            let merged = self.merge(lhs, rhs);
+            entries.splice(idx..idx + 2, vec![merged]).for_each(drop);
+        }
+    }
+}
+
+impl<K, V> MemtableRep<K, V> for SlabRep<K, V>
+where
+    K: Ord + Clone + std::fmt::Debug + Send + Sync + 'static,
+    V: Clone + std::fmt::Debug + Send + Sync + 'static,
+{
+    fn insert(&self, key: (K, u64, usize), v: DbValue<V>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.push(Arc::new(vec![(key, v)]));
+        for i in (0..(entries.len() - 1)).rev() {
+            self.maybe_fix_at(&mut entries, i);
+        }
+    }
+
+    fn scan(&self) -> Box<dyn KVIter<(K, u64, usize), DbValue<V>>> {
+        Box::new(
+            MergingIter::new(
+                self.entries
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|e| VecIter::new(e.clone()).with_comparator(self.comparator.clone())),
+            )
+            .with_comparator(self.comparator.clone()),
+        )
+    }
+}
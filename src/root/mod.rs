@@ -1,57 +1,325 @@
-use serde::{de::DeserializeOwned, Serialize};
+// The database's metadata is kept in a manifest: a log of the edits made to
+// it, each batch framed with its length and a checksum. A manifest starts with
+// a snapshot of the whole state, so once one grows too large it's replaced by
+// a new one with just a snapshot in it. CURRENT names the manifest in use.
+use std::io::{Seek, SeekFrom};
+
+use anyhow::{anyhow, bail};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::fs::{DbDir, DbFile};
 
+const CURRENT: &str = "CURRENT";
+const TMP_CURRENT: &str = "TMP_CURRENT";
+// Before there was a manifest, the whole state was rewritten to this file on
+// every change.
+const LEGACY_ROOT: &str = "ROOT";
+
+// State which is built up by applying edits to it, starting from the default.
+pub trait Versioned: Serialize + DeserializeOwned + Default + Clone {
+    type Edit: Serialize + DeserializeOwned + std::fmt::Debug;
+
+    // Fails if `edit` doesn't make sense for the current state, e.g. if it
+    // refers to something that doesn't exist.
+    fn apply(&mut self, edit: Self::Edit) -> anyhow::Result<()>;
+}
+
+#[derive(Serialize, Deserialize)]
+enum Record<T, E> {
+    Snapshot(T),
+    Edits(Vec<E>),
+}
+
+fn manifest_name(number: usize) -> String {
+    format!("MANIFEST-{}", number)
+}
+
+// Each record is its length, then the checksum of its payload, then the
+// payload itself.
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(payload.len() + 8);
+    buf.extend((payload.len() as u32).to_le_bytes());
+    buf.extend(checksum(payload).to_le_bytes());
+    buf.extend(payload);
+    buf
+}
+
+fn checksum(payload: &[u8]) -> u32 {
+    crc32fast::hash(payload)
+}
+
+// Rebuilds the state a manifest describes. Also returns whether the manifest
+// ended with a torn record, which was left by a crash part way through
+// appending it (and so was never acknowledged).
+fn replay<T: Versioned>(buf: &[u8]) -> anyhow::Result<(T, bool)> {
+    let mut data: Option<T> = None;
+    let mut pos = 0;
+    while pos < buf.len() {
+        let header = buf.get(pos..pos + 8);
+        let len = header.map(|h| u32::from_le_bytes(h[..4].try_into().unwrap()) as usize);
+        let Some(payload) = len.and_then(|len| buf.get(pos + 8..pos + 8 + len)) else {
+            return Ok((
+                data.ok_or_else(|| anyhow!("manifest has no snapshot"))?,
+                true,
+            ));
+        };
+        let expected = u32::from_le_bytes(buf[pos + 4..pos + 8].try_into().unwrap());
+        pos += 8 + payload.len();
+        if checksum(payload) != expected {
+            if pos == buf.len() {
+                return Ok((
+                    data.ok_or_else(|| anyhow!("manifest has no snapshot"))?,
+                    true,
+                ));
+            }
+            bail!("manifest record ending at offset {} is corrupt", pos);
+        }
+        match (serde_json::from_slice(payload)?, &mut data) {
+            (Record::Snapshot(snapshot), None) => data = Some(snapshot),
+            (Record::Edits(edits), Some(data)) => {
+                for edit in edits {
+                    data.apply(edit)?;
+                }
+            }
+            (Record::Snapshot(_), Some(_)) => bail!("manifest has a second snapshot"),
+            (Record::Edits(_), None) => bail!("manifest doesn't start with a snapshot"),
+        }
+    }
+    Ok((data.ok_or_else(|| anyhow!("manifest is empty"))?, false))
+}
+
+// Whether `name` is one of the files that make up a manifest.
+pub fn is_manifest_file(name: &str) -> bool {
+    [CURRENT, TMP_CURRENT, LEGACY_ROOT].contains(&name)
+        || name
+            .strip_prefix("MANIFEST-")
+            .is_some_and(|n| n.parse::<usize>().is_ok())
+}
+
 pub struct Root<T, D>
 where
-    T: Serialize + DeserializeOwned + Default,
+    T: Versioned,
     D: DbDir,
 {
     dir: D,
     pub(crate) data: T,
+    manifest: D::DbFile,
+    manifest_number: usize,
+    // Once the manifest is larger than this, it's rolled over.
+    max_manifest_size: usize,
 }
 
 impl<T, D> Root<T, D>
 where
-    T: Serialize + DeserializeOwned + Default,
+    T: Versioned,
     D: DbDir,
 {
-    pub fn load(mut dir: D) -> anyhow::Result<Self> {
-        match dir.open(&"ROOT") {
-            Some(f) => Ok(Self {
+    pub fn load(dir: D) -> anyhow::Result<Self> {
+        Self::load_or_migrate(dir, |buf| Ok(serde_json::from_slice(buf)?))
+    }
+
+    // Like `load`, but if there's only a legacy root, `migrate` turns its
+    // contents into the state to start the manifest with. The legacy root is
+    // removed once that's written.
+    pub fn load_or_migrate<F>(mut dir: D, migrate: F) -> anyhow::Result<Self>
+    where
+        F: FnOnce(&[u8]) -> anyhow::Result<T>,
+    {
+        let Some(current) = dir.open(&CURRENT) else {
+            // Didn't exist, so start from the legacy root if there is one, or
+            // from default values.
+            let data = match dir.open(&LEGACY_ROOT) {
+                Some(f) => migrate(&f.read_all())?,
+                None => T::default(),
+            };
+            let manifest = write_manifest(&mut dir, 0, &data)?;
+            dir.unlink(&LEGACY_ROOT)?;
+            return Ok(Self {
                 dir,
-                data: serde_json::from_slice(&f.read_all())?,
-            }),
-            None => {
-                // Didn't exist, so create it with default values.
-                let data = T::default();
-                let mut result = Self { dir, data };
-                result.write(T::default())?;
-                Ok(result)
-            }
+                data,
+                manifest,
+                manifest_number: 0,
+                max_manifest_size: usize::MAX,
+            });
+        };
+
+        let name = String::from_utf8(current.read_all())?;
+        let manifest_number = name
+            .strip_prefix("MANIFEST-")
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| anyhow!("CURRENT names {:?}, which isn't a manifest", name))?;
+        let mut manifest = dir
+            .open(&name)
+            .ok_or_else(|| anyhow!("CURRENT names {}, which doesn't exist", name))?;
+        let (data, torn) = replay(&manifest.read_all())?;
+        manifest.seek(SeekFrom::End(0))?;
+        let mut root = Self {
+            dir,
+            data,
+            manifest,
+            manifest_number,
+            max_manifest_size: usize::MAX,
+        };
+        // Don't append after a torn record.
+        if torn {
+            root.roll()?;
         }
+        Ok(root)
     }
 
-    pub fn write(&mut self, t: T) -> anyhow::Result<()> {
-        self.dir.unlink(&"TMP_ROOT")?;
-        let mut file = self.dir.create(&"TMP_ROOT")?.unwrap();
-        let encoded = serde_json::to_string(&t)?;
-        file.write(encoded.as_bytes())?;
-        file.sync()?;
+    // Whether there's a database in `dir` already.
+    pub fn exists(dir: &mut D) -> bool {
+        dir.open(&CURRENT).is_some() || dir.open(&LEGACY_ROOT).is_some()
+    }
+
+    // Writes a manifest holding the current state into `target`, to go with
+    // copies of the files it refers to. Returns the names of the files it
+    // wrote, in the order they were written.
+    pub fn copy_to(&self, target: &mut D) -> anyhow::Result<Vec<String>> {
+        write_manifest(target, 0, &self.data)?;
+        Ok(vec![manifest_name(0), CURRENT.to_owned()])
+    }
 
-        self.dir.rename(&"TMP_ROOT", &"ROOT")?;
+    pub fn with_max_manifest_size(mut self, max_manifest_size: usize) -> Self {
+        self.max_manifest_size = max_manifest_size;
+        self
+    }
 
-        self.data = t;
+    // Durably applies `edits`, all at once, to the state.
+    pub fn edit(&mut self, edits: Vec<T::Edit>) -> anyhow::Result<()> {
+        if edits.is_empty() {
+            return Ok(());
+        }
+        let payload = serde_json::to_vec(&Record::<&T, &T::Edit>::Edits(edits.iter().collect()))?;
+        // Edits that don't apply are refused before they reach the manifest,
+        // where they'd stop it from being loaded again.
+        let mut data = self.data.clone();
+        for edit in edits {
+            data.apply(edit)?;
+        }
+        self.manifest.write(&frame(&payload))?;
+        self.manifest.sync()?;
+        self.data = data;
 
+        if self.manifest.len() > self.max_manifest_size {
+            self.roll()?;
+        }
         Ok(())
     }
 
-    pub fn transform<F>(&mut self, f: F) -> anyhow::Result<()>
-    where
-        F: FnOnce(T) -> T,
-    {
-        let new_data = f(std::mem::take(&mut self.data));
-        self.write(new_data)?;
+    // Replaces the manifest with a new one that only holds a snapshot of the
+    // current state.
+    fn roll(&mut self) -> anyhow::Result<()> {
+        let next = self.manifest_number + 1;
+        self.manifest = write_manifest(&mut self.dir, next, &self.data)?;
+        self.dir.unlink(&manifest_name(self.manifest_number))?;
+        self.manifest_number = next;
         Ok(())
     }
 }
+
+// Writes out a manifest holding a snapshot of `data`, and points CURRENT at
+// it.
+fn write_manifest<T, D>(dir: &mut D, number: usize, data: &T) -> anyhow::Result<D::DbFile>
+where
+    T: Versioned,
+    D: DbDir,
+{
+    let name = manifest_name(number);
+    dir.unlink(&name)?;
+    let mut file = dir.create(&name)?.expect("manifest already existed");
+    let payload = serde_json::to_vec(&Record::<&T, &T::Edit>::Snapshot(data))?;
+    file.write(&frame(&payload))?;
+    file.sync()?;
+
+    dir.unlink(&TMP_CURRENT)?;
+    let mut current = dir.create(&TMP_CURRENT)?.unwrap();
+    current.write(name.as_bytes())?;
+    current.sync()?;
+    dir.rename(&TMP_CURRENT, &CURRENT)?;
+
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Seek, SeekFrom};
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::fs::{DbDir, DbFile, MockDir};
+
+    use super::{Root, Versioned};
+
+    #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+    struct Names(Vec<String>);
+
+    impl Versioned for Names {
+        type Edit = String;
+
+        fn apply(&mut self, edit: String) -> anyhow::Result<()> {
+            if edit.is_empty() {
+                anyhow::bail!("names can't be empty");
+            }
+            self.0.push(edit);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_manifest() {
+        let mut dir = MockDir::new();
+        let mut root: Root<Names, _> = Root::load(dir.clone()).unwrap().with_max_manifest_size(100);
+        root.edit(vec!["a".into(), "b".into()]).unwrap();
+        assert!(dir.open(&"MANIFEST-0").is_some());
+
+        // Once it's too big, the manifest is replaced.
+        for i in 0..10 {
+            root.edit(vec![i.to_string()]).unwrap();
+        }
+        assert!(dir.open(&"MANIFEST-0").is_none());
+        let manifest = String::from_utf8(dir.open(&"CURRENT").unwrap().read_all()).unwrap();
+        assert_ne!(manifest, "MANIFEST-0");
+        let expected = root.data.0.clone();
+        assert_eq!(expected.len(), 12);
+
+        // A record that was only partly written is ignored.
+        let mut f = dir.open(&manifest).unwrap();
+        let len = f.len();
+        f.seek(SeekFrom::Start(len as u64)).unwrap();
+        f.write(&[0x20, 0, 0, 0, 1, 2]).unwrap();
+        let mut root: Root<Names, _> = Root::load(dir.clone()).unwrap();
+        assert_eq!(root.data.0, expected);
+        root.edit(vec!["c".into()]).unwrap();
+        let mut root: Root<Names, _> = Root::load(dir.clone()).unwrap();
+        assert_eq!(root.data.0.last().unwrap(), "c");
+
+        // Edits that don't apply aren't recorded, even alongside ones that do.
+        assert!(root.edit(vec!["d".into(), "".into()]).is_err());
+        assert_eq!(root.data.0.last().unwrap(), "c");
+        let root: Root<Names, _> = Root::load(dir.clone()).unwrap();
+        assert_eq!(root.data.0.last().unwrap(), "c");
+
+        // Corruption anywhere else is an error.
+        let manifest = String::from_utf8(dir.open(&"CURRENT").unwrap().read_all()).unwrap();
+        let mut f = dir.open(&manifest).unwrap();
+        f.seek(SeekFrom::Start(10)).unwrap();
+        f.write(b"X").unwrap();
+        assert!(Root::<Names, _>::load(dir.clone()).is_err());
+    }
+
+    #[test]
+    fn test_legacy_root() {
+        let mut dir = MockDir::new();
+        dir.create(&"ROOT")
+            .unwrap()
+            .unwrap()
+            .write(br#"["x","y"]"#)
+            .unwrap();
+        let root: Root<Names, _> = Root::load(dir.clone()).unwrap();
+        assert_eq!(root.data.0, vec!["x", "y"]);
+        assert!(dir.open(&"ROOT").is_none());
+        let root: Root<Names, _> = Root::load(dir).unwrap();
+        assert_eq!(root.data.0, vec!["x", "y"]);
+    }
+}
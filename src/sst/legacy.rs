@@ -0,0 +1,137 @@
+// Reads SSTs in the format from before checksums and the format version were
+// added, so that they can be rewritten in the current one. Such a file is:
+// * the data blocks, each a run of prefix-compressed entries, where the first
+//   entry in a block shares no prefix with the one before it,
+// * the index block,
+// * the min and max keys, prefix-compressed the same way,
+// * the length of the index block, as a u32, and
+// * the length of everything after the index block (including the previous
+//   field, but not this one), as a u32.
+// The integers are little-endian.
+use anyhow::{anyhow, bail};
+
+use crate::{
+    encoding::{Decode, KeyReader},
+    fs::DbFile,
+};
+
+// Decodes every entry in the legacy SST `file`, in order.
+pub fn read_entries<K, V, D>(file: &D) -> anyhow::Result<Vec<(K, V)>>
+where
+    K: Decode + std::fmt::Debug,
+    V: Decode + std::fmt::Debug,
+    D: DbFile,
+{
+    let buf = file.read_all();
+    let u32_at = |pos: usize| -> anyhow::Result<usize> {
+        let bytes = buf
+            .get(pos..pos + 4)
+            .ok_or_else(|| anyhow!("legacy sst is too short"))?;
+        Ok(u32::from_le_bytes(bytes.try_into()?) as usize)
+    };
+    if buf.len() < 8 {
+        bail!("legacy sst is too short to hold its metadata length");
+    }
+    let meta_len = u32_at(buf.len() - 4)?;
+    let index_len = u32_at(buf.len() - 8)?;
+    let data_len = (buf.len() - 4)
+        .checked_sub(meta_len)
+        .and_then(|n| n.checked_sub(index_len))
+        .ok_or_else(|| anyhow!("legacy sst metadata length {} is invalid", meta_len))?;
+
+    let mut entries = Vec::new();
+    let mut key = Vec::new();
+    let mut pos = 0;
+    while pos < data_len {
+        let len = u32_at(pos)?;
+        let prefix = u32_at(pos + 4)?;
+        let suffix = buf
+            .get(pos + 8..pos + 8 + len)
+            .filter(|_| prefix <= key.len() && pos + 8 + len <= data_len)
+            .ok_or_else(|| anyhow!("legacy sst entry at offset {} is corrupt", pos))?;
+        key.truncate(prefix);
+        key.extend(suffix);
+        let mut kr = KeyReader::new();
+        kr.load(&key);
+        entries.push(<(K, V)>::decode(&mut kr)?);
+        pos += 8 + len;
+    }
+    Ok(entries)
+}
+
+// Writes `entries` out the way SSTs used to be, two to a block.
+#[cfg(test)]
+pub fn write_entries<K, V, D>(file: &mut D, entries: &[(K, V)]) -> anyhow::Result<()>
+where
+    K: crate::encoding::Encode + Clone,
+    V: crate::encoding::Encode,
+    D: DbFile,
+{
+    use crate::encoding::{Encode, KeyWriter};
+
+    // Each chunk of entries is prefix-compressed against the one before it,
+    // starting from nothing.
+    fn compress<T: Encode>(items: &[T]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut prev: Vec<u8> = Vec::new();
+        for item in items {
+            let mut kw = KeyWriter::new();
+            item.write_bytes(&mut kw);
+            let buf = kw.buf;
+            let shared = prev.iter().zip(&buf).take_while(|(a, b)| a == b).count();
+            out.extend(((buf.len() - shared) as u32).to_le_bytes());
+            out.extend((shared as u32).to_le_bytes());
+            out.extend(&buf[shared..]);
+            prev = buf;
+        }
+        out
+    }
+
+    let mut offset = 0;
+    let mut index = Vec::new();
+    for block in entries.chunks(2) {
+        let data = compress(block);
+        file.write(&data)?;
+        index.push((block[0].0.clone(), (offset as u32, data.len() as u32)));
+        offset += data.len();
+    }
+    let index = compress(&index);
+    file.write(&index)?;
+    let bounds = compress(&[entries[0].0.clone(), entries[entries.len() - 1].0.clone()]);
+    file.write(&bounds)?;
+    file.write(&(index.len() as u32).to_le_bytes())?;
+    file.write(&((bounds.len() + 4) as u32).to_le_bytes())?;
+    file.sync()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fs::{DbDir, DbFile, MockDir};
+
+    use super::{read_entries, write_entries};
+
+    #[test]
+    fn test_read_legacy_sst() {
+        let entries: Vec<_> = (0..5)
+            .map(|i| {
+                (
+                    (format!("key{}", i), i),
+                    (i % 2 == 0).then(|| i.to_string()),
+                )
+            })
+            .collect();
+        let mut dir = MockDir::new();
+        let mut file = dir.create(&"legacy.sst").unwrap().unwrap();
+        write_entries(&mut file, &entries).unwrap();
+        let file = dir.open(&"legacy.sst").unwrap();
+        assert_eq!(
+            read_entries::<(String, usize), Option<String>, _>(&file).unwrap(),
+            entries
+        );
+
+        let mut dir = MockDir::new();
+        let mut file = dir.create(&"short.sst").unwrap().unwrap();
+        file.write(&[1, 0, 0, 0]).unwrap();
+        assert!(read_entries::<(String, usize), Option<String>, _>(&file).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+pub mod legacy;
 pub mod reader;
 pub mod writer;
 
@@ -19,11 +20,88 @@ pub mod writer;
 // hierarchical scheme) are low-cost.
 //
 // At the end of an SST, the _index block_ is written, which is another sequence
-// of key-value pairs, where the keys are the first key in each block, and the
-// values are the offset of that block from the start of the file.
+// of key-value pairs, with one for each block. The key is at or after the
+// block's last key and before the next block's first one (as short as the
+// comparator can make it), and the value is the offset and length of the
+// block.
 //
-// Finally, after all the data blocks and the index block, metadata about the SST is written.
+// After that comes the _range deletion block_, which holds the range
+// tombstones in the SST as (start, end) pairs. It's small enough that it's
+// always read in its entirety.
+//
+// Finally, after all the data blocks, the index block and the range deletion
+// block, metadata about the SST is written.
 // At time of writiing, that metadata is:
-// * the minimum key in the block,
-// * the maximum key in the block, and
-// * the length of the index block (which is needed to parse the SST).
+// * the minimum key in the block (or range tombstone),
+// * the maximum key in the block (or range tombstone),
+// * the id and name of the column family the SST belongs to, if it was given
+//   one,
+// * the length of the index block (which is needed to parse the SST),
+// * the length of the range deletion block,
+// * the length of the column family, and
+// * the format version, which is bumped whenever any of this changes.
+// It's followed by its length, and then the file ends with a magic number.
+// SSTs from before checksums were added don't have one, and can't be read
+// by `SstReader`, but `legacy` can decode them so that they can be rewritten.
+//
+// Keys in the data and index blocks are written in a `KeyFormat`, which the
+// reader has to be given.
+
+use crate::encoding::{Decode, Encode, KeyReader, KeyWriter};
+
+// The version of the layout described above that's written, and the only one
+// that can be read.
+const FORMAT_VERSION: u32 = 2;
+
+const MAGIC: u64 = 0x7373_745f_6c73_6d31;
+
+// How the keys in an SST's data and index blocks are written. Usually that's
+// just their encoding, but a format can leave out parts of them which are
+// always the same, and fill them back in when reading. The bounds and range
+// tombstones in the metadata are always written in full.
+pub trait KeyFormat<K>: std::fmt::Debug + Send + Sync {
+    fn write(&self, k: &K, kw: &mut KeyWriter);
+    // Whether what `write` wrote needs a separator before whatever follows.
+    fn needs_delimiter(&self, k: &K) -> bool;
+    fn read(&self, kr: &mut KeyReader) -> anyhow::Result<K>;
+}
+
+// Writes keys with their `Encode` impl.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Encoded;
+
+impl<K: Encode + Decode> KeyFormat<K> for Encoded {
+    fn write(&self, k: &K, kw: &mut KeyWriter) {
+        k.write_bytes(kw)
+    }
+
+    fn needs_delimiter(&self, k: &K) -> bool {
+        k.needs_delimiter()
+    }
+
+    fn read(&self, kr: &mut KeyReader) -> anyhow::Result<K> {
+        K::decode(kr)
+    }
+}
+
+// A key, to be written in some format.
+struct Formatted<'a, K> {
+    k: &'a K,
+    format: &'a dyn KeyFormat<K>,
+}
+
+impl<K: std::fmt::Debug> std::fmt::Debug for Formatted<'_, K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.k.fmt(f)
+    }
+}
+
+impl<K: std::fmt::Debug> Encode for Formatted<'_, K> {
+    fn write_bytes(&self, kw: &mut KeyWriter) {
+        self.format.write(self.k, kw)
+    }
+
+    fn needs_delimiter(&self) -> bool {
+        self.format.needs_delimiter(self.k)
+    }
+}
@@ -1,15 +1,22 @@
 #![allow(dead_code)]
 use std::{
+    cmp::Ordering,
     io::{Cursor, Read, Seek, SeekFrom},
     marker::PhantomData,
+    sync::Arc,
 };
 
+use anyhow::{anyhow, bail};
+
 use crate::{
-    encoding::{Decode, KeyReader},
+    comparator::{Comparator, OrdComparator},
+    encoding::{Bytes, Decode, Encode, KeyReader},
     fs::{DbDir, DbFile},
     memtable::KVIter,
 };
 
+use super::{Encoded, KeyFormat, FORMAT_VERSION, MAGIC};
+
 struct Reader<T: Decode, R: Seek + Read> {
     r: R,
     data_len: u64,
@@ -79,6 +86,20 @@ where
     }
 }
 
+// Reads the `len` bytes at `loc`, and checks them against the checksum that
+// follows them.
+fn read_checked<R: Read + Seek>(r: &mut R, loc: usize, len: usize) -> anyhow::Result<Vec<u8>> {
+    r.seek(SeekFrom::Start(loc as u64))?;
+    let mut buf = vec![0; len + 4];
+    r.read_exact(&mut buf)?;
+    let expected = u32::from_le_bytes(buf[len..].try_into()?);
+    buf.truncate(len);
+    if crc32fast::hash(&buf) != expected {
+        bail!("sst block at offset {} is corrupt", loc);
+    }
+    Ok(buf)
+}
+
 #[derive(Debug)]
 struct Block<K, V> {
     buf: Vec<u8>,
@@ -103,12 +124,16 @@ where
         }
     }
 
-    fn seek_ge(&mut self, seek_key: &K) {
-        self.idx = self.data.partition_point(|(k, _v)| k < seek_key);
+    fn seek_ge(&mut self, comparator: &dyn Comparator<K>, seek_key: &K) {
+        self.idx = self
+            .data
+            .partition_point(|(k, _v)| comparator.lt(k, seek_key));
     }
 
-    fn seek_gt(&mut self, seek_key: &K) {
-        self.idx = self.data.partition_point(|(k, _v)| k <= seek_key);
+    fn seek_gt(&mut self, comparator: &dyn Comparator<K>, seek_key: &K) {
+        self.idx = self
+            .data
+            .partition_point(|(k, _v)| comparator.le(k, seek_key));
     }
 
     fn align_end(&mut self) {
@@ -157,7 +182,14 @@ where
         }
     }
 
-    fn load<R: Read>(&mut self, data: &mut R, mut n: u32) -> anyhow::Result<()> {
+    // Loads the `n` bytes of entries in `data`, reading each key with
+    // `read_key`.
+    fn load<R: Read>(
+        &mut self,
+        data: &mut R,
+        mut n: u32,
+        read_key: &dyn Fn(&mut KeyReader) -> anyhow::Result<K>,
+    ) -> anyhow::Result<()> {
         self.data.clear();
         self.scratch.clear();
         self.buf.clear();
@@ -177,7 +209,8 @@ where
 
             let mut kr = KeyReader::new();
             kr.load(&self.buf);
-            self.data.push(<(K, V)>::decode(&mut kr)?);
+            let k = read_key(&mut kr)?;
+            self.data.push((k, V::decode(&mut kr)?));
 
             n -= len + 8;
         }
@@ -185,14 +218,16 @@ where
     }
 }
 
+// What's in an SST's footer. This is also what `SstWriter::write` returns.
 #[derive(Debug)]
-pub struct SstMeta<K>
-where
-    K: Decode,
-{
+pub struct SstMeta<K> {
     pub min_key: K,
     pub max_key: K,
     pub num_bytes: usize,
+    // (start, end) pairs, ordered by start.
+    pub range_tombstones: Vec<(K, K)>,
+    // The id and name of the column family, if the writer was given them.
+    pub column_family: Option<(usize, String)>,
 }
 
 #[derive(Debug)]
@@ -207,6 +242,10 @@ where
     index_block: Block<K, (u32, u32)>,
     current_block: Block<K, V>,
     state: ReaderState,
+    // Why a block couldn't be read. Iteration stops until it's taken.
+    error: Option<anyhow::Error>,
+    comparator: Arc<dyn Comparator<K>>,
+    key_format: Arc<dyn KeyFormat<K>>,
     pub sst_meta: SstMeta<K>,
     _marker: PhantomData<(K, V)>,
 }
@@ -224,12 +263,15 @@ where
     D: DbDir,
 {
     fn next(&mut self) -> Option<(&K, &V)> {
+        if self.error.is_some() {
+            return None;
+        }
         if self.current_block.peek().is_some() {
             self.current_block.next()
         } else {
             match self.state {
                 ReaderState::RightOfLoadedBlock => {
-                    if !self.next_block().unwrap() {
+                    if !self.load_next_block() {
                         self.state = ReaderState::LeftOfLoadedBlock;
                         None
                     } else {
@@ -238,7 +280,7 @@ where
                     }
                 }
                 ReaderState::LeftOfLoadedBlock => {
-                    if !self.next_block().unwrap() || !self.next_block().unwrap() {
+                    if !self.load_next_block() || !self.load_next_block() {
                         self.state = ReaderState::LeftOfLoadedBlock;
                         None
                     } else {
@@ -251,12 +293,15 @@ where
     }
 
     fn peek(&mut self) -> Option<(&K, &V)> {
+        if self.error.is_some() {
+            return None;
+        }
         if self.current_block.peek().is_some() {
             self.current_block.peek()
         } else {
             match self.state {
                 ReaderState::RightOfLoadedBlock => {
-                    if !self.next_block().unwrap() {
+                    if !self.load_next_block() {
                         self.state = ReaderState::LeftOfLoadedBlock;
                         None
                     } else {
@@ -265,7 +310,7 @@ where
                     }
                 }
                 ReaderState::LeftOfLoadedBlock => {
-                    if !self.next_block().unwrap() || !self.next_block().unwrap() {
+                    if !self.load_next_block() || !self.load_next_block() {
                         self.state = ReaderState::LeftOfLoadedBlock;
                         None
                     } else {
@@ -278,12 +323,15 @@ where
     }
 
     fn prev(&mut self) -> Option<(&K, &V)> {
+        if self.error.is_some() {
+            return None;
+        }
         if self.current_block.peek_prev().is_some() {
             self.current_block.prev()
         } else {
             match self.state {
                 ReaderState::LeftOfLoadedBlock => {
-                    if !self.prev_block().unwrap() {
+                    if !self.load_prev_block() {
                         self.state = ReaderState::RightOfLoadedBlock;
                         None
                     } else {
@@ -292,7 +340,7 @@ where
                     }
                 }
                 ReaderState::RightOfLoadedBlock => {
-                    if !self.prev_block().unwrap() || !self.prev_block().unwrap() {
+                    if !self.load_prev_block() || !self.load_prev_block() {
                         self.state = ReaderState::RightOfLoadedBlock;
                         None
                     } else {
@@ -305,12 +353,15 @@ where
     }
 
     fn peek_prev(&mut self) -> Option<(&K, &V)> {
+        if self.error.is_some() {
+            return None;
+        }
         if self.current_block.peek_prev().is_some() {
             self.current_block.peek_prev()
         } else {
             match self.state {
                 ReaderState::LeftOfLoadedBlock => {
-                    if !self.prev_block().unwrap() {
+                    if !self.load_prev_block() {
                         self.state = ReaderState::RightOfLoadedBlock;
                         None
                     } else {
@@ -319,7 +370,7 @@ where
                     }
                 }
                 ReaderState::RightOfLoadedBlock => {
-                    if !self.prev_block().unwrap() || !self.prev_block().unwrap() {
+                    if !self.load_prev_block() || !self.load_prev_block() {
                         self.state = ReaderState::RightOfLoadedBlock;
                         None
                     } else {
@@ -332,29 +383,38 @@ where
     }
 
     fn seek_ge(&mut self, key: &K) {
-        self.index_block.seek_gt(key);
-        if self.index_block.idx > 0 {
-            self.index_block.idx -= 1;
+        // Each index key is at or after the last key of its block, and before
+        // the first key of the next, so the first block with an index key at
+        // or after `key` is the only one that can hold it.
+        self.index_block.seek_ge(&*self.comparator, key);
+        if self.index_block.peek().is_none() {
+            self.end();
+            return;
         }
-        // TODO: how to handle errors here without infecting the nice simple traits?
-        self.next_block().unwrap();
-        self.current_block.seek_ge(key);
+        self.load_next_block();
+        self.current_block.seek_ge(&*self.comparator, key);
         self.state = ReaderState::RightOfLoadedBlock;
     }
 
     fn start(&mut self) {
         self.index_block.align_start();
         self.state = ReaderState::RightOfLoadedBlock;
-        self.next_block().unwrap();
+        self.load_next_block();
     }
 
     fn end(&mut self) {
         self.index_block.align_end();
         self.state = ReaderState::RightOfLoadedBlock;
-        self.prev_block().unwrap();
-        self.index_block.idx += 1;
+        // An SST can have no blocks at all if it only holds range tombstones.
+        if self.load_prev_block() {
+            self.index_block.idx += 1;
+        }
         self.current_block.align_end();
     }
+
+    fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.error.take()
+    }
 }
 
 impl<K, V, D> SstReader<K, V, D>
@@ -380,8 +440,10 @@ where
             }
             Some((_k, (loc, len))) => {
                 // TODO: check if loc is where we already are and don't move if so.
-                self.file.seek(SeekFrom::Start(*loc as u64))?;
-                self.current_block.load(&mut self.file, *len as u32)?;
+                let data = read_checked(&mut self.file, *loc as usize, *len as usize)?;
+                let key_format = &*self.key_format;
+                self.current_block
+                    .load(&mut Cursor::new(data), *len, &|kr| key_format.read(kr))?;
                 self.current_block.align_start();
 
                 Ok(true)
@@ -400,8 +462,10 @@ where
             }
             Some((_k, (loc, len))) => {
                 // TODO: check if loc is where we already are and don't move if so.
-                self.file.seek(SeekFrom::Start(*loc as u64))?;
-                self.current_block.load(&mut self.file, *len as u32)?;
+                let data = read_checked(&mut self.file, *loc as usize, *len as usize)?;
+                let key_format = &*self.key_format;
+                self.current_block
+                    .load(&mut Cursor::new(data), *len, &|kr| key_format.read(kr))?;
                 self.current_block.align_end();
 
                 Ok(true)
@@ -409,72 +473,233 @@ where
         }
     }
 
-    pub fn load(mut file: D::DbFile) -> anyhow::Result<Self> {
-        // First, read the length of the metadata.
-        file.seek(SeekFrom::End(-4))?;
-        let mut buf = [0_u8; 4];
-        file.read_exact(&mut buf)?;
-        let meta_len: i64 = u32::from_le_bytes(buf).into();
-
-        file.seek(SeekFrom::End(-4 - meta_len))?;
-
-        // TODO: can we derive this value here instead of hardcoding it?
-        let mut bounds_info = (0..(meta_len - 4)).map(|_| 0u8).collect::<Vec<_>>();
-        file.read_exact(&mut bounds_info)?;
-
-        let mut reader = KeyReader::new();
-        reader.load(&bounds_info);
+    // Like `next_block`, but keeps the error for `take_error`, leaving the
+    // current block empty.
+    fn load_next_block(&mut self) -> bool {
+        let loaded = self.next_block();
+        self.keep_error(loaded)
+    }
 
-        let mut b = Block::<K, ()>::new();
-        let len = bounds_info.len().try_into()?;
-        b.load(&mut Cursor::new(bounds_info), len)?;
+    fn load_prev_block(&mut self) -> bool {
+        let loaded = self.prev_block();
+        self.keep_error(loaded)
+    }
 
-        let min_key = (*b.next().unwrap().0).clone();
-        let max_key = (*b.next().unwrap().0).clone();
+    fn keep_error(&mut self, loaded: anyhow::Result<bool>) -> bool {
+        loaded.unwrap_or_else(|err| {
+            self.current_block = Block::new();
+            self.error = Some(err);
+            false
+        })
+    }
 
-        let mut buf = [0_u8; 4];
+    pub fn load(file: D::DbFile) -> anyhow::Result<Self>
+    where
+        K: Encode + 'static,
+    {
+        Self::load_with_key_format(file, Arc::new(Encoded))
+    }
 
+    // Loads an SST whose keys were written in `key_format`.
+    pub fn load_with_key_format(
+        mut file: D::DbFile,
+        key_format: Arc<dyn KeyFormat<K>>,
+    ) -> anyhow::Result<Self> {
+        // The file ends with the magic number, and before that the length of
+        // the metadata, which comes just before it, and holds the bounds
+        // keys, the lengths of the blocks before that and the format version.
+        let num_bytes = file.len();
+        if num_bytes < 12 {
+            bail!("sst is too short to hold its metadata length");
+        }
+        file.seek(SeekFrom::End(-12))?;
+        let mut buf = [0_u8; 12];
         file.read_exact(&mut buf)?;
-        let index_len = u32::from_le_bytes(buf);
+        if u64::from_le_bytes(buf[4..].try_into()?) != MAGIC {
+            bail!(
+                "sst doesn't end with the magic number: it's either not an sst, or \
+                 was written before ssts had checksums and a format version, and \
+                 can't be read"
+            );
+        }
+        let meta_len = u32::from_le_bytes(buf[..4].try_into()?) as usize;
+        let meta_start = (num_bytes - 12)
+            .checked_sub(meta_len)
+            .filter(|_| meta_len >= 20)
+            .ok_or_else(|| anyhow!("sst metadata length {} is invalid", meta_len))?;
+        let meta = read_checked(&mut file, meta_start, meta_len - 4)?;
+
+        let fixed_start = meta.len() - 16;
+        let version = u32::from_le_bytes(meta[fixed_start + 12..].try_into()?);
+        if version != FORMAT_VERSION {
+            bail!(
+                "sst format version {} is not supported (expected {})",
+                version,
+                FORMAT_VERSION
+            );
+        }
+        let field = |i: usize| -> anyhow::Result<u32> {
+            let at = fixed_start + 4 * i;
+            Ok(u32::from_le_bytes(meta[at..at + 4].try_into()?))
+        };
+        let (index_len, range_del_len) = (field(0)?, field(1)?);
+        let bounds_len = fixed_start
+            .checked_sub(field(2)? as usize)
+            .ok_or_else(|| anyhow!("sst column family length is invalid"))?;
+        let column_family = Bytes(meta[bounds_len..fixed_start].to_vec()).decode()?;
+
+        let mut b = Block::<K, ()>::new();
+        b.load(
+            &mut Cursor::new(&meta[..bounds_len]),
+            bounds_len.try_into()?,
+            &K::decode,
+        )?;
+        let mut bounds = b.data.into_iter().map(|(k, _)| k);
+        let (Some(min_key), Some(max_key)) = (bounds.next(), bounds.next()) else {
+            bail!("sst metadata is missing its bounds");
+        };
+
+        // Load the range tombstones.
+        let range_del_start = meta_start
+            .checked_sub(range_del_len as usize + 4)
+            .ok_or_else(|| anyhow!("sst range deletion block length is invalid"))?;
+        let range_del_data = read_checked(&mut file, range_del_start, range_del_len as usize)?;
+        let mut range_del_block = Block::<K, K>::new();
+        range_del_block.load(&mut Cursor::new(range_del_data), range_del_len, &K::decode)?;
+        let range_tombstones = range_del_block.data;
 
         // Load the index block into memory.
-        file.seek(SeekFrom::End(-4 - (index_len as i64) - meta_len))?;
-        let mut index_data: Vec<_> = vec![0; index_len.try_into()?];
-        file.read_exact(&mut index_data)?;
+        let index_start = range_del_start
+            .checked_sub(index_len as usize + 4)
+            .ok_or_else(|| anyhow!("sst index block length is invalid"))?;
+        let index_data = read_checked(&mut file, index_start, index_len as usize)?;
         let mut index_block = Block::new();
-        let len = index_data.len() as u32;
-
-        index_block.load(&mut Cursor::new(index_data), len)?;
+        index_block.load(&mut Cursor::new(index_data), index_len, &|kr| {
+            key_format.read(kr)
+        })?;
 
         file.seek(SeekFrom::Start(0))?;
 
-        let num_bytes = file.len();
-
         Ok(SstReader {
             file,
             current_block: Block::new(),
             index_block,
             state: ReaderState::RightOfLoadedBlock,
+            error: None,
+            comparator: Arc::new(OrdComparator),
+            key_format,
             sst_meta: SstMeta {
                 min_key,
                 max_key,
                 num_bytes,
+                range_tombstones,
+                column_family,
             },
             _marker: PhantomData,
         })
     }
+
+    // Reads the whole SST, checking each block's checksum, that keys are in
+    // order within and across blocks, that the index agrees with the blocks
+    // it points to, and that the bounds in the metadata match the contents.
+    // Leaves the reader at the start.
+    pub fn verify(&mut self) -> anyhow::Result<()> {
+        let comparator = self.comparator.clone();
+        let mut first: Option<K> = None;
+        let mut last: Option<K> = None;
+        let mut prev_index_key: Option<K> = None;
+        self.index_block.align_start();
+        while let Some((index_key, (loc, len))) = self.index_block.next() {
+            let (index_key, loc, len) = (index_key.clone(), *loc, *len);
+            let data = read_checked(&mut self.file, loc as usize, len as usize)?;
+            let mut block = Block::<K, V>::new();
+            let key_format = &*self.key_format;
+            block.load(&mut Cursor::new(data), len, &|kr| key_format.read(kr))?;
+            let (Some((block_first, _)), Some((block_last, _))) =
+                (block.data.first(), block.data.last())
+            else {
+                bail!("block at offset {} is empty", loc);
+            };
+            if comparator.lt(&index_key, block_last) {
+                bail!(
+                    "index key {:?} for the block at offset {} comes before its last key {:?}",
+                    index_key,
+                    loc,
+                    block_last
+                );
+            }
+            if let Some(prev_index_key) = &prev_index_key {
+                if !comparator.lt(prev_index_key, block_first) {
+                    bail!(
+                        "the block at offset {} starts at {:?}, which the previous index key {:?} \
+                         should come before",
+                        loc,
+                        block_first,
+                        prev_index_key
+                    );
+                }
+            }
+            prev_index_key = Some(index_key);
+            for (k, _) in block.data {
+                if let Some(prev) = &last {
+                    if !comparator.lt(prev, &k) {
+                        bail!("{:?} comes after {:?}", k, prev);
+                    }
+                }
+                if first.is_none() {
+                    first = Some(k.clone());
+                }
+                last = Some(k);
+            }
+        }
+        self.start();
+
+        let mut min_key = first;
+        let mut max_key = last;
+        for (start, end) in &self.sst_meta.range_tombstones {
+            if min_key.as_ref().is_none_or(|k| comparator.lt(start, k)) {
+                min_key = Some(start.clone());
+            }
+            if max_key.as_ref().is_none_or(|k| comparator.lt(k, end)) {
+                max_key = Some(end.clone());
+            }
+        }
+        let same = |a: &Option<K>, b: &K| {
+            a.as_ref()
+                .is_some_and(|a| comparator.compare(a, b) == Ordering::Equal)
+        };
+        if !same(&min_key, &self.sst_meta.min_key) || !same(&max_key, &self.sst_meta.max_key) {
+            bail!(
+                "metadata says the sst spans {:?} to {:?}, but it spans {:?} to {:?}",
+                self.sst_meta.min_key,
+                self.sst_meta.max_key,
+                min_key,
+                max_key
+            );
+        }
+        Ok(())
+    }
+
+    // The ordering the SST was written in, which seeks rely on.
+    pub fn with_comparator(mut self, comparator: Arc<dyn Comparator<K>>) -> Self {
+        self.comparator = comparator;
+        self
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use std::rc::Rc;
 
     use rand::Rng;
 
+    use std::sync::Arc;
+
     use crate::{
-        fs::{DbDir, MockDir},
+        comparator::{BytewiseComparator, Comparator},
+        encoding::Bytes,
+        fs::{DbDir, DbFile, MockDir},
         memtable::{KVIter, VecIter},
-        sst::writer::SstWriter,
+        sst::{writer::SstWriter, FORMAT_VERSION},
     };
 
     use super::SstReader;
@@ -492,12 +717,11 @@ mod test {
     fn results_match(data: &[((String, usize), Option<String>)], ops: &[Op], show: bool) -> bool {
         let mut dir = MockDir::new();
 
-        let mut vec_iter = VecIter::new(Rc::new(data.to_vec()));
+        let mut vec_iter = VecIter::new(Arc::new(data.to_vec()));
 
         let sst_fname = "/tmp/test_sst.sst";
         let file = dir.create(&sst_fname).unwrap().unwrap();
-        let data_source = vec_iter.clone();
-        let writer = SstWriter::new(data_source, file);
+        let writer = SstWriter::new(data.iter().cloned(), file);
         writer.write().unwrap();
         let mut reader: SstReader<(String, usize), Option<String>, MockDir> =
             SstReader::load(dir.open(&sst_fname).unwrap()).unwrap();
@@ -612,4 +836,100 @@ mod test {
             panic!("results did not match")
         }
     }
+
+    #[test]
+    fn test_shortened_index() {
+        let mut dir = MockDir::new();
+        let comparator: Arc<dyn Comparator<Bytes>> = Arc::new(BytewiseComparator);
+        let keys: Vec<_> = (0..20)
+            .map(|i| Bytes(format!("key-{:03}-padding", i * 7).into_bytes()))
+            .collect();
+        let data: Vec<_> = keys.iter().map(|k| (k.clone(), k.clone())).collect();
+        let file = dir.create(&"bytes.sst").unwrap().unwrap();
+        SstWriter::new(data.into_iter(), file)
+            .with_comparator(comparator.clone())
+            .write()
+            .unwrap();
+        let mut reader: SstReader<Bytes, Bytes, MockDir> =
+            SstReader::load(dir.open(&"bytes.sst").unwrap())
+                .unwrap()
+                .with_comparator(comparator);
+        reader.verify().unwrap();
+        // The index holds short separators rather than whole keys, where
+        // there's room for one between neighbouring blocks.
+        let shortened = reader
+            .index_block
+            .data
+            .iter()
+            .filter(|(k, _)| k.0.len() < "key-000-padding".len())
+            .count();
+        assert!(shortened >= 2, "{}", shortened);
+
+        // Every seek, including ones that land between a block's last key and
+        // its index key, finds the first key at or after it.
+        for i in 0..=140 {
+            let target = Bytes(format!("key-{:03}", i).into_bytes());
+            reader.seek_ge(&target);
+            let want = keys.iter().find(|k| **k >= target);
+            assert_eq!(reader.peek().map(|(k, _)| k), want, "{}", i);
+            let before = keys.iter().rev().find(|k| **k < target);
+            assert_eq!(reader.peek_prev().map(|(k, _)| k), before, "{}", i);
+        }
+    }
+
+    #[test]
+    fn test_paranoid_checks() {
+        let mut dir = MockDir::new();
+        let data = vec![
+            (("b".to_owned(), 0_usize), None),
+            (("a".to_owned(), 0), Some("x".to_owned())),
+        ];
+        for (paranoid, fname) in [(false, "lax.sst"), (true, "paranoid.sst")] {
+            let file = dir.create(&fname).unwrap().unwrap();
+            let result = SstWriter::new(data.clone().into_iter(), file)
+                .with_paranoid_checks(paranoid)
+                .write();
+            assert_eq!(result.is_err(), paranoid);
+        }
+
+        // What was written without the check is caught by verifying it.
+        let mut reader: SstReader<(String, usize), Option<String>, MockDir> =
+            SstReader::load(dir.open(&"lax.sst").unwrap()).unwrap();
+        assert!(reader.verify().is_err());
+    }
+
+    #[test]
+    fn test_unknown_format_version() {
+        let mut dir = MockDir::new();
+        let file = dir.create(&"a.sst").unwrap().unwrap();
+        SstWriter::new(vec![(1_u64, 1_u64)].into_iter(), file)
+            .write()
+            .unwrap();
+
+        // Bump the version at the end of the metadata, and fix up the
+        // metadata's checksum to match.
+        let mut data = dir.open(&"a.sst").unwrap().read_all();
+        let len = data.len() - 8;
+        let meta_len = u32::from_le_bytes(data[len - 4..len].try_into().unwrap()) as usize;
+        let meta = len - 4 - meta_len..len - 8;
+        data[len - 12..len - 8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        let crc = crc32fast::hash(&data[meta]);
+        data[len - 8..len - 4].copy_from_slice(&crc.to_le_bytes());
+        let mut file = dir.create(&"b.sst").unwrap().unwrap();
+        file.write(&data).unwrap();
+
+        let err = SstReader::<u64, u64, MockDir>::load(dir.open(&"b.sst").unwrap()).unwrap_err();
+        assert!(err.to_string().contains("format version"), "{}", err);
+
+        // An sst from before checksums has no magic number: the metadata
+        // length comes last, after an unchecked footer.
+        let mut legacy = Vec::new();
+        legacy.extend(b"not checksummed");
+        legacy.extend(15_u32.to_le_bytes());
+        let mut file = dir.create(&"legacy.sst").unwrap().unwrap();
+        file.write(&legacy).unwrap();
+        let err =
+            SstReader::<u64, u64, MockDir>::load(dir.open(&"legacy.sst").unwrap()).unwrap_err();
+        assert!(err.to_string().contains("magic number"), "{}", err);
+    }
 }
@@ -1,16 +1,20 @@
 use std::{
     io::{Cursor, Write},
+    iter::Peekable,
     marker::PhantomData,
+    sync::Arc,
 };
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 
 use crate::{
-    encoding::{Encode, KeyWriter},
+    comparator::{Comparator, OrdComparator},
+    encoding::{Bytes, Decode, Encode, KeyWriter},
     fs::DbFile,
-    memtable::KVIter,
 };
 
+use super::{reader::SstMeta, Encoded, Formatted, KeyFormat, FORMAT_VERSION, MAGIC};
+
 const RESET_INTERVAL: usize = 2;
 
 struct Writer<W>
@@ -68,105 +72,251 @@ where
     }
 }
 
+// Every block is followed by the checksum of its contents.
+fn write_checked<D: DbFile>(file: &mut D, block: &[u8]) -> anyhow::Result<()> {
+    file.write(block)?;
+    file.write(&crc32fast::hash(block).to_le_bytes())
+}
+
+// What the writer counted while writing an SST, beyond what goes in its
+// footer.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SstStats {
+    pub num_entries: usize,
+    // The range of the seqnums of its entries and range tombstones, if the
+    // writer was told how to find them.
+    pub seqnums: Option<(usize, usize)>,
+}
+
+impl SstStats {
+    fn add_seqnum(&mut self, seqnum: usize) {
+        self.seqnums = Some(match self.seqnums {
+            Some((min, max)) => (min.min(seqnum), max.max(seqnum)),
+            None => (seqnum, seqnum),
+        });
+    }
+}
+
+// Writes the entries coming out of `it`, which must be sorted, into an SST.
+// They're only read once, front to back, so `it` can produce them as it goes.
 pub struct SstWriter<I, K, V, D>
 where
-    I: KVIter<K, V>,
+    I: Iterator<Item = (K, V)>,
     K: Ord + Encode,
     V: Encode,
     D: DbFile,
 {
     file: D,
-    it: I,
+    it: Peekable<I>,
+    range_tombstones: Vec<(K, K)>,
+    comparator: Arc<dyn Comparator<K>>,
+    key_format: Arc<dyn KeyFormat<K>>,
+    column_family: Option<(usize, String)>,
+    // If set, check that the keys coming out of `it` are strictly increasing.
+    paranoid_checks: bool,
+    // The last key written so far.
+    last_key: Option<K>,
+    seqnum: Option<fn(&K) -> usize>,
+    stats: SstStats,
     _marker: PhantomData<(K, V)>,
 }
 
 impl<I, K, V, D> SstWriter<I, K, V, D>
 where
-    I: KVIter<K, V>,
-    K: Ord + Encode + Clone,
+    I: Iterator<Item = (K, V)>,
+    K: Ord + Encode + Decode + Clone + std::fmt::Debug,
     V: Encode,
     D: DbFile,
 {
     pub fn new(it: I, file: D) -> Self {
         SstWriter {
             file,
-            it,
+            it: it.peekable(),
+            range_tombstones: Vec::new(),
+            comparator: Arc::new(OrdComparator),
+            key_format: Arc::new(Encoded),
+            column_family: None,
+            paranoid_checks: false,
+            last_key: None,
+            seqnum: None,
+            stats: SstStats::default(),
             _marker: PhantomData,
         }
     }
 
-    fn build_block(&mut self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+    // How to find the seqnum in a key, so that their range can be recorded in
+    // the stats.
+    pub fn with_seqnums(mut self, seqnum: fn(&K) -> usize) -> Self {
+        self.seqnum = Some(seqnum);
+        self
+    }
+
+    // The ordering of the keys coming out of `it`. Set this before the range
+    // tombstones, which are sorted with it.
+    pub fn with_comparator(mut self, comparator: Arc<dyn Comparator<K>>) -> Self {
+        self.comparator = comparator;
+        self
+    }
+
+    // How to write the keys. The SST has to be read with the same format.
+    pub fn with_key_format(mut self, key_format: Arc<dyn KeyFormat<K>>) -> Self {
+        self.key_format = key_format;
+        self
+    }
+
+    // The id and name of the column family the SST belongs to, which are
+    // recorded in its metadata.
+    pub fn with_column_family(mut self, id: usize, name: String) -> Self {
+        self.column_family = Some((id, name));
+        self
+    }
+
+    // Range tombstones to write alongside the entries, as (start, end) pairs.
+    pub fn with_range_tombstones(mut self, mut range_tombstones: Vec<(K, K)>) -> Self {
+        range_tombstones.sort_by(|a, b| self.comparator.compare(&a.0, &b.0));
+        self.range_tombstones = range_tombstones;
+        self
+    }
+
+    pub fn with_paranoid_checks(mut self, paranoid_checks: bool) -> Self {
+        self.paranoid_checks = paranoid_checks;
+        self
+    }
+
+    // Writes the next few entries into `data`, and returns the last of their
+    // keys. There must be at least one entry left.
+    fn build_block(&mut self, data: &mut Vec<u8>) -> anyhow::Result<K> {
         let mut writer = Writer::new(Cursor::new(data));
         let mut written = 0;
-        while let Some((k, v)) = self.it.next() {
-            writer.write(&(k, v))?;
+        for (k, v) in self.it.by_ref() {
+            if self.paranoid_checks {
+                if let Some(last) = &self.last_key {
+                    if !self.comparator.lt(last, &k) {
+                        bail!("sst keys out of order: {:?} came after {:?}", k, last);
+                    }
+                }
+            }
+            let key = Formatted {
+                k: &k,
+                format: &*self.key_format,
+            };
+            writer.write(&(key, &v))?;
+            self.stats.num_entries += 1;
+            if let Some(seqnum) = self.seqnum {
+                self.stats.add_seqnum(seqnum(&k));
+            }
+            self.last_key = Some(k);
             written += 1;
             if written >= RESET_INTERVAL {
                 break;
             }
         }
 
-        Ok(())
+        self.last_key
+            .clone()
+            .filter(|_| written > 0)
+            .ok_or_else(|| anyhow!("can't build an empty block"))
     }
 
-    pub fn write(mut self) -> anyhow::Result<()> {
+    // Writes out everything in one pass over `it`.
+    pub fn write(mut self) -> anyhow::Result<(SstMeta<K>, SstStats)> {
         let mut index = Vec::new();
         let mut index_writer = Writer::new(&mut index);
 
         let mut bytes_written = 0;
         let mut block_buffer = Vec::new();
 
-        let min_key = if let Some((k, _)) = self.it.peek() {
-            k.clone()
-        } else {
+        if self.it.peek().is_none() && self.range_tombstones.is_empty() {
             bail!("will only write non-empty SST")
-        };
-
-        while let Some((header_key, _)) = self.it.peek() {
-            // TODO: is this clone necessary?
-            let k = (*header_key).clone();
-
-            self.build_block(&mut block_buffer)?;
-            self.file.write(&block_buffer)?;
+        }
 
-            let index_entry = (k, (bytes_written as u32, block_buffer.len() as u32));
-            index_writer.write(&index_entry)?;
+        // The bounds cover the range tombstones as well as the entries.
+        let mut min_key = self.it.peek().map(|(k, _)| k.clone());
+        for (start, _) in &self.range_tombstones {
+            if min_key
+                .as_ref()
+                .is_none_or(|k| self.comparator.lt(start, k))
+            {
+                min_key = Some(start.clone());
+            }
+        }
 
-            bytes_written += block_buffer.len();
+        while self.it.peek().is_some() {
+            let block_last = self.build_block(&mut block_buffer)?;
+            write_checked(&mut self.file, &block_buffer)?;
+
+            // Each block is indexed by a key at or after its last one, and
+            // before the first key of the next block. That can be shorter
+            // than either of them.
+            let index_key = match self.it.peek() {
+                Some((next, _)) => self.comparator.shortest_separator(&block_last, next),
+                None => self.comparator.successor(&block_last),
+            };
+            let key = Formatted {
+                k: &index_key,
+                format: &*self.key_format,
+            };
+            index_writer.write(&(key, (bytes_written as u32, block_buffer.len() as u32)))?;
+
+            bytes_written += block_buffer.len() + 4;
 
             block_buffer.clear();
         }
 
-        self.it.end();
-
-        let max_key = if let Some((k, _)) = self.it.peek_prev() {
-            k.clone()
-        } else {
-            unreachable!()
-        };
+        let mut max_key = self.last_key.clone();
+        for (_, end) in &self.range_tombstones {
+            if max_key.as_ref().is_none_or(|k| self.comparator.lt(k, end)) {
+                max_key = Some(end.clone());
+            }
+        }
 
         // Write the index block.
-        self.file.write(&index)?;
+        write_checked(&mut self.file, &index)?;
 
-        // Write the metadata.
-        let mut metadata_len = 0;
+        // Write the range deletion block.
+        let mut range_del = Vec::new();
+        let mut range_del_writer = Writer::new(&mut range_del);
+        for tombstone in &self.range_tombstones {
+            range_del_writer.write(tombstone)?;
+        }
+        write_checked(&mut self.file, &range_del)?;
 
-        // Write the bounds keys.
+        // Write the metadata: the bounds keys and the column family, then the
+        // lengths of the index and range deletion blocks and the column
+        // family, then the format version.
+        let (min_key, max_key) = (min_key.unwrap(), max_key.unwrap());
         let mut data = Vec::new();
         let mut writer = Writer::new(Cursor::new(&mut data));
         writer.write(&min_key)?;
         writer.write(&max_key)?;
-        self.file.write(&data)?;
-        metadata_len += data.len();
-
-        // Write the length of the index block.
-        self.file.write(&(index.len() as u32).to_le_bytes())?;
-        metadata_len += 4;
-        // Write the length of the metadata.
-        self.file.write(&(metadata_len as u32).to_le_bytes())?;
+        let column_family = Bytes::encode(&self.column_family).0;
+        data.extend(&column_family);
+        data.extend((index.len() as u32).to_le_bytes());
+        data.extend((range_del.len() as u32).to_le_bytes());
+        data.extend((column_family.len() as u32).to_le_bytes());
+        data.extend(FORMAT_VERSION.to_le_bytes());
+        write_checked(&mut self.file, &data)?;
+        // Write the length of the metadata, along with its checksum.
+        self.file.write(&((data.len() + 4) as u32).to_le_bytes())?;
+        self.file.write(&MAGIC.to_le_bytes())?;
 
         self.file.sync()?;
 
-        Ok(())
+        if let Some(seqnum) = self.seqnum {
+            for (start, _) in &self.range_tombstones {
+                self.stats.add_seqnum(seqnum(start));
+            }
+        }
+
+        Ok((
+            SstMeta {
+                min_key,
+                max_key,
+                num_bytes: self.file.len(),
+                range_tombstones: self.range_tombstones,
+                column_family: self.column_family,
+            },
+            self.stats,
+        ))
     }
 }